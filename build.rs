@@ -0,0 +1,14 @@
+// The tree-sitter grammars in grammars/ are compiled in with the tree-sitter feature
+fn main() {
+    #[cfg(feature = "tree-sitter")]
+    for (language, files) in [("c", &["parser.c"][..]), ("rust", &["parser.c", "scanner.c"])] {
+        let src = std::path::Path::new("grammars").join(language).join("src");
+        let mut build = cc::Build::new();
+        build.std("c11").include(&src).warnings(false);
+        for file in files {
+            build.file(src.join(file));
+            println!("cargo:rerun-if-changed={}", src.join(file).display());
+        }
+        build.compile(&format!("tree-sitter-{}", language));
+    }
+}
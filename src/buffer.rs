@@ -0,0 +1,372 @@
+use std::{cell::RefCell, io, rc::Rc};
+use ropey::{Rope, RopeSlice};
+use crate::{editor_open, editor_save, grapheme, highlight, operator::Pos, syntax::Syntax, tab, undo::UndoHistory, EditorConfig};
+#[cfg(feature = "tree-sitter")]
+use crate::tree;
+
+// One loaded file. The one being edited lives in ec.buf, the others wait in ec.buffers
+#[derive(Default)]
+pub struct Buffer {
+    pub id: usize, // the number :ls shows, never reused
+    // the lines joined with \n, numrows is kept apart so a buffer can have no lines at all
+    text: Rope,
+    pub numrows: usize,
+    pub filename: String,
+    pub filetype: String, // picks the syntax, empty when there is none
+    pub dirty: bool,
+    pub undo: UndoHistory,
+    pub visual_marks: Option<(Pos, Pos)>, // '< and '> from the last selection
+    // where the cursor was when the buffer was left
+    pub cursor: Pos,
+    pub rowoff: usize,
+    // filled in as lines are drawn
+    highlight: RefCell<highlight::Highlighter>,
+    // colours instead of highlight when the filetype has a grammar
+    #[cfg(feature = "tree-sitter")]
+    tree: RefCell<tree::SyntaxTree>,
+}
+
+impl Buffer {
+    pub fn new(id: usize) -> Buffer {
+        Buffer { id, ..Default::default() }
+    }
+
+    pub fn name(&self) -> &str {
+        if self.filename.is_empty() {"[No Name]"} else {&self.filename}
+    }
+
+    // Replace everything with the contents of a file
+    pub fn load(&mut self, content: &str) {
+        let content = content.replace("\r\n", "\n");
+        let content = content.strip_suffix('\n').unwrap_or(&content);
+        self.text = Rope::from_str(content);
+        self.numrows = if content.is_empty() {0} else {self.text.len_lines()};
+        self.highlight.get_mut().clear();
+        #[cfg(feature = "tree-sitter")]
+        self.tree.get_mut().clear();
+    }
+
+    // Line y without its \n
+    pub fn line(&self, y: usize) -> String {
+        let line = self.text.line(y);
+        let len = line.len_chars() - usize::from(y + 1 < self.text.len_lines());
+        line.slice(..len).to_string()
+    }
+
+    // Length of line y in bytes
+    pub fn line_len(&self, y: usize) -> usize {
+        self.text.line(y).len_bytes() - usize::from(y + 1 < self.text.len_lines())
+    }
+
+    // The char at byte x of line y
+    pub fn char_at(&self, y: usize, x: usize) -> Option<char> {
+        if x >= self.line_len(y) {return None}
+        let byte = self.text.line_to_byte(y) + x;
+        Some(self.text.char(self.text.byte_to_char(byte)))
+    }
+
+    // Line y without its \n, as a slice of the rope
+    fn line_slice(&self, y: usize) -> RopeSlice<'_> {
+        self.text.line(y).byte_slice(..self.line_len(y))
+    }
+
+    // Cursor steps in line y, see grapheme
+    pub fn next_grapheme(&self, y: usize, x: usize) -> usize {
+        grapheme::next_in(self.line_slice(y), x)
+    }
+
+    pub fn prev_grapheme(&self, y: usize, x: usize) -> usize {
+        grapheme::prev_in(self.line_slice(y), x)
+    }
+
+    // x kept inside line y and off the middle of a character
+    pub fn snap(&self, y: usize, x: usize) -> usize {
+        grapheme::floor_in(self.line_slice(y), x)
+    }
+
+    // Where (x, y) is counting chars from the start, lines joined with \n
+    pub fn char_index(&self, (x, y): Pos) -> usize {
+        self.text.byte_to_char(self.text.line_to_byte(y) + x)
+    }
+
+    pub fn char_pos(&self, i: usize) -> Pos {
+        let y = self.text.char_to_line(i);
+        (self.text.char_to_byte(i) - self.text.line_to_byte(y), y)
+    }
+
+    // The chars from char index i on with their indices, for scanning without copying the text
+    pub fn chars_after(&self, i: usize) -> impl Iterator<Item = (usize, char)> + '_ {
+        (i..).zip(self.text.chars_at(i))
+    }
+
+    // The chars before char index i, nearest first
+    pub fn chars_before(&self, i: usize) -> impl Iterator<Item = (usize, char)> + '_ {
+        (0..i).rev().zip(self.text.chars_at(i).reversed())
+    }
+
+    // Text from start up to (not including) end, lines joined with \n
+    pub fn text(&self, start: Pos, end: Pos) -> String {
+        self.text.slice(self.char_index(start)..self.char_index(end)).to_string()
+    }
+
+    // Tell the syntax tree the text from start to end is about to become inserted
+    fn replace(&mut self, start: Pos, end: Pos, inserted: &str) {
+        #[cfg(feature = "tree-sitter")]
+        self.tree.get_mut().replace(&self.text, start, end, inserted);
+        #[cfg(not(feature = "tree-sitter"))]
+        let _ = (start, end, inserted);
+    }
+
+    pub fn insert_line(&mut self, at: usize, s: &str) {
+        // a \n joins the line to the ones around it
+        let (pos, inserted) = if self.numrows == 0 {
+            ((0, 0), s.to_string())
+        } else if at == self.numrows {
+            ((self.line_len(at - 1), at - 1), format!("\n{}", s))
+        } else {
+            ((0, at), format!("{}\n", s))
+        };
+        self.replace(pos, pos, &inserted);
+        self.text.insert(self.char_index(pos), &inserted);
+        self.numrows += 1;
+        self.highlight.get_mut().inserted(at);
+    }
+
+    pub fn remove_line(&mut self, at: usize) -> String {
+        let old = self.line(at);
+        let (start, end) = if at + 1 < self.numrows {
+            ((0, at), (0, at + 1))
+        } else if at > 0 {
+            // the last line takes the \n before it instead
+            ((self.line_len(at - 1), at - 1), (old.len(), at))
+        } else {
+            ((0, 0), (old.len(), 0))
+        };
+        self.replace(start, end, "");
+        self.text.remove(self.char_index(start)..self.char_index(end));
+        self.numrows -= 1;
+        self.highlight.get_mut().removed(at);
+        old
+    }
+
+    pub fn set_line(&mut self, y: usize, s: &str) {
+        let (start, end) = ((0, y), (self.line_len(y), y));
+        self.replace(start, end, s);
+        self.text.remove(self.char_index(start)..self.char_index(end));
+        self.text.insert(self.char_index(start), s);
+        self.highlight.get_mut().changed(y);
+    }
+
+    // Colours of line y, see highlight
+    pub fn highlight(&self, syntaxes: &[Rc<Syntax>], y: usize) -> Vec<highlight::Span> {
+        #[cfg(feature = "tree-sitter")]
+        if let Some(spans) = self.tree.borrow_mut().line(&self.filetype, &self.text, y) {return spans}
+        self.highlight.borrow_mut().line(y, syntaxes, &self.filetype, |y| self.line(y))
+    }
+
+    // Lines up to last whose colours changed because of an edit above them, they need drawing again
+    pub fn restyled(&self, syntaxes: &[Rc<Syntax>], last: usize) -> Vec<usize> {
+        #[cfg(feature = "tree-sitter")]
+        if let Some(lines) = self.tree.borrow_mut().restyled(&self.filetype, &self.text, last) {return lines}
+        let mut highlight = self.highlight.borrow_mut();
+        if last < self.numrows {highlight.line(last, syntaxes, &self.filetype, |y| self.line(y));}
+        highlight.take_restyled()
+    }
+
+    // Where the next or previous function in the syntax tree starts or ends, None without a tree
+    pub fn function(&self, at: Pos, forward: bool, ends: bool) -> Option<Option<Pos>> {
+        #[cfg(feature = "tree-sitter")]
+        return self.tree.borrow_mut().function(&self.filetype, &self.text, self.text.line_to_byte(at.1) + at.0, forward, ends);
+        #[cfg(not(feature = "tree-sitter"))]
+        {
+            let _ = (at, forward, ends);
+            None
+        }
+    }
+
+    // The syntax node around start..end, see tree
+    pub fn node(&self, start: Pos, end: Pos, count: usize, grow: bool, inner: bool) -> Option<(Pos, Pos)> {
+        #[cfg(feature = "tree-sitter")]
+        {
+            let byte = |(x, y): Pos| self.text.line_to_byte(y) + x;
+            let pos = |byte: usize| {
+                let y = self.text.byte_to_line(byte).min(self.numrows.saturating_sub(1));
+                ((byte - self.text.line_to_byte(y)).min(self.line_len(y)), y)
+            };
+            let range = self.tree.borrow_mut().node(&self.filetype, &self.text, (byte(start), byte(end)), count, grow, inner)?;
+            Some((pos(range.start), pos(range.end)))
+        }
+        #[cfg(not(feature = "tree-sitter"))]
+        {
+            let _ = (start, end, count, grow, inner);
+            None
+        }
+    }
+
+    pub fn write_to<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        self.text.write_to(writer)
+    }
+
+    // The whole text in pieces, without copying it
+    pub fn chunks(&self) -> impl Iterator<Item = &str> {
+        self.text.chunks()
+    }
+}
+
+// Make buf the current buffer, putting the current one away with its cursor
+fn enter(ec: &mut EditorConfig, buf: Buffer) {
+    let mut old = std::mem::replace(&mut ec.buf, buf);
+    old.cursor = (ec.cx, ec.cy);
+    old.rowoff = ec.rowoff;
+    ec.alternate = Some(old.id);
+    ec.buffers.push(old);
+    (ec.cx, ec.cy) = ec.buf.cursor;
+    ec.rowoff = ec.buf.rowoff;
+    ec.dirty_rows.extend(0..ec.screenrows);
+}
+
+// Load filename into a new buffer, or go to the buffer it is already loaded in
+pub fn open(ec: &mut EditorConfig, filename: String) -> io::Result<()> {
+    if filename == ec.buf.filename {return Ok(())}
+    if let Some(id) = ec.buffers.iter().find(|b| b.filename == filename).map(|b| b.id) {
+        let _ = switch(ec, id);
+        return Ok(());
+    }
+    // the file is loaded in place of the current buffer, which is only put away once that worked
+    let current = std::mem::replace(&mut ec.buf, Buffer::new(ec.next_buffer));
+    let opened = editor_open(ec, filename);
+    let buf = std::mem::replace(&mut ec.buf, current);
+    opened?;
+    ec.next_buffer += 1;
+    enter(ec, buf);
+    Ok(())
+}
+
+// A [No Name] buffer with one empty line, for :tabnew and deleting the last buffer
+pub fn new_empty(ec: &mut EditorConfig) {
+    let mut empty = Buffer::new(ec.next_buffer);
+    ec.next_buffer += 1;
+    empty.insert_line(0, "");
+    enter(ec, empty);
+}
+
+pub fn switch(ec: &mut EditorConfig, id: usize) -> Result<(), String> {
+    if id == ec.buf.id {return Ok(())}
+    let at = ec.buffers.iter().position(|b| b.id == id).ok_or(format!("E86: Buffer {} does not exist", id))?;
+    let buf = ec.buffers.remove(at);
+    enter(ec, buf);
+    Ok(())
+}
+
+// The buffer with this id, whether it is current or not
+pub fn get(ec: &EditorConfig, id: usize) -> &Buffer {
+    ec.buffers.iter().find(|b| b.id == id).unwrap_or(&ec.buf)
+}
+
+// Buffer ids in :ls order
+fn ids(ec: &EditorConfig) -> Vec<usize> {
+    let mut ids: Vec<usize> = ec.buffers.iter().map(|b| b.id).chain([ec.buf.id]).collect();
+    ids.sort();
+    ids
+}
+
+// :bn and :bp, wrapping around the list
+pub fn cycle(ec: &mut EditorConfig, count: usize, forward: bool) -> Result<(), String> {
+    let ids = ids(ec);
+    let at = ids.iter().position(|&id| id == ec.buf.id).unwrap_or(0);
+    let count = count % ids.len();
+    let to = if forward {(at + count) % ids.len()} else {(at + ids.len() - count) % ids.len()};
+    switch(ec, ids[to])
+}
+
+// Ctrl-^
+pub fn alternate(ec: &mut EditorConfig) -> Result<(), String> {
+    let id = ec.alternate.ok_or("E23: No alternate file")?;
+    switch(ec, id)
+}
+
+// :b takes a number or a unique part of a file name
+pub fn find(ec: &EditorConfig, arg: &str) -> Result<usize, String> {
+    if let Ok(id) = arg.parse() {return Ok(id)}
+    let all: Vec<&Buffer> = ec.buffers.iter().chain([&ec.buf]).collect();
+    if let Some(b) = all.iter().find(|b| b.filename == arg) {return Ok(b.id)}
+    let matches: Vec<&&Buffer> = all.iter().filter(|b| b.filename.contains(arg)).collect();
+    match matches.as_slice() {
+        [b] => Ok(b.id),
+        [] => Err(format!("E94: No matching buffer for {}", arg)),
+        _ => Err(format!("E93: More than one match for {}", arg)),
+    }
+}
+
+// :bd, the current buffer is replaced by the alternate one, or an empty one if it was the last
+pub fn delete(ec: &mut EditorConfig, id: usize, force: bool) -> Result<(), String> {
+    let dirty = if id == ec.buf.id {
+        ec.buf.dirty
+    } else {
+        ec.buffers.iter().find(|b| b.id == id).ok_or("E516: No buffers were deleted")?.dirty
+    };
+    if dirty && !force {
+        return Err(format!("E89: No write since last change for buffer {} (add ! to override)", id));
+    }
+    if id == ec.buf.id {
+        let alternate = ec.alternate.filter(|a| ec.buffers.iter().any(|b| b.id == *a));
+        match alternate.or(ec.buffers.first().map(|b| b.id)) {
+            Some(next) => switch(ec, next)?,
+            None => new_empty(ec),
+        }
+    }
+    ec.buffers.retain(|b| b.id != id);
+    if ec.alternate == Some(id) {ec.alternate = None}
+    // windows that showed it move to what is current now
+    let current = ec.buf.id;
+    for w in ec.windows.iter_mut().filter(|w| w.buffer == id) {
+        (w.buffer, w.cursor, w.rowoff) = (current, (0, 0), 0);
+    }
+    for w in tab::hidden_windows(ec).filter(|w| w.buffer == id) {
+        (w.buffer, w.cursor, w.rowoff) = (current, (0, 0), 0);
+    }
+    Ok(())
+}
+
+// :ls, one entry per buffer on the message line
+pub fn list(ec: &EditorConfig) -> String {
+    ids(ec).into_iter().map(|id| {
+        let (buf, line) = if id == ec.buf.id {
+            (&ec.buf, ec.cy)
+        } else {
+            let buf = ec.buffers.iter().find(|b| b.id == id).unwrap();
+            (buf, buf.cursor.1)
+        };
+        let flag = if id == ec.buf.id {"%a"} else if ec.alternate == Some(id) {"# "} else {"  "};
+        let modified = if buf.dirty {"+"} else {" "};
+        format!("{} {}{} \"{}\" line {}", id, flag, modified, buf.name(), line + 1)
+    }).collect::<Vec<_>>().join(" | ")
+}
+
+// First modified buffer other than the current one, :q refuses to leave it behind
+pub fn hidden_dirty(ec: &EditorConfig) -> Option<&Buffer> {
+    ec.buffers.iter().find(|b| b.dirty)
+}
+
+fn save(ec: &mut EditorConfig) -> Result<(), String> {
+    if ec.buf.filename.is_empty() {return Err(format!("E141: No file name for buffer {}", ec.buf.id))}
+    editor_save(ec).map_err(|_| format!("E212: Can't open file for writing: {}", ec.buf.filename))
+}
+
+// :wa, every modified buffer is swapped in and saved
+pub fn write_all(ec: &mut EditorConfig) -> Result<usize, String> {
+    let mut written = 0;
+    for i in 0..ec.buffers.len() {
+        if !ec.buffers[i].dirty {continue}
+        std::mem::swap(&mut ec.buf, &mut ec.buffers[i]);
+        let result = save(ec);
+        std::mem::swap(&mut ec.buf, &mut ec.buffers[i]);
+        result?;
+        written += 1;
+    }
+    if ec.buf.dirty {
+        save(ec)?;
+        written += 1;
+    }
+    Ok(written)
+}
@@ -0,0 +1,70 @@
+use std::{io, sync::mpsc::{self, Receiver, RecvTimeoutError}, thread, time::{Duration, Instant}};
+use crossterm::event::{self, Event};
+
+// What the main loop wakes up for
+pub enum EditorEvent {
+    Input(Event),
+    Timer(Timer),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Timer {
+    KeySequence, // jk in insert mode is given up on
+}
+
+// Terminal input comes in from its own thread, so waiting for it can also wait for the next timer
+// instead of polling
+pub struct Events {
+    input: Receiver<io::Result<Event>>,
+    timers: Vec<(Instant, Timer)>,
+}
+
+impl Events {
+    pub fn start() -> Events {
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || loop {
+            let event = event::read();
+            let failed = event.is_err();
+            if sender.send(event).is_err() || failed {break}
+        });
+        Events { input, timers: vec![] }
+    }
+
+    // No terminal behind it, waiting for input fails at once
+    #[cfg(test)]
+    pub fn none() -> Events {
+        Events { input: mpsc::channel().1, timers: vec![] }
+    }
+
+    // Fire timer after delay, instead of when it was set for before
+    pub fn set_timer(&mut self, timer: Timer, delay: Duration) {
+        self.cancel_timer(timer);
+        self.timers.push((Instant::now() + delay, timer));
+    }
+
+    pub fn cancel_timer(&mut self, timer: Timer) {
+        self.timers.retain(|&(_, t)| t != timer);
+    }
+
+    #[cfg(test)]
+    pub fn is_set(&self, timer: Timer) -> bool {
+        self.timers.iter().any(|&(_, t)| t == timer)
+    }
+
+    // Block until there is input or a timer is due
+    pub fn next(&mut self) -> io::Result<EditorEvent> {
+        let closed = || io::Error::new(io::ErrorKind::BrokenPipe, "terminal input closed");
+        let Some(i) = (0..self.timers.len()).min_by_key(|&i| self.timers[i].0) else {
+            return self.input.recv().map_err(|_| closed())?.map(EditorEvent::Input);
+        };
+        let (at, timer) = self.timers[i];
+        match self.input.recv_timeout(at.saturating_duration_since(Instant::now())) {
+            Ok(event) => event.map(EditorEvent::Input),
+            Err(RecvTimeoutError::Timeout) => {
+                self.timers.remove(i);
+                Ok(EditorEvent::Timer(timer))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(closed()),
+        }
+    }
+}
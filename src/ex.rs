@@ -0,0 +1,628 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{stdout, Write},
+    path::Path,
+    process::{exit, Command, Stdio},
+};
+use crossterm::{terminal::{disable_raw_mode, LeaveAlternateScreen}, ExecutableCommand};
+use crate::{
+    buffer, buffer_text, editor_close, editor_open, editor_save, filetype, insert_row, search, set_status_message, substitute,
+    options, tab, undo, window,
+    utils::{expand_path, leading_whitespace},
+    EditorConfig,
+};
+
+// Lines are 0-based and inclusive once parsed
+pub type Range = (usize, usize);
+
+// A single line address: N, ., $, 'x followed by any number of +N / -N
+fn parse_address<'a>(ec: &EditorConfig, s: &'a str) -> Result<(Option<isize>, &'a str), String> {
+    let mut chars = s.chars();
+    let (line, mut rest) = match chars.next() {
+        Some('.') => (Some(ec.cy as isize), chars.as_str()),
+        Some('$') => (Some(ec.buf.numrows as isize - 1), chars.as_str()),
+        Some('\'') => {
+            let (start, end) = ec.buf.visual_marks.ok_or("E20: Mark not set")?;
+            match chars.next() {
+                Some('<') => (Some(start.1 as isize), chars.as_str()),
+                Some('>') => (Some(end.1 as isize), chars.as_str()),
+                _ => return Err(String::from("E20: Mark not set")),
+            }
+        }
+        Some(c) if c.is_ascii_digit() => {
+            let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+            let n: isize = s[..digits].parse().map_err(|_| "E16: Invalid range")?;
+            (Some(n - 1), &s[digits..])
+        }
+        // a bare offset is relative to the cursor
+        Some('+' | '-') => (Some(ec.cy as isize), s),
+        _ => (None, s),
+    };
+    let Some(mut line) = line else {return Ok((None, rest))};
+
+    while let Some(sign) = rest.chars().next().filter(|c| *c == '+' || *c == '-') {
+        rest = &rest[1..];
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let n: isize = if digits == 0 {1} else {rest[..digits].parse().map_err(|_| "E16: Invalid range")?};
+        rest = &rest[digits..];
+        line += if sign == '+' {n} else {-n};
+    }
+    Ok((Some(line), rest))
+}
+
+// The range in front of a command, and the rest of the command after it
+pub fn parse_range<'a>(ec: &EditorConfig, cmd: &'a str) -> Result<(Option<Range>, &'a str), String> {
+    let cmd = cmd.trim_start();
+    if let Some(rest) = cmd.strip_prefix('%') {
+        return Ok((Some((0, ec.buf.numrows - 1)), rest));
+    }
+    let (first, rest) = parse_address(ec, cmd)?;
+    let (first, last, rest) = match rest.strip_prefix([',', ';']) {
+        Some(rest) => {
+            // either side of the comma defaults to the current line
+            let (last, rest) = parse_address(ec, rest)?;
+            (first.unwrap_or(ec.cy as isize), last.unwrap_or(ec.cy as isize), rest)
+        }
+        None => match first {
+            Some(first) => (first, first, rest),
+            None => return Ok((None, rest)),
+        },
+    };
+    let valid = 0..ec.buf.numrows as isize;
+    if !valid.contains(&first) || !valid.contains(&last) {
+        return Err(String::from("E16: Invalid range"));
+    }
+    // backwards ranges are swapped instead of asking
+    let (first, last) = (first.min(last) as usize, first.max(last) as usize);
+    Ok((Some((first, last)), rest))
+}
+
+// One parsed command from the command line
+pub struct Cmd<'a> {
+    pub range: Option<Range>,
+    pub bang: bool,
+    pub args: &'a str,
+}
+
+type Handler = fn(&mut EditorConfig, &Cmd) -> Result<(), String>;
+
+struct ExCommand {
+    name: &'static str,
+    abbrev: usize, // shortest prefix that is accepted
+    range: bool,
+    bang: bool,
+    args: bool,
+    run: Handler,
+}
+
+// Earlier entries win when an abbreviation matches more than one
+const COMMANDS: &[ExCommand] = &[
+    ExCommand { name: "write", abbrev: 1, range: true, bang: true, args: true, run: write },
+    ExCommand { name: "wq", abbrev: 2, range: true, bang: true, args: true, run: write_quit },
+    ExCommand { name: "wall", abbrev: 2, range: false, bang: true, args: false, run: write_all },
+    ExCommand { name: "wqall", abbrev: 3, range: false, bang: true, args: false, run: write_quit_all },
+    ExCommand { name: "quit", abbrev: 1, range: false, bang: true, args: false, run: quit },
+    ExCommand { name: "qall", abbrev: 2, range: false, bang: true, args: false, run: quit_all },
+    ExCommand { name: "xit", abbrev: 1, range: true, bang: true, args: true, run: xit },
+    ExCommand { name: "xall", abbrev: 2, range: false, bang: true, args: false, run: write_quit_all },
+    ExCommand { name: "exit", abbrev: 3, range: true, bang: true, args: true, run: xit },
+    ExCommand { name: "edit", abbrev: 1, range: false, bang: true, args: true, run: edit },
+    ExCommand { name: "read", abbrev: 1, range: true, bang: false, args: true, run: read },
+    ExCommand { name: "saveas", abbrev: 3, range: false, bang: true, args: true, run: saveas },
+    ExCommand { name: "buffer", abbrev: 1, range: false, bang: false, args: true, run: buffer },
+    ExCommand { name: "bnext", abbrev: 2, range: false, bang: false, args: true, run: buffer_next },
+    ExCommand { name: "bprevious", abbrev: 2, range: false, bang: false, args: true, run: buffer_previous },
+    ExCommand { name: "bNext", abbrev: 2, range: false, bang: false, args: true, run: buffer_previous },
+    ExCommand { name: "bdelete", abbrev: 2, range: false, bang: true, args: true, run: buffer_delete },
+    ExCommand { name: "split", abbrev: 2, range: false, bang: false, args: true, run: hsplit },
+    ExCommand { name: "vsplit", abbrev: 2, range: false, bang: false, args: true, run: vsplit },
+    ExCommand { name: "close", abbrev: 3, range: false, bang: true, args: false, run: close },
+    ExCommand { name: "only", abbrev: 2, range: false, bang: true, args: false, run: only },
+    ExCommand { name: "tabnext", abbrev: 4, range: false, bang: false, args: true, run: tab_next },
+    ExCommand { name: "tabnew", abbrev: 6, range: false, bang: false, args: true, run: tab_new },
+    ExCommand { name: "tabedit", abbrev: 4, range: false, bang: false, args: true, run: tab_new },
+    ExCommand { name: "tabprevious", abbrev: 4, range: false, bang: false, args: true, run: tab_previous },
+    ExCommand { name: "tabNext", abbrev: 4, range: false, bang: false, args: true, run: tab_previous },
+    ExCommand { name: "tabclose", abbrev: 4, range: false, bang: true, args: false, run: tab_close },
+    ExCommand { name: "tabonly", abbrev: 4, range: false, bang: true, args: false, run: tab_only },
+    ExCommand { name: "ls", abbrev: 2, range: false, bang: false, args: false, run: list },
+    ExCommand { name: "buffers", abbrev: 7, range: false, bang: false, args: false, run: list },
+    ExCommand { name: "files", abbrev: 5, range: false, bang: false, args: false, run: list },
+    ExCommand { name: "substitute", abbrev: 1, range: true, bang: false, args: true, run: substitute },
+    ExCommand { name: "nohlsearch", abbrev: 3, range: false, bang: false, args: false, run: nohlsearch },
+    ExCommand { name: "earlier", abbrev: 2, range: false, bang: false, args: true, run: earlier },
+    ExCommand { name: "later", abbrev: 3, range: false, bang: false, args: true, run: later },
+    ExCommand { name: "set", abbrev: 2, range: false, bang: false, args: true, run: set },
+];
+
+fn lookup(name: &str) -> Option<&'static ExCommand> {
+    COMMANDS.iter().find(|c| name.len() >= c.abbrev && c.name.starts_with(name))
+}
+
+// Split off the next command at an unescaped |
+fn split_bar(s: &str) -> (&str, Option<&str>) {
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            '|' if !escaped => return (&s[..i], Some(&s[i + 1..])),
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+    (s, None)
+}
+
+// Split off the next command after a :s, its pattern and replacement can hold a | between the
+// delimiters. Only the flags after them end at one
+fn split_substitute(s: &str) -> (&str, Option<&str>) {
+    let start = s.len() - s.trim_start().len();
+    let mut chars = s[start..].char_indices();
+    let Some((_, delim)) = chars.next() else {return (s, None)};
+    if delim.is_alphanumeric() || ['\\', '"', '|'].contains(&delim) {return split_bar(s)}
+    let (mut fields, mut escaped) = (0, false);
+    for (i, c) in chars {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == delim {
+            fields += 1;
+            if fields < 2 {continue}
+            let end = start + i + c.len_utf8();
+            let (flags, next) = split_bar(&s[end..]);
+            return (&s[..end + flags.len()], next);
+        }
+    }
+    (s, None)
+}
+
+// Run everything typed after :, stopping at the first command that fails
+pub fn run(ec: &mut EditorConfig, line: &str) -> Result<(), String> {
+    let mut next = Some(line);
+    while let Some(line) = next {
+        next = execute(ec, line)?;
+    }
+    Ok(())
+}
+
+fn execute<'a>(ec: &mut EditorConfig, line: &'a str) -> Result<Option<&'a str>, String> {
+    let (range, rest) = parse_range(ec, line)?;
+    let rest = rest.trim_start();
+    let len = rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len());
+    let (name, rest) = rest.split_at(len);
+
+    if name.is_empty() {
+        let (rest, next) = split_bar(rest);
+        if !rest.trim().is_empty() {return Err(format!("E492: Not an editor command: {}", line.trim()))}
+        // a range on its own jumps to the last line of it
+        if let Some((_, last)) = range {
+            ec.cy = last;
+            ec.cx = leading_whitespace(ec.buf.line(last));
+        }
+        return Ok(next);
+    }
+
+    let command = lookup(name).ok_or_else(|| format!("E492: Not an editor command: {}", line.trim()))?;
+    let (bang, rest) = match rest.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    // a shell command gets the rest of the line, | and all
+    let (args, next) = if rest.trim_start().starts_with('!') {(rest, None)}
+        else if command.name == "substitute" {split_substitute(rest)}
+        else {split_bar(rest)};
+    // trailing white space is part of a :s replacement. The other commands drop it, but for a
+    // space escaped with a backslash, for :set sbr=>\<space>
+    let args = args.trim_start();
+    let trimmed = args.trim_end();
+    let args = if command.name == "substitute" {args}
+        else if trimmed.ends_with('\\') && trimmed.len() < args.len() {&args[..trimmed.len() + 1]}
+        else {trimmed};
+    if range.is_some() && !command.range {return Err(String::from("E481: No range allowed"))}
+    if bang && !command.bang {return Err(String::from("E477: No ! allowed"))}
+    if !args.trim_end().is_empty() && !command.args {return Err(format!("E488: Trailing characters: {}", args))}
+
+    (command.run)(ec, &Cmd { range, bang, args })?;
+    Ok(next)
+}
+
+fn leave() -> ! {
+    let _ = disable_raw_mode();
+    let _ = stdout().execute(LeaveAlternateScreen);
+    exit(0)
+}
+
+// Run cmd with sh, feeding it input, and return what it printed
+fn shell(cmd: &str, input: Option<String>) -> Result<String, String> {
+    let mut child = Command::new("sh").arg("-c").arg(cmd)
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+        .spawn().map_err(|e| format!("E282: Cannot execute shell: {}", e))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        let _ = stdin.write_all(input.as_bytes());
+    }
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    if !output.status.success() {
+        return Err(format!("shell returned {}", output.status.code().unwrap_or(-1)));
+    }
+    Ok(text)
+}
+
+fn written(path: &str, lines: usize, verb: &str) -> String {
+    format!("{} {}L {}", path, lines, verb)
+}
+
+// :w, :w file, :w >> file and :w !cmd, all of them on a range if given
+fn write(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    let (first, last) = cmd.range.unwrap_or((0, ec.buf.numrows - 1));
+    let lines = last - first + 1;
+
+    if let Some(shell_cmd) = cmd.args.strip_prefix('!') {
+        let output = shell(shell_cmd.trim(), Some(buffer_text(ec, first, last)))?;
+        let _ = set_status_message(ec, output.lines().last().unwrap_or_default().to_string());
+        return Ok(());
+    }
+    if let Some(path) = cmd.args.strip_prefix(">>") {
+        let path = if path.trim().is_empty() {ec.buf.filename.clone()} else {expand_path(path.trim())};
+        let mut file = OpenOptions::new().append(true).open(&path).map_err(|_| format!("E212: Can't open file for writing: {}", path))?;
+        // files are written without a trailing newline, so start a new line for the appended text
+        let separator = if fs::metadata(&path).map(|m| m.len() > 0).unwrap_or(false) {"\n"} else {""};
+        file.write_all(format!("{}{}", separator, buffer_text(ec, first, last)).as_bytes()).map_err(|e| e.to_string())?;
+        let _ = set_status_message(ec, written(&path, lines, "appended"));
+        return Ok(());
+    }
+
+    let path = if cmd.args.is_empty() {ec.buf.filename.clone()} else {expand_path(cmd.args)};
+    if path.is_empty() {return Err(String::from("E32: No file name"))}
+    let whole = cmd.range.is_none() || lines == ec.buf.numrows;
+    if path == ec.buf.filename {
+        if !whole && !cmd.bang {return Err(String::from("E140: Use ! to write partial buffer"))}
+        if whole {
+            editor_save(ec).map_err(|_| format!("E212: Can't open file for writing: {}", path))?;
+            let _ = set_status_message(ec, written(&path, lines, "written"));
+            return Ok(());
+        }
+    } else if Path::new(&path).exists() && !cmd.bang {
+        return Err(String::from("E13: File exists (add ! to override)"));
+    }
+    fs::write(&path, buffer_text(ec, first, last)).map_err(|_| format!("E212: Can't open file for writing: {}", path))?;
+    let _ = set_status_message(ec, written(&path, lines, "written"));
+    Ok(())
+}
+
+// Modified buffers that are not on screen would be lost on quitting
+fn check_hidden(ec: &EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    match buffer::hidden_dirty(ec) {
+        Some(buf) if !cmd.bang => Err(format!("E162: No write since last change for buffer \"{}\"", buf.name())),
+        _ => Ok(()),
+    }
+}
+
+// With more than one window :q only closes this one, and in the last window of a tab the tab
+fn quit(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    if ec.windows.len() > 1 {return window::close(ec)}
+    if ec.tabs.len() > 1 {return tab::close(ec)}
+    if ec.buf.dirty && !cmd.bang {
+        return Err(String::from("E37: No write since last change (add ! to override)"));
+    }
+    check_hidden(ec, cmd)?;
+    leave()
+}
+
+fn write_quit(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    write(ec, cmd)?;
+    if ec.windows.len() > 1 {return window::close(ec)}
+    if ec.tabs.len() > 1 {return tab::close(ec)}
+    check_hidden(ec, cmd)?;
+    leave()
+}
+
+// :x only writes when there is something to write
+fn xit(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    if ec.buf.dirty || !cmd.args.is_empty() {write(ec, cmd)?}
+    if ec.windows.len() > 1 {return window::close(ec)}
+    if ec.tabs.len() > 1 {return tab::close(ec)}
+    check_hidden(ec, cmd)?;
+    leave()
+}
+
+// :e file opens another file in its own buffer and :e on its own reloads this one. Either needs !
+// when this one has changes, :e! on its own throws them away
+fn edit(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    let path = if cmd.args.is_empty() {ec.buf.filename.clone()} else {expand_path(cmd.args)};
+    if path.is_empty() {return Err(String::from("E32: No file name"))}
+    // changes are only left behind in another buffer with !
+    if ec.buf.dirty && !cmd.bang {
+        return Err(String::from("E37: No write since last change (add ! to override)"));
+    }
+    if path != ec.buf.filename {
+        return buffer::open(ec, path.clone()).map_err(|e| format!("E484: Can't open file {}: {}", path, e));
+    }
+    let old = editor_close(ec);
+    if let Err(e) = editor_open(ec, path.clone()) {
+        (ec.cx, ec.cy) = old.cursor;
+        ec.rowoff = old.rowoff;
+        ec.buf = old;
+        return Err(format!("E484: Can't open file {}: {}", path, e));
+    }
+    if ec.status_msg != "new file" {
+        let _ = set_status_message(ec, format!("{} {}L", path, ec.buf.numrows));
+    }
+    Ok(())
+}
+
+// :split [file] and :vsplit [file]
+fn split(ec: &mut EditorConfig, cmd: &Cmd, vertical: bool) -> Result<(), String> {
+    window::split(ec, vertical)?;
+    if cmd.args.is_empty() {return Ok(())}
+    let path = expand_path(cmd.args);
+    buffer::open(ec, path.clone()).map_err(|e| format!("E484: Can't open file {}: {}", path, e))
+}
+
+fn hsplit(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    split(ec, cmd, false)
+}
+
+fn vsplit(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    split(ec, cmd, true)
+}
+
+fn close(ec: &mut EditorConfig, _cmd: &Cmd) -> Result<(), String> {
+    window::close(ec)
+}
+
+fn only(ec: &mut EditorConfig, _cmd: &Cmd) -> Result<(), String> {
+    window::only(ec);
+    Ok(())
+}
+
+// :tabnew [file]
+fn tab_new(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    tab::new(ec);
+    if cmd.args.is_empty() {return Ok(())}
+    let path = expand_path(cmd.args);
+    buffer::open(ec, path.clone()).map_err(|e| format!("E484: Can't open file {}: {}", path, e))
+}
+
+// :tabn goes to the next tab, or to tab N
+fn tab_next(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    if cmd.args.is_empty() {
+        tab::next(ec, 1);
+        return Ok(());
+    }
+    tab::goto(ec, count(cmd)?)
+}
+
+fn tab_previous(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    tab::previous(ec, count(cmd)?);
+    Ok(())
+}
+
+fn tab_close(ec: &mut EditorConfig, _cmd: &Cmd) -> Result<(), String> {
+    tab::close(ec)
+}
+
+fn tab_only(ec: &mut EditorConfig, _cmd: &Cmd) -> Result<(), String> {
+    tab::only(ec);
+    Ok(())
+}
+
+// :ls
+fn list(ec: &mut EditorConfig, _cmd: &Cmd) -> Result<(), String> {
+    let _ = set_status_message(ec, buffer::list(ec));
+    Ok(())
+}
+
+// :b N or :b name
+fn buffer(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    if cmd.args.is_empty() {return Ok(())}
+    let id = buffer::find(ec, cmd.args)?;
+    buffer::switch(ec, id)
+}
+
+fn count(cmd: &Cmd) -> Result<usize, String> {
+    if cmd.args.is_empty() {return Ok(1)}
+    cmd.args.parse().map_err(|_| format!("E488: Trailing characters: {}", cmd.args))
+}
+
+fn buffer_next(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    buffer::cycle(ec, count(cmd)?, true)
+}
+
+fn buffer_previous(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    buffer::cycle(ec, count(cmd)?, false)
+}
+
+// :bd [N|name], the current buffer when none is given
+fn buffer_delete(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    let id = if cmd.args.is_empty() {ec.buf.id} else {buffer::find(ec, cmd.args)?};
+    buffer::delete(ec, id, cmd.bang)
+}
+
+fn write_all(ec: &mut EditorConfig, _cmd: &Cmd) -> Result<(), String> {
+    let written = buffer::write_all(ec)?;
+    let _ = set_status_message(ec, format!("{} buffer{} written", written, if written == 1 {""} else {"s"}));
+    Ok(())
+}
+
+fn quit_all(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    if ec.buf.dirty && !cmd.bang {
+        return Err(String::from("E37: No write since last change (add ! to override)"));
+    }
+    check_hidden(ec, cmd)?;
+    leave()
+}
+
+// :wqa and :xa
+fn write_quit_all(ec: &mut EditorConfig, _cmd: &Cmd) -> Result<(), String> {
+    buffer::write_all(ec)?;
+    leave()
+}
+
+// :r file and :r !cmd put the text below the cursor, or below the line given as the range
+fn read(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    let text = if let Some(shell_cmd) = cmd.args.strip_prefix('!') {
+        shell(shell_cmd.trim(), None)?
+    } else {
+        if cmd.args.is_empty() {return Err(String::from("E32: No file name"))}
+        let path = expand_path(cmd.args);
+        fs::read_to_string(&path).map_err(|_| format!("E484: Can't open file {}", path))?
+    };
+    let below = cmd.range.map(|(_, last)| last).unwrap_or(ec.cy);
+    let mut y = below;
+    for line in text.lines() {
+        y += 1;
+        insert_row(ec, y, line.to_string());
+    }
+    if y > below {
+        ec.cy = below + 1;
+        ec.cx = leading_whitespace(ec.buf.line(ec.cy));
+    }
+    ec.dirty_rows.extend(0..ec.screenrows);
+    Ok(())
+}
+
+// :saveas file writes to file and makes it the current one
+fn saveas(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    if cmd.args.is_empty() {return Err(String::from("E471: Argument required"))}
+    let path = expand_path(cmd.args);
+    if path != ec.buf.filename && Path::new(&path).exists() && !cmd.bang {
+        return Err(String::from("E13: File exists (add ! to override)"));
+    }
+    ec.buf.filename = path.clone();
+    // a new name can tell what the text is when the old one couldn't
+    if ec.buf.filetype.is_empty() {ec.buf.filetype = filetype::detect(&ec.syntaxes, &ec.buf)}
+    editor_save(ec).map_err(|_| format!("E212: Can't open file for writing: {}", path))?;
+    let _ = set_status_message(ec, written(&path, ec.buf.numrows, "written"));
+    Ok(())
+}
+
+fn substitute(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    let range = cmd.range.unwrap_or((ec.cy, ec.cy));
+    substitute::substitute(ec, range, cmd.args)
+}
+
+fn nohlsearch(ec: &mut EditorConfig, _cmd: &Cmd) -> Result<(), String> {
+    search::clear_highlight(ec);
+    Ok(())
+}
+
+fn earlier(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    undo::travel(ec, cmd.args, false)
+}
+
+fn later(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    undo::travel(ec, cmd.args, true)
+}
+
+fn set(ec: &mut EditorConfig, cmd: &Cmd) -> Result<(), String> {
+    options::set(ec, cmd.args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(ec: &EditorConfig, cmd: &str) -> Result<Option<Range>, String> {
+        parse_range(ec, cmd).map(|(range, _)| range)
+    }
+
+    #[test]
+    fn ranges() {
+        let mut ec = EditorConfig::test("1\n2\n3\n4\n5");
+        ec.cy = 2;
+        assert_eq!(range(&ec, "s"), Ok(None));
+        assert_eq!(range(&ec, "."), Ok(Some((2, 2))));
+        assert_eq!(range(&ec, "$"), Ok(Some((4, 4))));
+        assert_eq!(range(&ec, "%"), Ok(Some((0, 4))));
+        assert_eq!(range(&ec, "2,4"), Ok(Some((1, 3))));
+        assert_eq!(range(&ec, "4,2"), Ok(Some((1, 3))));
+        assert_eq!(range(&ec, ",$"), Ok(Some((2, 4))));
+        assert_eq!(range(&ec, "+"), Ok(Some((3, 3))));
+        assert_eq!(range(&ec, "-2,.+1"), Ok(Some((0, 3))));
+        assert_eq!(range(&ec, "1+1+1"), Ok(Some((2, 2))));
+        assert_eq!(range(&ec, "6"), Err(String::from("E16: Invalid range")));
+        assert_eq!(range(&ec, "-3"), Err(String::from("E16: Invalid range")));
+        assert_eq!(range(&ec, "'<"), Err(String::from("E20: Mark not set")));
+        ec.buf.visual_marks = Some(((0, 1), (0, 3)));
+        assert_eq!(range(&ec, "'<,'>"), Ok(Some((1, 3))));
+        assert_eq!(parse_range(&ec, " 2,3 s/a/b/").map(|(_, rest)| rest), Ok(" s/a/b/"));
+    }
+
+    #[test]
+    fn bars_split_commands() {
+        assert_eq!(split_bar("set ts=2|set sw=2"), ("set ts=2", Some("set sw=2")));
+        assert_eq!(split_bar("e a\\|b"), ("e a\\|b", None));
+        assert_eq!(split_bar("e a\\\\|b"), ("e a\\\\", Some("b")));
+        assert_eq!(split_substitute("/a|b/c|d/g|noh"), ("/a|b/c|d/g", Some("noh")));
+        assert_eq!(split_substitute("/a\\/|b/c/ | noh"), ("/a\\/|b/c/ ", Some(" noh")));
+        assert_eq!(split_substitute("/a/b|noh"), ("/a/b|noh", None));
+        assert_eq!(split_substitute("|noh"), ("", Some("noh")));
+    }
+
+    #[test]
+    fn names_abbreviate_down_to_their_shortest_prefix() {
+        for (name, full) in [("w", "write"), ("write", "write"), ("q", "quit"), ("quit", "quit"), ("wq", "wq"),
+            ("wa", "wall"), ("e", "edit"), ("s", "substitute"), ("noh", "nohlsearch"), ("se", "set"), ("tabn", "tabnext")] {
+            assert_eq!(lookup(name).map(|c| c.name), Some(full));
+        }
+        for name in ["no", "writes", "tab"] {
+            assert!(lookup(name).is_none(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let mut ec = EditorConfig::test("one\ntwo");
+        assert_eq!(run(&mut ec, "frobnicate"), Err(String::from("E492: Not an editor command: frobnicate")));
+        assert_eq!(run(&mut ec, "1,2ls"), Err(String::from("E481: No range allowed")));
+        assert_eq!(run(&mut ec, "ls!"), Err(String::from("E477: No ! allowed")));
+        assert_eq!(run(&mut ec, "noh now"), Err(String::from("E488: Trailing characters: now")));
+        assert_eq!(run(&mut ec, "2foo"), Err(String::from("E492: Not an editor command: 2foo")));
+        ec.keys(":bogus<CR>");
+        assert_eq!(ec.status_msg, "E492: Not an editor command: bogus");
+        assert!(ec.mode == crate::Mode::Normal);
+    }
+
+    #[test]
+    fn ranges_bangs_arguments_and_chains() {
+        let mut ec = EditorConfig::test("1\n2\n3\n4");
+        assert_eq!(run(&mut ec, "3"), Ok(()));
+        assert_eq!(ec.cy, 2);
+        assert_eq!(run(&mut ec, "set ts=2 | set sw=3|1"), Ok(()));
+        assert_eq!((ec.options.tabstop, ec.options.shiftwidth, ec.cy), (2, 3, 0));
+        // a failing command stops the chain
+        assert!(run(&mut ec, "bogus|3").is_err());
+        assert_eq!(ec.cy, 0);
+
+        let path = std::env::temp_dir().join("rim-ex-write.txt").to_string_lossy().into_owned();
+        assert_eq!(run(&mut ec, &format!("2,3write! {}  ", path)), Ok(()));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "2\n3");
+        assert_eq!(run(&mut ec, &format!("w! {}", path)), Ok(()));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1\n2\n3\n4");
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn substitute_keeps_trailing_spaces() {
+        let mut ec = EditorConfig::test("a");
+        assert_eq!(run(&mut ec, "s/a/b  "), Ok(()));
+        assert_eq!(ec.lines(), ["b  "]);
+        assert_eq!(run(&mut ec, "s/b/c/|s/c/d/"), Ok(()));
+        assert_eq!(ec.lines(), ["d  "]);
+    }
+
+    #[test]
+    fn edit_keeps_a_changed_buffer() {
+        let mut ec = EditorConfig::test("one");
+        ec.buf.filename = String::from("one.txt");
+        ec.keys("x");
+        let other = std::env::temp_dir().join("rim-edit-other.txt").to_string_lossy().into_owned();
+        assert_eq!(run(&mut ec, &format!("e {}", other)), Err(String::from("E37: No write since last change (add ! to override)")));
+        assert_eq!(ec.buf.filename, "one.txt");
+        assert_eq!(run(&mut ec, &format!("e! {}", other)), Ok(()));
+        assert_eq!(ec.buf.filename, other);
+        assert!(ec.buffers.iter().any(|b| b.filename == "one.txt" && b.dirty));
+    }
+}
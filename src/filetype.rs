@@ -0,0 +1,211 @@
+use std::{fs, rc::Rc};
+use crate::{buffer::Buffer, syntax::Syntax};
+
+// Files known by name that no syntax claims, as (pattern, filetype). A pattern with a / is matched
+// against the whole path, others against the file name
+const PATTERNS: &[(&str, &str)] = &[
+    ("Makefile", "make"), ("makefile", "make"), ("GNUmakefile", "make"), ("*.mk", "make"),
+    ("Dockerfile", "dockerfile"), ("Containerfile", "dockerfile"), ("Dockerfile.*", "dockerfile"), ("*.dockerfile", "dockerfile"),
+    (".gitconfig", "gitconfig"), ("*/.git/config", "gitconfig"), ("COMMIT_EDITMSG", "gitcommit"),
+    ("CMakeLists.txt", "cmake"), ("*.cmake", "cmake"),
+];
+
+// Programs on a #! line, version numbers taken off (python3.11 is python)
+const INTERPRETERS: &[(&str, &str)] = &[
+    ("make", "make"), ("perl", "perl"), ("ruby", "ruby"), ("php", "php"), ("awk", "awk"), ("gawk", "awk"),
+];
+
+// Other names for a filetype, from emacs modes and the like
+const ALIASES: &[(&str, &str)] = &[
+    ("bash", "sh"), ("shell-script", "sh"), ("c++", "c"), ("cpp", "c"), ("makefile", "make"),
+    ("makefile-gmake", "make"), ("js", "javascript"), ("gfm", "markdown"), ("shell", "sh"), ("console", "sh"),
+    ("golang", "go"), ("python3", "python"), ("ts", "typescript"), ("yml", "yaml"),
+];
+
+// Left behind by backups and patches, foo.rs.orig is still rust
+const BACKUP_SUFFIXES: &[&str] = &["~", ".orig", ".bak", ".old", ".rej", ".swp", ".rpmnew", ".rpmsave", ".dpkg-dist", ".dpkg-old"];
+
+// How many lines at the start and end are checked for a vim modeline
+const MODELINES: usize = 5;
+
+fn alias(filetype: &str) -> String {
+    ALIASES.iter().find(|(name, _)| *name == filetype).map_or(filetype, |(_, to)| to).to_string()
+}
+
+// The syntax for the language a Markdown code block names, like rust, py or bash
+pub fn syntax_for(syntaxes: &[Rc<Syntax>], language: &str) -> Option<Rc<Syntax>> {
+    if language.is_empty() {return None}
+    let language = alias(&language.to_lowercase());
+    syntaxes.iter().find(|s| s.name == language || s.extensions.contains(&language)).cloned()
+}
+
+// Filetype of a buffer, empty when it isn't recognised. A modeline wins over the file's name,
+// which wins over its #! line
+pub fn detect(syntaxes: &[Rc<Syntax>], buf: &Buffer) -> String {
+    let lines = buf.numrows;
+    let modelines = (0..lines.min(MODELINES)).chain(lines.saturating_sub(MODELINES).max(MODELINES)..lines);
+    for y in modelines {
+        if let Some(filetype) = vim_modeline(&buf.line(y)) {return filetype}
+    }
+    // emacs only looks at the first line, or the second after a #! line
+    for y in 0..lines.min(2) {
+        if let Some(mode) = emacs_modeline(&buf.line(y)) {return alias(&mode)}
+    }
+    let first = if lines > 0 {buf.line(0)} else {String::new()};
+    by_name(syntaxes, &buf.filename).or_else(|| by_interpreter(syntaxes, &first)).unwrap_or_default()
+}
+
+fn by_name(syntaxes: &[Rc<Syntax>], path: &str) -> Option<String> {
+    if path.is_empty() {return None}
+    let name = path.rsplit('/').next().unwrap_or(path);
+    // a leading dot hides a file, it doesn't start an extension
+    let extension = name.rsplit_once('.').filter(|(stem, _)| !stem.is_empty()).map(|(_, e)| e);
+    let full = fs::canonicalize(path).map(|p| p.to_string_lossy().into_owned()).unwrap_or(path.to_string());
+    let matches = |pattern: &str| glob(pattern, if pattern.contains('/') {&full} else {name});
+
+    let syntax = syntaxes.iter().find(|s| {
+        s.filenames.iter().any(|f| f == name)
+            || s.globs.iter().any(|g| matches(g))
+            || extension.is_some_and(|e| s.extensions.iter().any(|x| x == e))
+    });
+    if let Some(syntax) = syntax {return Some(syntax.name.clone())}
+    if let Some((_, filetype)) = PATTERNS.iter().find(|(pattern, _)| matches(pattern)) {return Some(filetype.to_string())}
+    let stripped = BACKUP_SUFFIXES.iter().find_map(|suffix| path.strip_suffix(suffix))?;
+    by_name(syntaxes, stripped)
+}
+
+// * matches any run of characters, ? any one
+fn glob(pattern: &str, text: &str) -> bool {
+    match pattern.chars().next() {
+        None => text.is_empty(),
+        Some('*') => text.char_indices().map(|(i, _)| i).chain([text.len()]).any(|i| glob(&pattern[1..], &text[i..])),
+        Some('?') => text.chars().next().is_some_and(|c| glob(&pattern[1..], &text[c.len_utf8()..])),
+        Some(p) => text.starts_with(p) && glob(&pattern[p.len_utf8()..], &text[p.len_utf8()..]),
+    }
+}
+
+// The program a #! line runs, looking past env
+fn interpreter(line: &str) -> Option<&str> {
+    let mut words = line.strip_prefix("#!")?.split_whitespace();
+    let name = words.next()?.rsplit('/').next()?;
+    if name != "env" {return Some(name)}
+    words.find(|w| !w.starts_with('-'))?.rsplit('/').next()
+}
+
+fn by_interpreter(syntaxes: &[Rc<Syntax>], line: &str) -> Option<String> {
+    let program = interpreter(line)?;
+    let unversioned = program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    let known = |name: &str| name == program || name == unversioned;
+    if let Some(syntax) = syntaxes.iter().find(|s| s.shebangs.iter().any(|s| known(s))) {return Some(syntax.name.clone())}
+    INTERPRETERS.iter().find(|(name, _)| known(name)).map(|(_, filetype)| filetype.to_string())
+}
+
+// ft= from "vim: set ft=rust:" or "vim: ft=rust ts=4", also after vi: and ex:
+fn vim_modeline(line: &str) -> Option<String> {
+    for marker in ["vim:", "Vim:", "vi:", "ex:"] {
+        for (at, _) in line.match_indices(marker) {
+            // only vim: may start the line
+            let start = if at == 0 {marker.starts_with("vim") || marker.starts_with("Vim")} else {line[..at].ends_with(char::is_whitespace)};
+            if !start {continue}
+            let rest = line[at + marker.len()..].trim_start();
+            let options: Vec<&str> = match rest.strip_prefix("set ").or(rest.strip_prefix("se ")) {
+                Some(set) => set.split(':').next().unwrap_or_default().split_whitespace().collect(),
+                None => rest.split(|c: char| c == ':' || c.is_whitespace()).collect(),
+            };
+            let filetype = options.iter().find_map(|o| o.strip_prefix("ft=").or(o.strip_prefix("filetype=")));
+            if let Some(filetype) = filetype {return Some(filetype.to_string())}
+        }
+    }
+    None
+}
+
+// -*- mode: python -*- or just -*- python -*-
+fn emacs_modeline(line: &str) -> Option<String> {
+    let (_, rest) = line.split_once("-*-")?;
+    let (vars, _) = rest.split_once("-*-")?;
+    let mode = if vars.contains(':') {
+        vars.split(';').find_map(|var| {
+            let (name, value) = var.split_once(':')?;
+            name.trim().eq_ignore_ascii_case("mode").then(|| value.trim())
+        })?
+    } else {
+        vars.trim()
+    };
+    Some(mode.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax;
+
+    fn detect_in(filename: &str, text: &str) -> String {
+        let mut buf = Buffer::new(1);
+        buf.filename = filename.to_string();
+        buf.load(text);
+        detect(&syntax::builtin(), &buf)
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob("*.mk", "rules.mk"));
+        assert!(glob("*.mk", ".mk"));
+        assert!(!glob("*.mk", "rules.mkd"));
+        assert!(glob("Dockerfile.*", "Dockerfile.dev"));
+        assert!(!glob("Dockerfile.*", "Dockerfile"));
+        assert!(glob("?akefile", "Makefile"));
+        assert!(!glob("?akefile", "akefile"));
+        assert!(glob("*/.git/config", "/src/rim/.git/config"));
+        assert!(glob("a*b*c", "aé_b_c"));
+        assert_eq!(detect_in("/src/rim/.git/config", ""), "gitconfig");
+        assert_eq!(detect_in("src/rules.mk", ""), "make");
+    }
+
+    #[test]
+    fn shebangs() {
+        assert_eq!(detect_in("run", "#!/usr/bin/env python3\nprint(1)"), "python");
+        assert_eq!(detect_in("run", "#!/usr/bin/env -S python3.11 -u"), "python");
+        assert_eq!(detect_in("run", "#!/bin/bash -e"), "sh");
+        assert_eq!(detect_in("run", "#! /usr/local/bin/lua5.4"), "lua");
+        assert_eq!(detect_in("run", "#!/usr/bin/env perl"), "perl");
+        assert_eq!(detect_in("run", "#!/usr/bin/env"), "");
+        assert_eq!(detect_in("run", "# not a shebang"), "");
+        // the name wins over the #! line
+        assert_eq!(detect_in("run.rs", "#!/bin/sh"), "rust");
+    }
+
+    #[test]
+    fn backup_suffixes() {
+        assert_eq!(detect_in("main.rs.orig", ""), "rust");
+        assert_eq!(detect_in("main.c~", ""), "c");
+        assert_eq!(detect_in("Makefile.bak", ""), "make");
+        assert_eq!(detect_in(".bashrc.swp", ""), "sh");
+        assert_eq!(detect_in("notes.orig", ""), "");
+    }
+
+    #[test]
+    fn vim_modelines() {
+        assert_eq!(vim_modeline("// vim: set ft=rust:"), Some(String::from("rust")));
+        assert_eq!(vim_modeline("# vim: ts=4 ft=python sw=4"), Some(String::from("python")));
+        assert_eq!(vim_modeline("/* vi: set filetype=c : */"), Some(String::from("c")));
+        assert_eq!(vim_modeline("vim: ft=lua"), Some(String::from("lua")));
+        assert_eq!(vim_modeline("# ex:ft=sh"), Some(String::from("sh")));
+        // vi: and ex: can't start the line, and need white space before them
+        assert_eq!(vim_modeline("vi: ft=sh"), None);
+        assert_eq!(vim_modeline("index: ft=sh"), None);
+        assert_eq!(vim_modeline("# vim: set ts=4:"), None);
+        // one in the last lines is found, the name doesn't count then
+        let text = format!("{}# vim: ft=python", "x\n".repeat(20));
+        assert_eq!(detect_in("build.rs", &text), "python");
+    }
+
+    #[test]
+    fn emacs_modelines() {
+        assert_eq!(emacs_modeline("# -*- mode: Python; coding: utf-8 -*-"), Some(String::from("python")));
+        assert_eq!(emacs_modeline(";; -*- lua -*-"), Some(String::from("lua")));
+        assert_eq!(emacs_modeline("-*- coding: utf-8 -*-"), None);
+        assert_eq!(emacs_modeline("-*- mode: c"), None);
+        assert_eq!(detect_in("run", "#!/bin/sh\n# -*- mode: shell-script -*-"), "sh");
+        assert_eq!(detect_in("run", "#!/bin/sh\n\n# -*- mode: python -*-"), "sh");
+    }
+}
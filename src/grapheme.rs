@@ -0,0 +1,100 @@
+use ropey::RopeSlice;
+use unicode_segmentation::{GraphemeCursor, GraphemeIncomplete, UnicodeSegmentation};
+use unicode_width::UnicodeWidthStr;
+
+// Positions in a line are byte offsets that always sit at the start of a grapheme cluster, so
+// é typed as e + accent or a flag made of two code points move and delete as one character
+
+// Where the grapheme after the one at x starts, the line length at the end
+pub fn next(line: &str, x: usize) -> usize {
+    next_in(line.into(), x)
+}
+
+// Where the grapheme before x starts, 0 at the start
+pub fn prev(line: &str, x: usize) -> usize {
+    prev_in(line.into(), x)
+}
+
+// x moved back to the start of the grapheme it falls in
+pub fn floor(line: &str, x: usize) -> usize {
+    floor_in(line.into(), x)
+}
+
+// The same for a line still in the rope. Only the chunks around x are looked at
+pub fn next_in(line: RopeSlice, x: usize) -> usize {
+    let x = floor_in(line, x);
+    boundary(line, x, |cursor, chunk, start| cursor.next_boundary(chunk, start)).unwrap_or(line.len_bytes())
+}
+
+pub fn prev_in(line: RopeSlice, x: usize) -> usize {
+    let x = floor_in(line, x);
+    boundary(line, x, |cursor, chunk, start| cursor.prev_boundary(chunk, start)).unwrap_or(0)
+}
+
+pub fn floor_in(line: RopeSlice, x: usize) -> usize {
+    if x >= line.len_bytes() {return line.len_bytes()}
+    // off the middle of a char first
+    let x = line.char_to_byte(line.byte_to_char(x));
+    if boundary(line, x, |cursor, chunk, start| cursor.is_boundary(chunk, start)) {return x}
+    boundary(line, x, |cursor, chunk, start| cursor.prev_boundary(chunk, start)).unwrap_or(0)
+}
+
+// Runs step on a grapheme cursor at x, handing it the chunks of line it asks for
+fn boundary<T>(line: RopeSlice, x: usize, mut step: impl FnMut(&mut GraphemeCursor, &str, usize) -> Result<T, GraphemeIncomplete>) -> T {
+    let mut cursor = GraphemeCursor::new(x, line.len_bytes(), true);
+    let (mut chunk, mut start, _, _) = line.chunk_at_byte(x);
+    loop {
+        match step(&mut cursor, chunk, start) {
+            Ok(found) => return found,
+            Err(GraphemeIncomplete::PreContext(end)) => {
+                let (context, at, _, _) = line.chunk_at_byte(end - 1);
+                cursor.provide_context(&context[..end - at], at);
+            }
+            Err(GraphemeIncomplete::PrevChunk) => (chunk, start, _, _) = line.chunk_at_byte(start - 1),
+            Err(GraphemeIncomplete::NextChunk) => (chunk, start, _, _) = line.chunk_at_byte(start + chunk.len()),
+            Err(GraphemeIncomplete::InvalidOffset) => unreachable!(),
+        }
+    }
+}
+
+// Columns s takes on screen, wide CJK characters take two and combining marks none
+pub fn width(s: &str) -> usize {
+    s.width()
+}
+
+// Columns g takes when drawn at column col, a tab reaches to the next tab stop
+pub fn cell_width(g: &str, col: usize, tabstop: usize) -> usize {
+    if g == "\t" {tabstop - col % tabstop} else {width(g)}
+}
+
+// Screen column of byte x
+pub fn col(line: &str, x: usize, tabstop: usize) -> usize {
+    line[..floor(line, x)].graphemes(true).fold(0, |col, g| col + cell_width(g, col, tabstop))
+}
+
+// The columns the grapheme at x covers, end exclusive. The end of the line counts as one column
+pub fn span(line: &str, x: usize, tabstop: usize) -> (usize, usize) {
+    let start = col(line, x, tabstop);
+    let g = &line[floor(line, x)..next(line, x)];
+    (start, start + cell_width(g, start, tabstop).max(1))
+}
+
+// The grapheme drawn at screen column col, the line length past the end
+pub fn at_col(line: &str, col: usize, tabstop: usize) -> usize {
+    let mut end = 0;
+    for (i, g) in line.grapheme_indices(true) {
+        end += cell_width(g, end, tabstop);
+        if end > col {return i}
+    }
+    line.len()
+}
+
+// The longest start of s that fits in width columns
+pub fn clip(s: &str, width: usize) -> &str {
+    let mut used = 0;
+    for (i, g) in s.grapheme_indices(true) {
+        used += self::width(g);
+        if used > width {return &s[..i]}
+    }
+    s
+}
@@ -0,0 +1,586 @@
+use std::{collections::BTreeMap, rc::Rc};
+use serde::Deserialize;
+use crate::{filetype, syntax::Syntax, SEPARATORS};
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Style {
+    Plain, // separators, drawn in the terminal's own colour
+    Text,
+    Function,
+    String,
+    Keyword,
+    Type,
+    #[serde(alias = "preprocess")]
+    Number,
+    Comment,
+}
+
+impl Style {
+    // Index into hl_colors
+    pub fn color(self) -> Option<usize> {
+        match self {
+            Style::Plain => None,
+            Style::Text => Some(0),
+            Style::Function => Some(1),
+            Style::String => Some(2),
+            Style::Keyword => Some(3),
+            Style::Type => Some(4),
+            Style::Number => Some(5),
+            Style::Comment => Some(6),
+        }
+    }
+}
+
+// Something open at the end of a line, carried over to the next one. What a line starts in is a
+// stack of these, empty outside of everything
+#[derive(Clone, PartialEq)]
+pub enum State {
+    Comment(usize), // nesting depth
+    String(char), // the quote it was opened with
+    LongString(usize), // which of the syntax's long_strings
+    RawString(usize), // number of #s
+    LongBracket(usize, bool), // number of =s and whether it is a comment
+    Heredoc(String, bool), // the word ending it and whether tabs may come before that
+    Interpolation(char, usize), // code in a string up to the closing bracket, and brackets opened since
+    Fence(String, String), // a Markdown code block, its fence and language
+}
+
+pub type Stack = Vec<State>;
+
+// What a token does to the stack
+enum Next {
+    Stay,
+    Push(State),
+    Pop,
+    Set(State),
+}
+
+// A line is coloured by runs, each one ending at a byte offset
+pub type Span = (usize, Style);
+
+pub fn push(spans: &mut Vec<Span>, end: usize, style: Style) {
+    match spans.last_mut() {
+        Some(last) if last.1 == style => last.0 = end,
+        _ => spans.push((end, style)),
+    }
+}
+
+fn whole(line: &str, style: Style) -> Vec<Span> {
+    if line.is_empty() {vec![]} else {vec![(line.len(), style)]}
+}
+
+// Colour one line starting in stack, giving the stack it ends in. Code blocks take their language
+// from syntaxes
+pub fn lex(line: &str, syntax: &Syntax, syntaxes: &[Rc<Syntax>], mut stack: Stack) -> (Vec<Span>, Stack) {
+    // the lines of a code block belong to its language, up to the closing fence
+    if let Some(f) = stack.iter().position(|s| matches!(s, State::Fence(..))) {
+        let State::Fence(fence, language) = stack[f].clone() else {unreachable!()};
+        let rest = line.trim_start();
+        let len = rest.len() - rest.trim_start_matches(fence.chars().next().unwrap()).len();
+        if len >= fence.len() && rest[len..].trim().is_empty() {
+            stack.truncate(f);
+            return (whole(line, Style::Comment), stack);
+        }
+        let Some(embedded) = filetype::syntax_for(syntaxes, &language) else {return (whole(line, Style::String), stack)};
+        let inner = stack.split_off(f + 1);
+        let (spans, inner) = lex(line, &embedded, syntaxes, inner);
+        stack.extend(inner);
+        return (spans, stack);
+    }
+    if let Some(State::Heredoc(word, tabs)) = stack.last() {
+        if (if *tabs {line.trim_start_matches('\t')} else {line}) == word {stack.pop();}
+        return (whole(line, Style::String), stack);
+    }
+    if let Some((fence, language)) = open_fence(line).filter(|_| syntax.fences && stack.is_empty()) {
+        stack.push(State::Fence(fence, language));
+        return (whole(line, Style::Comment), stack);
+    }
+
+    let mut spans = vec![];
+    let mut heredoc = None; // starts on the next line
+    let mut regex_ok = true; // a / here starts a regex rather than dividing
+    let mut i = 0;
+    while i < line.len() {
+        let rest = &line[i..];
+        let (len, style, next) = match stack.last() {
+            None => code(rest, i == 0, regex_ok, syntax),
+            Some(&State::Interpolation(close, depth)) => {
+                let (len, style, next) = code(rest, i == 0, regex_ok, syntax);
+                let open = match close {')' => '(', ']' => '[', _ => '{'};
+                match rest.chars().next() {
+                    Some(c) if len == 1 && c == close && depth == 0 => (len, Style::Number, Next::Pop),
+                    Some(c) if len == 1 && c == close => (len, style, Next::Set(State::Interpolation(close, depth - 1))),
+                    Some(c) if len == 1 && c == open => (len, style, Next::Set(State::Interpolation(close, depth + 1))),
+                    _ => (len, style, next),
+                }
+            }
+            Some(State::Comment(depth)) => {
+                let depth = *depth;
+                let (open, close) = syntax.block_comment.as_ref().unwrap();
+                let opened = rest.find(open.as_str()).filter(|_| syntax.nested_comments);
+                match (opened, rest.find(close.as_str())) {
+                    (Some(o), c) if c.is_none_or(|c| o < c) => (o + open.len(), Style::Comment, Next::Set(State::Comment(depth + 1))),
+                    (_, Some(c)) => (c + close.len(), Style::Comment, if depth > 1 {Next::Set(State::Comment(depth - 1))} else {Next::Pop}),
+                    _ => (rest.len(), Style::Comment, Next::Stay),
+                }
+            }
+            Some(&State::String(quote)) => {
+                let close = if quote == '<' {'>'} else {quote};
+                match scan_string(rest, close.encode_utf8(&mut [0; 4]), syntax) {
+                    Scan::Closed(j) => (j, Style::String, Next::Pop),
+                    Scan::Opened(j, opener) => interpolate(j, opener),
+                    // a backslash at the end continues the string on the next line
+                    Scan::Open(escaped) if escaped || (syntax.multiline_strings && quote != '<') => (rest.len(), Style::String, Next::Stay),
+                    Scan::Open(_) => (rest.len(), Style::String, Next::Pop),
+                }
+            }
+            Some(&State::LongString(n)) => match scan_string(rest, &syntax.long_strings[n], syntax) {
+                Scan::Closed(j) => (j, Style::String, Next::Pop),
+                Scan::Opened(j, opener) => interpolate(j, opener),
+                Scan::Open(_) => (rest.len(), Style::String, Next::Stay),
+            },
+            Some(State::RawString(hashes)) => {
+                let close = format!("\"{}", "#".repeat(*hashes));
+                match rest.find(&close) {
+                    Some(e) => (e + close.len(), Style::String, Next::Pop),
+                    None => (rest.len(), Style::String, Next::Stay),
+                }
+            }
+            Some(State::LongBracket(level, comment)) => {
+                let close = format!("]{}]", "=".repeat(*level));
+                let style = if *comment {Style::Comment} else {Style::String};
+                match rest.find(&close) {
+                    Some(e) => (e + close.len(), style, Next::Pop),
+                    None => (rest.len(), style, Next::Stay),
+                }
+            }
+            Some(State::Heredoc(..) | State::Fence(..)) => unreachable!(),
+        };
+        // every token takes at least a char, or the line would never end
+        let len = if len == 0 {rest.chars().next().map_or(1, char::len_utf8)} else {len};
+        let token = rest[..len].trim();
+        if matches!(stack.last(), None | Some(State::Interpolation(..))) && !token.is_empty() {
+            regex_ok = style == Style::Keyword || (matches!(style, Style::Plain | Style::Text) && token.ends_with(|c| "(,=:[!&|?{};+-*%<>~^".contains(c)));
+        }
+        match next {
+            Next::Stay => {}
+            Next::Push(state @ State::Heredoc(..)) => heredoc = Some(state),
+            Next::Push(state) => stack.push(state),
+            Next::Pop => {stack.pop();}
+            Next::Set(state) => *stack.last_mut().unwrap() = state,
+        }
+        push(&mut spans, i + len, style);
+        i += len;
+    }
+    stack.extend(heredoc);
+    (spans, stack)
+}
+
+// How a string goes on from some point
+enum Scan<'a> {
+    Closed(usize), // after this many bytes
+    Opened(usize, &'a str), // code starts this far in with the opener
+    Open(bool), // to the end of the line, and whether that is escaped
+}
+
+fn scan_string<'a>(rest: &str, close: &str, syntax: &'a Syntax) -> Scan<'a> {
+    let openers: &[String] = if syntax.interpolated.iter().any(|d| d == close) {&syntax.interpolations} else {&[]};
+    let mut escaped = false;
+    for (j, c) in rest.char_indices() {
+        if !escaped {
+            if rest[j..].starts_with(close) {return Scan::Closed(j + close.len())}
+            if let Some(opener) = openers.iter().find(|o| rest[j..].starts_with(o.as_str())) {return Scan::Opened(j, opener)}
+        }
+        escaped = !escaped && c == '\\';
+    }
+    Scan::Open(escaped)
+}
+
+// The string up to an opener, or the opener itself
+fn interpolate(j: usize, opener: &str) -> (usize, Style, Next) {
+    if j > 0 {return (j, Style::String, Next::Stay)}
+    (opener.len(), Style::Number, Next::Push(State::Interpolation(closer(opener), 0)))
+}
+
+// The bracket closing $( or ${
+fn closer(opener: &str) -> char {
+    match opener.chars().last() {
+        Some('(') => ')',
+        Some('[') => ']',
+        _ => '}',
+    }
+}
+
+// The token at the start of rest, outside of comments and strings
+fn code(rest: &str, line_start: bool, regex_ok: bool, syntax: &Syntax) -> (usize, Style, Next) {
+    if let Some((len, style)) = syntax.pattern(rest, line_start) {return (len, style, Next::Stay)}
+    if let Some(comment) = syntax.line_comment.as_deref().filter(|c| rest.starts_with(c)) {
+        if let Some(level) = long_bracket(&rest[comment.len()..]).filter(|_| syntax.long_brackets) {
+            return (comment.len() + level + 2, Style::Comment, Next::Push(State::LongBracket(level, true)));
+        }
+        return (rest.len(), Style::Comment, Next::Stay);
+    }
+    if let Some((open, _)) = syntax.block_comment.as_ref().filter(|(open, _)| rest.starts_with(open.as_str())) {
+        return (open.len(), Style::Comment, Next::Push(State::Comment(1)));
+    }
+    if let Some(level) = long_bracket(rest).filter(|_| syntax.long_brackets) {
+        return (level + 2, Style::String, Next::Push(State::LongBracket(level, false)));
+    }
+    if let Some((len, hashes)) = raw_string(rest).filter(|_| syntax.raw_strings) {
+        return (len, Style::String, Next::Push(State::RawString(hashes)));
+    }
+    if let Some(len) = char_literal(rest).filter(|_| syntax.char_literals) {
+        return (len, Style::String, Next::Stay);
+    }
+    if let Some(n) = syntax.long_strings.iter().position(|d| rest.starts_with(d.as_str())) {
+        return (syntax.long_strings[n].len(), Style::String, Next::Push(State::LongString(n)));
+    }
+    if let Some((len, word, tabs)) = heredoc(rest).filter(|_| syntax.heredocs) {
+        return (len, Style::String, Next::Push(State::Heredoc(word, tabs)));
+    }
+    if let Some(opener) = syntax.interpolations.iter().find(|o| rest.starts_with(o.as_str())) {
+        return interpolate(0, opener);
+    }
+    if let Some(len) = regex_literal(rest).filter(|_| syntax.regex_literals && regex_ok) {
+        return (len, Style::String, Next::Stay);
+    }
+    let c = rest.chars().next().unwrap();
+    if syntax.strings.contains(&c) {return (c.len_utf8(), Style::String, Next::Push(State::String(c)))}
+    if SEPARATORS.contains(&c) {return (c.len_utf8(), Style::Plain, Next::Stay)}
+
+    // a word runs up to a separator, a pattern or something that opens a comment, a string or code
+    let len = rest.char_indices().skip(1)
+        .find(|&(j, c)| SEPARATORS.contains(&c) || syntax.strings.contains(&c) || breaks_word(&rest[j..], syntax))
+        .map_or(rest.len(), |(j, _)| j);
+    let word = &rest[..len];
+    let style = if syntax.keywords.iter().any(|k| k == word) {
+        Style::Keyword
+    } else if syntax.types.iter().any(|t| t == word) {
+        Style::Type
+    } else if syntax.preprocess.iter().any(|p| p == word) || syntax.is_number(word) {
+        Style::Number
+    } else if rest[len..].starts_with('(') {
+        Style::Function
+    } else {
+        Style::Text
+    };
+    (len, style, Next::Stay)
+}
+
+fn breaks_word(rest: &str, syntax: &Syntax) -> bool {
+    syntax.line_comment.as_deref().is_some_and(|c| rest.starts_with(c) && !syntax.comment_starts_word)
+        || syntax.block_comment.as_ref().is_some_and(|(open, _)| rest.starts_with(open.as_str()))
+        || syntax.long_strings.iter().chain(&syntax.interpolations).any(|s| rest.starts_with(s.as_str()))
+        || syntax.pattern(rest, false).is_some()
+}
+
+// [[ or [==[, giving the number of =s
+fn long_bracket(rest: &str) -> Option<usize> {
+    let level = rest.strip_prefix('[')?.bytes().take_while(|&b| b == b'=').count();
+    rest[1 + level..].starts_with('[').then_some(level)
+}
+
+// r"..., br#"... and the like, giving the length of the opening and the number of #s
+fn raw_string(rest: &str) -> Option<(usize, usize)> {
+    let prefix = if rest.starts_with("br") {2} else if rest.starts_with('r') {1} else {return None};
+    let hashes = rest[prefix..].bytes().take_while(|&b| b == b'#').count();
+    rest[prefix + hashes..].starts_with('"').then_some((prefix + hashes + 1, hashes))
+}
+
+// 'a', '\n', '\u{1F600}', but not a lifetime
+fn char_literal(rest: &str) -> Option<usize> {
+    let body = rest.strip_prefix('\'')?;
+    let first = body.chars().next()?;
+    let close = if first == '\\' {
+        let after = 1 + body[1..].chars().next()?.len_utf8();
+        after + body[after..].find('\'')?
+    } else {
+        body[first.len_utf8()..].starts_with('\'').then_some(first.len_utf8())?
+    };
+    Some(close + 2)
+}
+
+// <<EOF, <<-EOF, <<'EOF' or << "EOF", giving its length, the word and whether tabs may come before
+// the word at the end. <<< is a here string
+fn heredoc(rest: &str) -> Option<(usize, String, bool)> {
+    let after = rest.strip_prefix("<<").filter(|a| !a.starts_with('<'))?;
+    let tabs = after.starts_with('-');
+    let quoted = after[usize::from(tabs)..].trim_start();
+    let quote = quoted.chars().next().filter(|&c| c == '\'' || c == '"');
+    let body = &quoted[quote.map_or(0, char::len_utf8)..];
+    let word = body.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(body.len());
+    if word == 0 {return None}
+    let close = match quote {
+        Some(q) => body[word..].starts_with(q).then_some(q.len_utf8())?,
+        None => 0,
+    };
+    Some((rest.len() - body.len() + word + close, body[..word].to_string(), tabs))
+}
+
+// /re/flags on one line, giving its length
+fn regex_literal(rest: &str) -> Option<usize> {
+    let body = rest.strip_prefix('/')?;
+    let (mut escaped, mut class) = (false, false);
+    for (j, c) in body.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '[' => class = true,
+            ']' => class = false,
+            '/' if !class && j > 0 => return Some(j + 2 + body[j + 1..].bytes().take_while(u8::is_ascii_alphabetic).count()),
+            _ => {}
+        }
+    }
+    None
+}
+
+// ``` or ~~~ and more, up to three spaces in, giving the fence and the language after it
+fn open_fence(line: &str) -> Option<(String, String)> {
+    let rest = line.trim_start_matches(' ');
+    if line.len() - rest.len() > 3 {return None}
+    let c = rest.chars().next().filter(|&c| c == '`' || c == '~')?;
+    let len = rest.len() - rest.trim_start_matches(c).len();
+    // a ` after ``` makes it inline code
+    if len < 3 || (c == '`' && rest[len..].contains('`')) {return None}
+    let language = rest[len..].split_whitespace().next().unwrap_or_default();
+    Some((rest[..len].to_string(), language.to_string()))
+}
+
+// How far above a line lexing starts when the lines before it haven't been lexed
+const SYNC_LINES: usize = 1000;
+// Lexed lines kept, the ones furthest from the last line asked for are dropped past this
+const CACHED_LINES: usize = 4 * SYNC_LINES;
+
+#[derive(Clone)]
+struct Line {
+    start: Stack,
+    end: Stack,
+    spans: Vec<Span>,
+}
+
+// Lexed lines of a buffer, only those around where it was last drawn are kept. An edit forgets the
+// lines it touched, and the lines after the first of them are only lexed again until one starts in
+// the same state as before
+#[derive(Default)]
+pub struct Highlighter {
+    filetype: Option<String>, // the syntax below is for
+    syntax: Option<Rc<Syntax>>, // none when the filetype has no syntax
+    lines: BTreeMap<usize, Line>,
+    valid: usize, // lines before this one are up to date
+    restyled: Vec<usize>, // lexed again after a line above them changed, with different colours
+}
+
+impl Highlighter {
+    pub fn changed(&mut self, y: usize) {
+        self.lines.remove(&y);
+        self.valid = self.valid.min(y);
+    }
+
+    pub fn inserted(&mut self, y: usize) {
+        self.shift(y, |i| i + 1);
+        self.valid = self.valid.min(y);
+    }
+
+    pub fn removed(&mut self, y: usize) {
+        self.lines.remove(&y);
+        self.shift(y + 1, |i| i - 1);
+        self.valid = self.valid.min(y);
+    }
+
+    // Renumber the kept lines from y on
+    fn shift(&mut self, y: usize, by: impl Fn(usize) -> usize) {
+        let below = self.lines.split_off(&y);
+        self.lines.extend(below.into_iter().map(|(i, line)| (by(i), line)));
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.valid = 0;
+    }
+
+    // The spans of line y, text gives the lines before it that aren't up to date. There are none
+    // when the filetype has no syntax
+    pub fn line(&mut self, y: usize, syntaxes: &[Rc<Syntax>], filetype: &str, text: impl Fn(usize) -> String) -> Vec<Span> {
+        if self.filetype.as_deref() != Some(filetype) {
+            self.clear();
+            self.syntax = syntaxes.iter().find(|s| s.name == filetype).cloned();
+            self.filetype = Some(filetype.to_string());
+        }
+        let Some(syntax) = self.syntax.clone() else {return vec![]};
+        // lexing starts after the nearest kept line above y known to be right. With none close
+        // enough it starts a little above y guessing nothing is open, the guess is checked when the
+        // lines in between are lexed
+        let limit = y.saturating_sub(SYNC_LINES);
+        let known = self.lines.range(..y.min(self.valid)).next_back().filter(|(&i, _)| i + 1 >= limit);
+        let exact = known.is_some() || limit == 0;
+        let (from, mut stack) = known.map_or((limit, vec![]), |(&i, line)| (i + 1, line.end.clone()));
+        for i in from..=y {
+            match self.lines.get(&i) {
+                Some(line) if line.start == stack => stack = line.end.clone(),
+                old => {
+                    let (spans, end) = lex(&text(i), &syntax, syntaxes, stack.clone());
+                    if old.is_some_and(|line| line.spans != spans) {self.restyled.push(i)}
+                    let start = std::mem::replace(&mut stack, end.clone());
+                    self.lines.insert(i, Line { start, end, spans });
+                }
+            }
+        }
+        if exact {self.valid = self.valid.max(y + 1)}
+        let spans = self.lines[&y].spans.clone();
+        if self.lines.len() > CACHED_LINES {
+            let keep = y.saturating_sub(CACHED_LINES / 2)..y + CACHED_LINES / 2;
+            self.lines.retain(|i, _| keep.contains(i));
+        }
+        spans
+    }
+
+    pub fn take_restyled(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.restyled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use super::*;
+    use crate::syntax;
+
+    // Lex lines one after another the way the screen does, carrying the stack
+    fn lex_lines(filetype: &str, lines: &[&str]) -> Vec<Vec<Span>> {
+        let syntaxes = syntax::builtin();
+        let syntax = syntaxes.iter().find(|s| s.name == filetype).unwrap();
+        let mut stack = vec![];
+        lines.iter().map(|line| {
+            let (spans, end) = lex(line, syntax, &syntaxes, stack.clone());
+            stack = end;
+            spans
+        }).collect()
+    }
+
+    fn style_at(spans: &[Span], x: usize) -> Style {
+        spans.iter().find(|(end, _)| *end > x).unwrap().1
+    }
+
+    #[test]
+    fn block_comment_spans_lines() {
+        let spans = lex_lines("c", &["int a; /* open", "still inside", "closed */ int b;"]);
+        assert_ne!(style_at(&spans[0], 0), Style::Comment);
+        assert_eq!(style_at(&spans[0], 7), Style::Comment);
+        assert_eq!(spans[1], vec![(12, Style::Comment)]);
+        assert_eq!(style_at(&spans[2], 8), Style::Comment);
+        assert_eq!(style_at(&spans[2], 10), Style::Type);
+    }
+
+    #[test]
+    fn raw_string_spans_lines() {
+        let spans = lex_lines("rust", &["let s = r##\"one", "\"# is not the end", "three\"##; let"]);
+        assert_eq!(style_at(&spans[0], 8), Style::String);
+        assert_eq!(spans[1], vec![(17, Style::String)]);
+        assert_eq!(style_at(&spans[2], 7), Style::String);
+        assert_eq!(style_at(&spans[2], 10), Style::Keyword);
+    }
+
+    #[test]
+    fn long_brackets_span_lines() {
+        let spans = lex_lines("lua", &["x = [==[ one", "]] two", "]==] if", "--[[ three", "four ]] if"]);
+        assert_eq!(style_at(&spans[0], 4), Style::String);
+        assert_eq!(spans[1], vec![(6, Style::String)]);
+        assert_eq!(style_at(&spans[2], 3), Style::String);
+        assert_eq!(style_at(&spans[2], 5), Style::Keyword);
+        assert_eq!(spans[3], vec![(10, Style::Comment)]);
+        assert_eq!(style_at(&spans[4], 6), Style::Comment);
+        assert_eq!(style_at(&spans[4], 8), Style::Keyword);
+    }
+
+    #[test]
+    fn relex_stops_where_the_state_is_the_same() {
+        let mut text = vec![String::from("int a;"); 50];
+        let syntaxes = syntax::builtin();
+        let mut highlighter = Highlighter::default();
+        let lexed = Cell::new(0);
+        let line = |h: &mut Highlighter, text: &[String], y| h.line(y, &syntaxes, "c", |i| {
+            lexed.set(lexed.get() + 1);
+            text[i].clone()
+        });
+        let before = line(&mut highlighter, &text, 49);
+
+        // an edit that leaves nothing open only lexes its own line
+        text[10] = String::from("int b;");
+        highlighter.changed(10);
+        lexed.set(0);
+        line(&mut highlighter, &text, 49);
+        assert_eq!(lexed.get(), 1);
+
+        // opening a comment restyles every line below it
+        text[10] = String::from("/* int b;");
+        highlighter.changed(10);
+        assert_eq!(line(&mut highlighter, &text, 49), vec![(6, Style::Comment)]);
+        assert_eq!(highlighter.take_restyled(), (11..50).collect::<Vec<_>>());
+
+        // closing it again further down gives the old colours back after it
+        text[20] = String::from("*/");
+        highlighter.changed(20);
+        assert_eq!(line(&mut highlighter, &text, 49), before);
+        assert_eq!(line(&mut highlighter, &text, 15), vec![(6, Style::Comment)]);
+        assert_eq!(highlighter.take_restyled(), (21..50).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn far_lines_lex_only_what_is_near() {
+        let syntaxes = syntax::builtin();
+        let mut highlighter = Highlighter::default();
+        let lexed = Cell::new(0);
+        let text = |_| {
+            lexed.set(lexed.get() + 1);
+            String::from("int a;")
+        };
+        highlighter.line(999_999, &syntaxes, "c", text);
+        assert_eq!(lexed.get(), SYNC_LINES + 1);
+        for y in (0..20_000).step_by(50) {highlighter.line(y, &syntaxes, "c", text);}
+        assert!(highlighter.lines.len() <= CACHED_LINES);
+        // going back up lexes again only what was dropped
+        lexed.set(0);
+        highlighter.line(19_000, &syntaxes, "c", text);
+        assert_eq!(lexed.get(), 0);
+    }
+
+    #[test]
+    fn inserted_and_removed_lines_move_the_rest() {
+        let mut text = vec![String::from("int a;"); 30];
+        text[10] = String::from("/* open");
+        text[20] = String::from("close */");
+        let syntaxes = syntax::builtin();
+        let mut highlighter = Highlighter::default();
+        let lexed = Cell::new(0);
+        let line = |h: &mut Highlighter, text: &[String], y| h.line(y, &syntaxes, "c", |i| {
+            lexed.set(lexed.get() + 1);
+            text[i].clone()
+        });
+        let plain = line(&mut highlighter, &text, 29);
+
+        // the kept lines move down with the text, only the new one is lexed
+        text.insert(5, String::from("int b;"));
+        highlighter.inserted(5);
+        lexed.set(0);
+        assert_eq!(line(&mut highlighter, &text, 15), vec![(6, Style::Comment)]);
+        assert_eq!(line(&mut highlighter, &text, 30), plain);
+        assert_eq!(lexed.get(), 1);
+
+        // taking out the line opening the comment lexes the lines up to its end again
+        text.remove(11);
+        highlighter.removed(11);
+        lexed.set(0);
+        assert_eq!(line(&mut highlighter, &text, 15), plain);
+        assert_eq!(line(&mut highlighter, &text, 29), plain);
+        assert_eq!(highlighter.take_restyled(), (11..21).collect::<Vec<_>>());
+        assert_eq!(lexed.get(), 10);
+    }
+
+    #[test]
+    fn no_syntax_no_spans() {
+        let mut highlighter = Highlighter::default();
+        assert_eq!(highlighter.line(3, &syntax::builtin(), "", |_| String::from("int a;")), vec![]);
+        assert!(highlighter.lines.is_empty());
+    }
+}
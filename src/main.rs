@@ -1,60 +1,46 @@
-use std::{env, fs, io::{self, stdout,  Write}, path::Path, process::exit};
+use std::{env, fs, io::{self, stdout,  Write}, path::Path, rc::Rc, time::Duration};
 use crossterm::{cursor::{self}, 
-    event::{self, Event, KeyCode, KeyModifiers}, 
+    event::{Event, KeyCode, KeyEvent, KeyModifiers}, 
     execute, 
     queue, 
-    style::{ResetColor, SetColors, SetForegroundColor}, 
+    style::{Attribute, ResetColor, SetAttribute, SetBackgroundColor, SetColors, SetForegroundColor}, 
     terminal::{
         self, 
-        disable_raw_mode, 
         enable_raw_mode, 
         size, 
         Clear, 
         ClearType, 
         DisableLineWrap, 
-        EnterAlternateScreen, 
-        LeaveAlternateScreen
+        EnterAlternateScreen
     }, ExecutableCommand};
+use events::{EditorEvent, Timer};
+use operator::MotionKind;
+use unicode_segmentation::UnicodeSegmentation;
 use utils::leading_whitespace;
 
+mod buffer;
+mod events;
+mod ex;
+mod filetype;
+mod grapheme;
+mod highlight;
+mod operator;
+mod options;
+mod register;
+mod search;
+mod substitute;
+mod syntax;
+mod tab;
+mod textobject;
+#[cfg(feature = "tree-sitter")]
+mod tree;
+mod undo;
 mod utils;
+mod visual;
+mod window;
+mod wrap;
 
-// C Syntax Highlighting
-const C_PREPROCESS: [&str; 4] = ["#include", "#ifndef", "#define", "extern"];
-const C_KEYWORDS: [&str; 15] = ["switch",    "if",      "while",   "for",    "break",
-                         "continue",  "return",  "else",    "struct", "union",
-                         "typedef",   "static",  "enum",    "class",  "case"];
-const C_TYPES: [&str; 8] = ["int", "long", "double", "float", "char",
-                                "unsigned", "signed", "void"];
-const C_ENCLOSERS: [char; 2] = ['<', '"'];
-
-// Rust Syntax Highlighting
-const RUST_PREPROCESS: [&str; 1] = [".use"];
-// These declarations are for if we want to be able to collect the file's variables (unused)
-// const RUST_DECLARATIONS: [&str; 2] = ["let", "mut"];
-const RUST_KEYWORDS: [&str; 51] = ["as", "break",  "const", "continue", "crate",  "else", "enum",
-    "extern", "false",  "fn",       "for",      "if",     "impl",    "in",
-    "let",    "loop",   "match",    "mod",      "move",   "mut",     "pub",
-    "ref",    "return", "self",     "Self",     "static", "struct",  "super",
-    "trait",  "true",   "type",     "unsafe",   "use",    "where",   "while",
-    "async",  "await",  "dyn",      "abstract", "become", "box",     "do",
-    "final",  "macro",  "override", "priv",     "typeof", "unsized", "virtual",
-    "yield",  "try"];
-const RUST_TYPES: [&str; 16] = ["i8", "i16", "i32",     "i64",     "i128",  "isize",  "u8",
-    "u16",   "u32",   "u64",     "u128",    "usize",  "f32",    "f64",
-    "bool",  "char"];
-const RUST_ENCLOSERS: [char; 1] = ['"'];
-
-// Lua Syntax Highlighting
-const LUA_PREPROCCESS: [&str; 11] = ["priority", "prefsys", "identifier", "class", "handler",
-    "hide", "defer", "disallow_manual", "import", "version", "description"];
-const LUA_KEYWORDS: [&str; 21] = ["and", "break", "do", "else", "elseif", "end", "false",
-    "for", "function", "if", "in", "local", "nil", "not", "or", "repeat", "return", "then", "true",
-    "until", "while"];
-const LUA_TYPES: [&str; 1] = ["local"];
-const LUA_ENCLOSERS: [char; 2] = ['"', '\''];
-
-const TAB_LENGTH: usize = 4;
+const SEARCH_HL: u32 = 0x5f5f00; // background of search matches
 const SEPARATORS: [char; 12] = [';', '\t', ' ', '.', ',', '{', '}', '(', ')', '<', '>', '"'];
 
 #[derive(Default, PartialEq, PartialOrd)]
@@ -62,20 +48,11 @@ enum Mode {
     #[default]
     Normal,
     Insert,
-    Visual, // Going to implement visual mode later
+    Visual,
+    VisualLine,
+    VisualBlock,
     Command,
-}
-
-struct Erow {
-    data: String,
-}
-
-impl Erow {
-    fn new(s: String) -> Erow {
-        Erow {
-            data: s,
-        }
-    }
+    Search,
 }
 
 struct EditorConfig {
@@ -85,13 +62,23 @@ struct EditorConfig {
     rx: usize,
     rowoff: usize,
     coloff: usize,
-    screenrows: usize,
+    skip: usize, // with wrap, screen lines of row rowoff above the window when it is taller than that
+    screenrows: usize, // text rows of the current window
     screencols: usize,
-    numrows: usize,
-    rows: Vec<Erow>,
-    dirty: bool,
+    termrows: usize,
+    termcols: usize,
+    windows: Vec<window::Window>,
+    window: usize, // id of the current window
+    layout: window::Layout,
+    next_window: usize,
+    tabs: Vec<tab::Tab>,
+    tab: usize, // index of the current tab page
+    buf: buffer::Buffer, // the buffer being edited, the rest are in buffers
+    buffers: Vec<buffer::Buffer>,
+    alternate: Option<usize>, // id of the buffer Ctrl-^ goes back to
+    next_buffer: usize,
     dirty_rows: Vec<usize>,
-    filename: String, 
+    wrapped: (usize, usize, Vec<usize>), // rowoff, skip and row heights the window was last drawn with
     status_msg: String,
     command: String,
     motion: String,
@@ -99,35 +86,54 @@ struct EditorConfig {
     hl_colors: Vec<u32>,
     // vars: Vec<String>,
     j_flag: bool,
+    registers: register::Registers,
+    register: Option<char>, // register picked with "x for the next command
+    operator: Option<String>, // operator waiting for a motion (d, c, y, ...)
+    operator_count: usize,
+    visual_start: (usize, usize), // the other end of the selection from the cursor
+    block_insert: Option<visual::BlockInsert>,
+    search: search::Search,
+    options: options::Options,
+    events: events::Events,
+    syntaxes: Vec<Rc<syntax::Syntax>>, // built in and from ~/.config/rim/syntax
 }
 
 impl EditorConfig {
     fn new() -> io::Result<Self> {
-        // This size is hardcoded for termex. figure out a way to
-        // implement the escape codes to do this (moves to bottom corner row, request cursor pos,
-        // what that returns is size)
-        let (mut cols, mut rows) = size()?;
-        // print!("rows: {} cols: {}", rows, cols);
-        if cols == 0 || cols > 1000 || rows == 0 || rows > 1000 {(cols, rows) = (60, 24)}
-
-        Ok(EditorConfig {
+        let (cols, rows) = terminal_size()?;
+        // started after the size is known, asking the terminal for it reads input too
+        Ok(EditorConfig::with_size(cols, rows, events::Events::start()))
+    }
+
+    fn with_size(cols: usize, rows: usize, events: events::Events) -> Self {
+        EditorConfig {
             mode: Mode::default(),
             cx: 0,
             cy: 0,
             rx: 0,
             rowoff: 0,
             coloff: 0,
-            screenrows: rows as usize - 2, // 2 bottom rows are for status line
-            screencols: cols as usize,
-            numrows: 0,
-            rows: vec![],
-            dirty: false,
-            dirty_rows: Vec::from_iter(0..rows as usize - 2), // mark all rows dirty at beginning
-            filename: String::default(),
+            skip: 0,
+            screenrows: rows - 2, // 2 bottom rows are for status line
+            screencols: cols,
+            termrows: rows,
+            termcols: cols,
+            windows: vec![window::Window::new(1, 1)],
+            window: 1,
+            layout: window::Layout::Leaf(1),
+            next_window: 2,
+            tabs: vec![tab::Tab::default()],
+            tab: 0,
+            buf: buffer::Buffer::new(1),
+            buffers: vec![],
+            alternate: None,
+            next_buffer: 2,
+            dirty_rows: Vec::from_iter(0..rows - 2), // mark all rows dirty at beginning
+            wrapped: (0, 0, vec![]),
             status_msg: String::default(),
             command: String::default(),
             motion: String::default(),
-            motion_count: 1,
+            motion_count: 0,
             hl_colors: vec![
                 0xffffff, // default text color
                 0x3674f0, // function call
@@ -139,7 +145,48 @@ impl EditorConfig {
             ],
             // vars: vec![],
             j_flag: false,
-        })
+            registers: register::Registers::default(),
+            register: None,
+            operator: None,
+            operator_count: 0,
+            visual_start: (0, 0),
+            block_insert: None,
+            search: search::Search::default(),
+            options: options::Options::default(),
+            events,
+            syntaxes: vec![],
+        }
+    }
+}
+
+// Columns and rows of the terminal. Some terminals (termex) don't report a size, those are asked
+// where the cursor ends up when it is sent to the bottom right corner
+fn terminal_size() -> io::Result<(usize, usize)> {
+    let known = |(cols, rows): (u16, u16)| (1..=1000).contains(&cols) && (1..=1000).contains(&rows);
+    if let Ok((cols, rows)) = size() {
+        if known((cols, rows)) {return Ok((cols as usize, rows as usize))}
+    }
+    execute!(stdout(), cursor::SavePosition, cursor::MoveTo(999, 999))?;
+    let corner = cursor::position();
+    execute!(stdout(), cursor::RestorePosition)?;
+    // no answer either, go with the usual default
+    Ok(match corner {
+        Ok((col, row)) if known((col + 1, row + 1)) => (col as usize + 1, row as usize + 1),
+        _ => (80, 24),
+    })
+}
+
+// The next key pressed, for commands that wait for one in the middle of running
+fn read_key(ec: &mut EditorConfig) -> io::Result<KeyEvent> {
+    loop {
+        match ec.events.next()? {
+            EditorEvent::Input(Event::Key(key)) => return Ok(key),
+            EditorEvent::Input(Event::Resize(cols, rows)) => {
+                window::resize(ec, cols as usize, rows as usize);
+                refresh_screen(ec)?;
+            }
+            _ => {}
+        }
     }
 }
 
@@ -151,24 +198,72 @@ fn main() -> io::Result<()> {
         DisableLineWrap
     )?;
     let mut ec = EditorConfig::new().unwrap();
+    window::arrange(&mut ec);
+    // files opened below need the syntaxes to know their filetype
+    let (syntaxes, mut errors) = syntax::load();
+    ec.syntaxes = syntaxes;
     let args: Vec<String> = env::args().collect();
-    if args.len() >= 2 {editor_open(&mut ec, args[1].clone()).unwrap();}
+    let cant_open = |file: &str, e: io::Error| format!("E484: Can't open file {}: {}", file, e);
+    if args.len() >= 2 {
+        if let Err(e) = editor_open(&mut ec, args[1].clone()) {errors.insert(0, cant_open(&args[1], e))}
+    }
+    // the rest of the files are loaded into buffers behind the first
+    for file in args.iter().skip(2) {
+        if let Err(e) = buffer::open(&mut ec, file.clone()) {errors.insert(0, cant_open(file, e))}
+    }
+    if args.len() > 2 {
+        let _ = buffer::switch(&mut ec, 1);
+        ec.alternate = None;
+    }
     set_config(&mut ec);
+    if let Some(error) = errors.first() {let _ = set_status_message(&mut ec, error.clone());}
 
 
     let mut refresh = true;
     loop {
         if refresh {let _ = refresh_screen(&mut ec);} 
         
-        refresh = match ec.mode {
-            Mode::Normal => handle_normal(&mut ec).unwrap(),
-            Mode::Insert => handle_insert(&mut ec).unwrap(),
-            Mode::Visual => handle_visual(&mut ec).unwrap(),
-            Mode::Command => handle_command(&mut ec).unwrap(),
+        // sleeps until a key comes in or a timer is due
+        refresh = match ec.events.next().unwrap() {
+            EditorEvent::Input(Event::Key(key)) => handle_key(&mut ec, key).unwrap(),
+            EditorEvent::Input(Event::Resize(cols, rows)) => {
+                window::resize(&mut ec, cols as usize, rows as usize);
+                true
+            }
+            EditorEvent::Input(_) => false,
+            EditorEvent::Timer(Timer::KeySequence) => {
+                // the j stays typed in and a k after it is just a k
+                ec.j_flag = false;
+                true
+            }
         };
     }
 }
 
+// A key goes to the handler of the mode the editor is in
+fn handle_key(ec: &mut EditorConfig, key: KeyEvent) -> io::Result<bool> {
+    let refresh = match ec.mode {
+        Mode::Normal => handle_normal(ec, key)?,
+        Mode::Insert => handle_insert(ec, key)?,
+        Mode::Visual | Mode::VisualLine | Mode::VisualBlock => handle_visual(ec, key)?,
+        Mode::Command => handle_command(ec, key)?,
+        Mode::Search => handle_search(ec, key)?,
+    };
+    key_sequence_timer(ec);
+    Ok(refresh)
+}
+
+// jk in insert mode waits timeoutlen for the k like a vim mapping does. Builtin commands such as
+// g, z and an operator wait for their next key however long it takes, as they do in vim
+fn key_sequence_timer(ec: &mut EditorConfig) {
+    let pending = ec.mode == Mode::Insert && ec.j_flag;
+    if pending && ec.options.timeout {
+        ec.events.set_timer(Timer::KeySequence, Duration::from_millis(ec.options.timeoutlen as u64));
+    } else {
+        ec.events.cancel_timer(Timer::KeySequence);
+    }
+}
+
 fn set_config(ec: &mut EditorConfig){
     // Todo: read in JSON file and set ec hl colors
     // basically, open the json file and check if each color exists and if it does update the
@@ -205,207 +300,436 @@ fn set_config(ec: &mut EditorConfig){
 }
 
 fn editor_scroll(ec: &mut EditorConfig) -> io::Result<()> {
+  // wrapped windows scroll by whole rows, a row may take several screen lines. refresh_screen
+  // redraws what moved
+  if ec.options.wrap {
+    ec.coloff = 0;
+    if ec.cy < ec.rowoff {ec.rowoff = ec.cy}
+    if ec.cy >= ec.rowoff + ec.screenrows {ec.rowoff = ec.cy + 1 - ec.screenrows}
+    if ec.rowoff != ec.cy {ec.skip = 0}
+    while ec.rowoff < ec.cy && wrap::cursor(ec).0 >= ec.screenrows {ec.rowoff += 1}
+    // a row taller than the window scrolls inside itself to keep the cursor in view
+    if ec.rowoff == ec.cy {
+        let i = wrap::segment(ec);
+        ec.skip = ec.skip.min(i).max((i + 1).saturating_sub(ec.screenrows));
+    }
+    return Ok(())
+  }
+  // scrolling the terminal only works while one window has the whole screen
+  let single = ec.windows.len() == 1;
   if ec.cy < ec.rowoff {
     let mut scroll_diff = ec.rowoff - ec.cy;
     scroll_diff = if scroll_diff > ec.screenrows {ec.screenrows} else {scroll_diff};
-    queue!(stdout(),
-        terminal::ScrollDown(scroll_diff as u16)
-    )?;
-    ec.dirty_rows.extend(0..scroll_diff);
+    if single {
+        queue!(stdout(),
+            terminal::ScrollDown(scroll_diff as u16)
+        )?;
+        ec.dirty_rows.extend(0..scroll_diff);
+    } else {
+        ec.dirty_rows.extend(0..ec.screenrows);
+    }
     ec.rowoff = ec.cy;
   } else if ec.cy >= ec.rowoff + ec.screenrows {
     let mut scroll_diff = ec.cy - (ec.rowoff + ec.screenrows);
     scroll_diff+=1;
     scroll_diff = if scroll_diff > ec.screenrows {ec.screenrows} else {scroll_diff};
-    queue!(stdout(),
-        terminal::ScrollUp(scroll_diff as u16)
-    )?;
-    ec.dirty_rows.extend((ec.screenrows - scroll_diff)..ec.screenrows);
+    if single {
+        queue!(stdout(),
+            terminal::ScrollUp(scroll_diff as u16)
+        )?;
+        ec.dirty_rows.extend((ec.screenrows - scroll_diff)..ec.screenrows);
+    } else {
+        ec.dirty_rows.extend(0..ec.screenrows);
+    }
     ec.rowoff = ec.cy - ec.screenrows + 1;
   }
 
-  if ec.rx < ec.coloff {
-    ec.coloff = ec.rx;
-  } else if ec.rx >= ec.coloff + ec.screencols {
-    ec.coloff = ec.rx - ec.screencols + 1;
+  // keep sidescrolloff columns around the cursor, moving at least sidescroll columns at a time.
+  // A sidescroll of 0 puts the cursor in the middle instead
+  let textcols = text_cols(ec);
+  let siso = ec.options.sidescrolloff.min((textcols - 1) / 2);
+  let step = ec.options.sidescroll;
+  if ec.rx < ec.coloff + siso {
+    let target = ec.rx.saturating_sub(siso);
+    ec.coloff = if step == 0 {ec.rx.saturating_sub(textcols / 2)} else {target.min(ec.coloff.saturating_sub(step))};
+    ec.dirty_rows.extend(0..ec.screenrows);
+  } else if ec.rx + siso >= ec.coloff + textcols {
+    let target = ec.rx + siso + 1 - textcols;
+    ec.coloff = if step == 0 {ec.rx.saturating_sub(textcols / 2)} else {target.max(ec.coloff + step)};
+    ec.dirty_rows.extend(0..ec.screenrows);
   }
     Ok(())
 }
 
+// Columns of the current window left for text after the line numbers
+fn text_cols(ec: &EditorConfig) -> usize {
+    ec.screencols.saturating_sub(6).max(1)
+}
+
 fn refresh_screen(ec: &mut EditorConfig) -> io::Result<()>{
+    // Prevent cx from going past row length or into the middle of a character
+    let line = ec.buf.line(ec.cy);
+    ec.cx = grapheme::floor(&line, ec.cx);
+    ec.rx = grapheme::col(&line, ec.cx, ec.options.tabstop);
+
     // set up terminal for writing to screen
     let _ = editor_scroll(ec);
     ec.dirty_rows.push(ec.cy - ec.rowoff);
-    // queue!(stdout(), 
-    //     cursor::Hide,
-    //     cursor::MoveTo(0, ec.numrows as u16),
-    //     terminal::Clear(ClearType::CurrentLine),
-    // )?;
-
-    let rowoff: usize = ec.rowoff;
-    for y in ec.dirty_rows.clone() {
-        queue!(stdout(), 
-            cursor::MoveTo(0,y as u16),
-            terminal::Clear(ClearType::CurrentLine),
-        )?;
+    // a wrapped row that changes height moves every row below it
+    if ec.options.wrap {
+        let rows = ec.rowoff..(ec.rowoff + ec.screenrows).min(ec.buf.numrows);
+        let wrapped = (ec.rowoff, ec.skip, rows.map(|y| wrap::height(ec, y)).collect::<Vec<_>>());
+        let moved = if (wrapped.0, wrapped.1) != (ec.wrapped.0, ec.wrapped.1) {Some(0)}
+            else {(0..=wrapped.2.len()).find(|&i| wrapped.2.get(i) != ec.wrapped.2.get(i))};
+        if let Some(y) = moved {ec.dirty_rows.extend(y..ec.screenrows)}
+        ec.wrapped = wrapped;
+    }
+    // opening or closing a comment colours the lines below differently
+    let last = (ec.rowoff + ec.screenrows).min(ec.buf.numrows).saturating_sub(1);
+    for y in ec.buf.restyled(&ec.syntaxes, last).into_iter().filter(|&y| y >= ec.rowoff) {ec.dirty_rows.push(y - ec.rowoff)}
+    if !ec.syntaxes.iter().any(|s| s.name == ec.buf.filetype) {
+        let _ = set_status_message(ec, "Filetype not supported for syntax higlighting!".to_string());
+    }
 
-        // If line is past file end draw ~
-        if y >= ec.numrows {
-            queue!(stdout(), crossterm::style::Print("~\r\n"))?;
-            continue;
+    // other windows are drawn in full, they may be showing the buffer that just changed
+    if ec.windows.len() > 1 {
+        for win in ec.windows.iter().filter(|w| w.id != ec.window) {
+            let buf = buffer::get(ec, win.buffer);
+            draw_rows(ec, buf, win, (win.rowoff, win.coloff), 0..win.height - 1, false)?;
+            draw_window_status(buf, win)?;
         }
+        draw_separators(ec)?;
+    }
+    if ec.tabs.len() > 1 {draw_tabline(ec)?}
+    let win = window::current(ec);
+    draw_rows(ec, &ec.buf, win, (ec.rowoff, ec.coloff), ec.dirty_rows.clone(), true)?;
 
-        // line numbering
-        // Relative line numbering first attempt. doesnt work now
-        let lineno = (y + rowoff).to_string();
-        let foreground_color = crossterm::style::Color::Rgb { r: 0x87, g: 0xce, b: 0xeb };
-        let lineno_spaces = " ".repeat(5 - lineno.len());
-        queue!(stdout(), 
-            SetForegroundColor(foreground_color),
-            crossterm::style::Print(format!("{}{} ", lineno_spaces, lineno)),
-            ResetColor
-        )?;
+    // write status line and command
+    draw_status(ec)?;
+    if ec.mode == Mode::Command || ec.mode == Mode::Search {draw_command(ec)?} else {draw_message(ec)?}
 
-        // highlighted words
-        let (keywords, types, preprocess, enclosers) = match ec.filename.split('.').last().unwrap() {
-            "rs" => (RUST_KEYWORDS.to_vec(), RUST_TYPES.to_vec(), RUST_PREPROCESS.to_vec(), RUST_ENCLOSERS.to_vec()),
-            "c" => (C_KEYWORDS.to_vec(), C_TYPES.to_vec(), C_PREPROCESS.to_vec(), C_ENCLOSERS.to_vec()),
-            "cpp" => (C_KEYWORDS.to_vec(), C_TYPES.to_vec(), C_PREPROCESS.to_vec(), C_ENCLOSERS.to_vec()),
-            "h" => (C_KEYWORDS.to_vec(), C_TYPES.to_vec(), C_PREPROCESS.to_vec(), C_ENCLOSERS.to_vec()),
-            "lua" => (LUA_KEYWORDS.to_vec(), LUA_TYPES.to_vec(), LUA_PREPROCCESS.to_vec(), LUA_ENCLOSERS.to_vec()),
-            _ => {
-                let _ = set_status_message(ec, "Filetype not supported for syntax higlighting!".to_string());
-                (vec![], vec![], vec![], vec![])
-            },
-        };
+    // Offset from line numbering
+    let win = window::current(ec);
+    let (y, x) = if ec.options.wrap {wrap::cursor(ec)} else {(ec.cy - ec.rowoff, ec.rx - ec.coloff)};
+    let x = x.min(text_cols(ec) - 1);
+    queue!(stdout(), 
+        cursor::MoveTo((win.left + x + 6) as u16, (win.top + y) as u16),
+        cursor::Show,
+    )?;
 
-        let mut enclosed = false;
-        let mut comment = false;
-        for token in ec.rows[y + rowoff].data.split_inclusive(SEPARATORS){
-            // Default white
-            let mut textcolor = ec.hl_colors[0];
+    // Set dirty rows to empty
+    ec.dirty_rows = vec![];
+    // Flush the queue to do the refresh
+    stdout().flush()?;
+    Ok(())
+}
 
-            let (mut token_text, mut separator);
-            if SEPARATORS.contains(&token.chars().last().unwrap()){
-                token_text = token.split(SEPARATORS).next().unwrap();
-                separator = token.chars().last().unwrap().to_string();
+// Draw screen rows ys of a window, relative to its top. Only the current window shows the selection
+fn draw_rows<I: IntoIterator<Item = usize>>(ec: &EditorConfig, buf: &buffer::Buffer, win: &window::Window, (rowoff, coloff): (usize, usize), ys: I, current: bool) -> io::Result<()> {
+    if ec.options.wrap {return draw_wrapped(ec, buf, win, rowoff, ys, current)}
+    let text_width = win.width.saturating_sub(6);
+    for y in ys {
+        if y + 1 >= win.height {continue}
+        queue!(stdout(), cursor::MoveTo(win.left as u16, (win.top + y) as u16))?;
 
-            } else {
-                token_text = token;
-                separator = "".to_string();
-            }
+        // If line is past file end draw ~
+        if y + rowoff >= buf.numrows {
+            queue!(stdout(), crossterm::style::Print(format!("{:<1$}", "~", win.width)))?;
+            continue;
+        }
+        draw_lineno(y + rowoff)?;
+        draw_line(ec, buf, y + rowoff, (coloff, text_width), current)?;
+    }
+    Ok(())
+}
 
-            if token_text == "//" {comment = true}
-
-            // highlight token text
-            if separator == '('.to_string() {textcolor = ec.hl_colors[1]}
-            if token_text != "".to_string() && token_text.chars().next().unwrap().is_numeric() {textcolor = ec.hl_colors[5]}
-            if keywords.contains(&token_text) {textcolor = ec.hl_colors[3]}
-            if types.contains(&token_text) {textcolor = ec.hl_colors[4]}
-            if preprocess.contains(&token_text) {textcolor = ec.hl_colors[5]}
-
-            // If we are in an "encloser" (like "") make all highlights yellow
-            if enclosed {
-                token_text = token;
-                separator = "".to_string();
-                textcolor = ec.hl_colors[2];
-                if ['\'', '\"', '>'].contains(&token.chars().last().unwrap()){
-                    enclosed = false
-                }
-            }
-            if enclosers.contains(&token.chars().next().unwrap()) {
-                token_text = token;
-                separator = "".to_string();
-                textcolor = ec.hl_colors[2];
-                enclosed = true;
+// With wrap set a row takes as many screen lines as it needs, ys are rows below rowoff and the
+// screen lines of each are drawn. The rows in between are only measured
+fn draw_wrapped<I: IntoIterator<Item = usize>>(ec: &EditorConfig, buf: &buffer::Buffer, win: &window::Window, rowoff: usize, ys: I, current: bool) -> io::Result<()> {
+    let text_width = win.width.saturating_sub(6);
+    let showbreak = grapheme::width(&ec.options.showbreak).min(text_width);
+    let (r, g, b) = utils::split_hex_into_bytes(ec.hl_colors[6]);
+    let nontext = SetForegroundColor(crossterm::style::Color::Rgb {r, g, b});
+    let dirty: Vec<usize> = ys.into_iter().collect();
+    let mut skip = if current {ec.skip} else {0};
+    let mut row = rowoff;
+    let mut y = 0;
+    while y + 1 < win.height {
+        let draw = dirty.contains(&(row - rowoff));
+        if row >= buf.numrows {
+            if draw {
+                queue!(stdout(),
+                    cursor::MoveTo(win.left as u16, (win.top + y) as u16),
+                    crossterm::style::Print(format!("{:<1$}", "~", win.width))
+                )?;
             }
-
-            if comment {textcolor = ec.hl_colors[6]}
-            let (r,g,b) = utils::split_hex_into_bytes(textcolor);
-            queue!(stdout(),
-                SetForegroundColor(crossterm::style::Color::Rgb {r, g, b}),
-                crossterm::style::Print(token_text.to_string()),
+            row += 1;
+            y += 1;
+            continue;
+        }
+        let line = buf.line(row);
+        let segments = wrap::segments(&line, text_width, &ec.options);
+        if !draw {
+            y += segments.len() - skip;
+            (row, skip) = (row + 1, 0);
+            continue;
+        }
+        let last = segments.len() - 1;
+        for (i, (from, to)) in segments.into_iter().enumerate().skip(skip) {
+            if y + 1 >= win.height {break}
+            queue!(stdout(), cursor::MoveTo(win.left as u16, (win.top + y) as u16))?;
+            let room = if i == 0 {
+                draw_lineno(row)?;
+                text_width
+            } else {
+                queue!(stdout(),
+                    crossterm::style::Print("      "),
+                    nontext,
+                    crossterm::style::Print(grapheme::clip(&ec.options.showbreak, showbreak)),
+                    ResetColor
+                )?;
+                text_width - showbreak
+            };
+            let (left, right) = (grapheme::col(&line, from, ec.options.tabstop), grapheme::col(&line, to, ec.options.tabstop));
+            let width = (right - left).min(room);
+            draw_line(ec, buf, row, (left, width), current)?;
+            queue!(stdout(), crossterm::style::Print(" ".repeat(room - width)))?;
+            // like vim's display=lastline, <<< over the start of a row scrolled inside itself and
+            // @@@ over the end of one cut off by the bottom of the window
+            let marker = if i == skip && i > 0 {Some((6, "<<<"))}
+                else if i < last && y + 2 >= win.height {Some((win.width.saturating_sub(3), "@@@"))}
+                else {None};
+            if let Some((x, text)) = marker {
+                queue!(stdout(),
+                    cursor::MoveTo((win.left + x) as u16, (win.top + y) as u16),
+                    nontext,
+                    crossterm::style::Print(grapheme::clip(text, win.width.saturating_sub(x))),
+                    ResetColor
                 )?;
-            queue!(stdout(),
-                ResetColor,
-                crossterm::style::Print(separator.to_string())
-            )?;
+            }
+            y += 1;
         }
-
-        queue!(stdout(),
-            crossterm::style::Print("\r\n"),
-            ResetColor
-        )?;
+        (row, skip) = (row + 1, 0);
     }
+    Ok(())
+}
 
-    // write status line and command
-    draw_status(ec)?;
-    if ec.mode == Mode::Command {draw_command(ec)?}
+fn draw_lineno(row: usize) -> io::Result<()> {
+    // Relative line numbering first attempt. doesnt work now
+    let lineno = row.to_string();
+    let foreground_color = crossterm::style::Color::Rgb { r: 0x87, g: 0xce, b: 0xeb };
+    let lineno_spaces = " ".repeat(5usize.saturating_sub(lineno.len()));
+    queue!(stdout(), 
+        SetForegroundColor(foreground_color),
+        crossterm::style::Print(format!("{}{} ", lineno_spaces, lineno)),
+        ResetColor
+    )
+}
 
-    // Prevent cx from going past row length
-    let rowlen = ec.rows[ec.cy].data.len();
-    if ec.cx > rowlen{
-        queue!(stdout(), cursor::MoveTo(rowlen as u16, ec.cy as u16))?;
-        ec.cx = rowlen;
+// Draw width columns of buffer row row starting at column coloff, with its highlighting
+fn draw_line(ec: &EditorConfig, buf: &buffer::Buffer, row: usize, (coloff, width): (usize, usize), current: bool) -> io::Result<()> {
+    let data = &buf.line(row);
+    let selection = if current {visual::selected_cols(ec, row)} else {None};
+    let matches = search::matches_in_row(ec, data);
+    let view = RowView { coloff, width, tabstop: ec.options.tabstop, selection, matches: &matches };
+    let (mut x, mut col) = (0, 0);
+    let spans = buf.highlight(&ec.syntaxes, row);
+    // a line with no syntax is all text
+    let spans = if spans.is_empty() {vec![(data.len(), highlight::Style::Text)]} else {spans};
+    for (end, style) in spans {
+        match style.color() {
+            Some(i) => {
+                let (r, g, b) = utils::split_hex_into_bytes(ec.hl_colors[i]);
+                queue!(stdout(), SetForegroundColor(crossterm::style::Color::Rgb {r, g, b}))?;
+            }
+            None => queue!(stdout(), ResetColor)?,
+        }
+        print_selected(&data[x..end], &mut x, &mut col, &view)?;
     }
 
-    // Offset from line numbering
-    queue!(stdout(), 
-        cursor::MoveTo(ec.cx as u16 + 6, ec.cy as u16 - ec.rowoff as u16),
-        cursor::Show,
-    )?;
+    // pad to the window edge instead of clearing the line, there may be a window to the right
+    queue!(stdout(),
+        ResetColor,
+        crossterm::style::Print(" ".repeat(width - col.saturating_sub(coloff).min(width)))
+    )
+}
 
-    // Set dirty rows to empty
-    ec.dirty_rows = vec![];
-    // Flush the queue to do the refresh
-    stdout().flush()?;
+// What stays the same while a row is drawn
+struct RowView<'a> {
+    coloff: usize, // first column shown
+    width: usize,
+    tabstop: usize,
+    selection: Option<(usize, usize)>,
+    matches: &'a [(usize, usize)],
+}
+
+// Print text found at byte x of its line, which reaches column col, reversing the part inside the
+// visual selection and marking search matches. Tabs are drawn as spaces up to the next tab stop.
+// Only the columns from coloff on that fit in the window are printed, the rest are still counted
+// so the highlighting doesn't change as the view scrolls
+fn print_selected(text: &str, x: &mut usize, col: &mut usize, view: &RowView) -> io::Result<()> {
+    let (left, right) = (view.coloff, view.coloff + view.width);
+    let mut at = *x;
+    *x += text.len();
+    let mut run = String::new();
+    let mut style = (false, false);
+    for g in text.graphemes(true) {
+        let width = grapheme::cell_width(g, *col, view.tabstop);
+        if *col + width > right {
+            // a wide character cut by the edge leaves blanks
+            run.push_str(&" ".repeat(right.saturating_sub((*col).max(left))));
+            *col = right.max(*col);
+            break;
+        }
+        let shown = if *col >= left && g != "\t" {
+            g.to_string()
+        } else {
+            // tabs, and the visible part of anything cut by the left edge
+            " ".repeat((*col + width).saturating_sub((*col).max(left)))
+        };
+        let selected = view.selection.is_some_and(|(from, to)| from <= at && at < to);
+        let matched = view.matches.iter().any(|&(from, to)| from <= at && at < to);
+        if !shown.is_empty() && (selected, matched) != style {
+            print_styled(&std::mem::take(&mut run), style)?;
+            style = (selected, matched);
+        }
+        run.push_str(&shown);
+        at += g.len();
+        *col += width;
+    }
+    print_styled(&run, style)
+}
+
+fn print_styled(text: &str, (selected, matched): (bool, bool)) -> io::Result<()> {
+    if text.is_empty() {return Ok(())}
+    let (r, g, b) = utils::split_hex_into_bytes(SEARCH_HL);
+    if selected {queue!(stdout(), SetAttribute(Attribute::Reverse))?}
+    if matched {queue!(stdout(), SetBackgroundColor(crossterm::style::Color::Rgb {r, g, b}))?}
+    queue!(stdout(), crossterm::style::Print(text))?;
+    if selected {queue!(stdout(), SetAttribute(Attribute::NoReverse))?}
+    if matched {queue!(stdout(), SetBackgroundColor(crossterm::style::Color::Reset))?}
     Ok(())
 }
 
+// Cut s down to the room left on a status line
+fn fit_status(s: &str, room: &mut usize) -> String {
+    let mut fitted = String::new();
+    for g in s.graphemes(true) {
+        let width = grapheme::width(g);
+        if width > *room {break}
+        *room -= width;
+        fitted.push_str(g);
+    }
+    fitted
+}
+
 fn draw_status(ec: &mut EditorConfig) -> io::Result<()> {
     let (mode_color, mode_string) = match ec.mode {
         Mode::Normal => (crossterm::style::Color::Blue, "NORMAL"),
         Mode::Insert => (crossterm::style::Color::Green, "INSERT"),
         Mode::Visual => (crossterm::style::Color::Magenta, "VISUAL"),
+        Mode::VisualLine => (crossterm::style::Color::Magenta, "V-LINE"),
+        Mode::VisualBlock => (crossterm::style::Color::Magenta, "V-BLOCK"),
         Mode::Command => (crossterm::style::Color::Yellow, "COMMAND"),
+        Mode::Search => (crossterm::style::Color::Yellow, "SEARCH"),
     };
+    let win = window::current(ec);
+    let mut room = win.width;
+    let info = format!(
+        " Row: {}/{} - Screen {}/{} - Col: {}/{} - Rowoff {}", 
+        ec.cy, 
+        ec.buf.numrows, 
+        ec.cy - ec.rowoff, 
+        ec.screenrows, 
+        ec.cx, 
+        ec.buf.line_len(ec.cy), 
+        ec.rowoff
+    );
     queue!(stdout(),
-        cursor::MoveTo(0, ec.screenrows as u16),
+        cursor::MoveTo(win.left as u16, (win.top + win.height - 1) as u16),
         SetColors(crossterm::style::Colors{ foreground: Some(crossterm::style::Color::Black), background: Some(mode_color)}),
-        crossterm::style::Print(mode_string),
+        crossterm::style::Print(fit_status(mode_string, &mut room)),
         SetColors(crossterm::style::Colors{ foreground: Some(crossterm::style::Color::Black), background: Some(crossterm::style::Color::White)}),
-        crossterm::style::Print(ec.filename.clone()),
+        crossterm::style::Print(fit_status(&ec.buf.filename, &mut room)),
+    )?;
+    if !ec.buf.filetype.is_empty() {
+        queue!(stdout(), crossterm::style::Print(fit_status(&format!(" [{}]", ec.buf.filetype), &mut room)))?;
+    }
+    if ec.buf.dirty {
+        queue!(stdout(), crossterm::style::Print(fit_status(" [+] ", &mut room)))?;
+    }
+    queue!(stdout(),
+        ResetColor,
+        crossterm::style::Print(fit_status(&info, &mut room)),
+        crossterm::style::Print(" ".repeat(room)),
+    )?;
+    Ok(())
+}
+
+// Status line of a window that isn't current, just the file
+fn draw_window_status(buf: &buffer::Buffer, win: &window::Window) -> io::Result<()> {
+    let name = format!(" {}{}", buf.name(), if buf.dirty {" [+]"} else {""});
+    let mut room = win.width;
+    queue!(stdout(),
+        cursor::MoveTo(win.left as u16, (win.top + win.height - 1) as u16),
+        SetColors(crossterm::style::Colors{ foreground: Some(crossterm::style::Color::Black), background: Some(crossterm::style::Color::Grey)}),
+        crossterm::style::Print(fit_status(&name, &mut room)),
+        crossterm::style::Print(" ".repeat(room)),
+        ResetColor,
     )?;
-    if ec.dirty {
-        queue!(stdout(), crossterm::style::Print(" [+] "))?;
+    Ok(())
+}
+
+// The row above the windows listing the tab pages, the current one stands out
+fn draw_tabline(ec: &EditorConfig) -> io::Result<()> {
+    let mut room = ec.termcols;
+    queue!(stdout(), cursor::MoveTo(0, 0))?;
+    for (i, (name, dirty)) in tab::labels(ec).into_iter().enumerate() {
+        let background = if i == ec.tab {crossterm::style::Color::White} else {crossterm::style::Color::DarkGrey};
+        let label = format!(" {}{} ", if dirty {"+ "} else {""}, name);
+        queue!(stdout(),
+            SetColors(crossterm::style::Colors{ foreground: Some(crossterm::style::Color::Black), background: Some(background)}),
+            crossterm::style::Print(fit_status(&label, &mut room)),
+        )?;
     }
     queue!(stdout(),
         ResetColor,
-        crossterm::style::Print(
-            format!(
-                " Row: {}/{} - Screen {}/{} - Col: {}/{} - Rowoff {}", 
-                ec.cy, 
-                ec.numrows, 
-                ec.cy - ec.rowoff, 
-                ec.screenrows, 
-                ec.cx, 
-                ec.rows[ec.cy].data.len(), 
-                ec.rowoff
-            )
-        ),
-        crossterm::style::Print("\r\n"),
+        crossterm::style::Print(" ".repeat(room)),
+    )?;
+    Ok(())
+}
+
+// The column between windows side by side
+fn draw_separators(ec: &EditorConfig) -> io::Result<()> {
+    for win in ec.windows.iter().filter(|w| w.left + w.width < ec.termcols) {
+        for y in win.top..win.top + win.height {
+            queue!(stdout(), cursor::MoveTo((win.left + win.width) as u16, y as u16), crossterm::style::Print("|"))?;
+        }
+    }
+    Ok(())
+}
+
+fn draw_message(ec: &mut EditorConfig) -> io::Result<()> {
+    queue!(stdout(),
+        cursor::MoveTo(0, ec.termrows as u16 - 1),
+        Clear(ClearType::CurrentLine),
         crossterm::style::Print(ec.status_msg.clone()),
     )?;
     Ok(())
 }
 
 fn draw_command(ec: &mut EditorConfig) -> io::Result<()>{
+    let prefix = match ec.mode {
+        Mode::Search if ec.search.forward() => "/",
+        Mode::Search => "?",
+        _ => ":",
+    };
     queue!(stdout(),
-        cursor::MoveTo(0, ec.screenrows as u16 + 1),
+        cursor::MoveTo(0, ec.termrows as u16 - 1),
         Clear(ClearType::CurrentLine),
-        crossterm::style::Print(":"),
+        crossterm::style::Print(prefix),
         crossterm::style::Print(ec.command.clone()),
-        cursor::MoveTo(1 + ec.command.len() as u16, ec.screenrows as u16 + 1)
+        cursor::MoveTo(1 + grapheme::width(&ec.command) as u16, ec.termrows as u16 - 1)
     )?;
     Ok(())
 }
@@ -418,43 +742,72 @@ fn set_status_message(ec: &mut EditorConfig, message: String) -> io::Result<()>
 fn editor_open(ec: &mut EditorConfig, filename: String) -> io::Result<()>{
     let file: String = match fs::read_to_string(filename.clone()){
         Ok(file_content) => file_content,
-        Err(_) => {
-            let mut file = fs::File::create(&filename)?;
-            let empty_file = "\r\n".repeat(ec.numrows);
-            file.write_all(empty_file.as_bytes())?; // Write an empty string to create the file
-            insert_row(ec, 0, String::default());
+        // nothing is created on disk until :w. Any other error leaves the buffer alone
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
             set_status_message(ec, String::from("new file"))?;
             String::new()
         }
+        Err(e) => return Err(e),
     };
 
-    for line in file.lines(){
-        insert_row(ec, ec.numrows, line.to_string());
-    }
-    if ec.numrows == 0 {insert_row(ec, 0, String::new())}
-    ec.dirty = false;
-    ec.filename = filename;
+    if !file.is_empty() {ec.buf.load(&file)}
+    if ec.buf.numrows == 0 {insert_row(ec, 0, String::new())}
+    ec.buf.dirty = false;
+    ec.buf.filename = filename;
+    ec.buf.filetype = filetype::detect(&ec.syntaxes, &ec.buf);
+    // loading the file is not an undoable change, but the history from last time might still apply
+    undo::load(ec, &file);
     Ok(())
 }
 
+// Drop the current file before :e reloads it, handing it back in case the reload fails
+fn editor_close(ec: &mut EditorConfig) -> buffer::Buffer {
+    let id = ec.buf.id;
+    let mut old = std::mem::replace(&mut ec.buf, buffer::Buffer::new(id));
+    (old.cursor, old.rowoff) = ((ec.cx, ec.cy), ec.rowoff);
+    (ec.cx, ec.cy, ec.rx) = (0, 0, 0);
+    (ec.rowoff, ec.coloff) = (0, 0);
+    ec.dirty_rows.extend(0..ec.screenrows);
+    old
+}
+
+// Lines first..=last joined the way they are written to disk
+fn buffer_text(ec: &EditorConfig, first: usize, last: usize) -> String {
+    ec.buf.text((0, first), (ec.buf.line_len(last), last))
+}
+
 fn editor_save(ec: &mut EditorConfig) -> io::Result<()>{
-    let content = ec.rows.iter()
-        .map(|s| &s.data)
-        .map(String::as_str)
-        .collect::<Vec<_>>()
-        .join("\n");
-    fs::write(ec.filename.clone(), content)?;
-    ec.dirty = false;
+    let file = fs::File::create(&ec.buf.filename)?;
+    ec.buf.write_to(io::BufWriter::new(file))?;
+    let _ = undo::save(ec);
+    ec.buf.dirty = false;
     Ok(())
 }
 
 fn insert_row(ec: &mut EditorConfig, at: usize, s: String) {
-    if at > ec.numrows {return;}
+    if at > ec.buf.numrows {return;}
+
+    ec.buf.insert_line(at, &s);
+    ec.buf.undo.record(undo::Splice { at, old: vec![], new: vec![s] }, (ec.cx, ec.cy));
+    ec.buf.dirty = true;
+}
 
-    let new_row = Erow::new(s);
-    ec.rows.insert(at, new_row);
-    ec.numrows += 1;
-    ec.dirty = true;
+fn delete_row(ec: &mut EditorConfig, at: usize) -> String {
+    let old = ec.buf.remove_line(at);
+    ec.buf.undo.record(undo::Splice { at, old: vec![old.clone()], new: vec![] }, (ec.cx, ec.cy));
+    ec.buf.dirty = true;
+    old
+}
+
+// All in-place row edits go through here so they are recorded for undo
+fn edit_row<F: FnOnce(&mut String)>(ec: &mut EditorConfig, at: usize, f: F) {
+    let old = ec.buf.line(at);
+    let mut new = old.clone();
+    f(&mut new);
+    if new == old {return}
+    ec.buf.set_line(at, &new);
+    ec.buf.undo.record(undo::Splice { at, old: vec![old], new: vec![new] }, (ec.cx, ec.cy));
+    ec.buf.dirty = true;
 }
 
 /*** Motions ***/
@@ -462,29 +815,52 @@ fn insert_row(ec: &mut EditorConfig, at: usize, s: String) {
 fn colon(ec: &mut EditorConfig){
     ec.mode = Mode::Command;
     let _ = set_status_message(ec, String::default());
-    print!("\x1b[1;{}H", ec.numrows);
+    print!("\x1b[1;{}H", ec.buf.numrows);
+}
+
+fn slash(ec: &mut EditorConfig){
+    search::start(ec, true);
+}
+
+fn question(ec: &mut EditorConfig){
+    search::start(ec, false);
+}
+
+fn n_motion(ec: &mut EditorConfig){
+    search::repeat(ec, false);
+}
+
+fn un_motion(ec: &mut EditorConfig){
+    search::repeat(ec, true);
+}
+
+fn star_motion(ec: &mut EditorConfig){
+    search::word_under_cursor(ec, true);
+}
+
+fn hash_motion(ec: &mut EditorConfig){
+    search::word_under_cursor(ec, false);
 }
 
 fn ua_motion(ec: &mut EditorConfig){
-    ec.cx = ec.rows[ec.cy].data.len();
+    ec.cx = ec.buf.line_len(ec.cy);
     let _ = stdout().execute(cursor::SetCursorStyle::SteadyBar);
     ec.mode = Mode::Insert;
 }
 
 fn a_motion(ec: &mut EditorConfig) {
-    ec.cx += 1;
+    ec.cx = ec.buf.next_grapheme(ec.cy, ec.cx);
     let _ = stdout().execute(cursor::SetCursorStyle::SteadyBar);
     ec.mode = Mode::Insert;
 }
 
 fn o_motion(ec: &mut EditorConfig){
     // Insert a new row below with the same indention as the current row
-    let whitespace = leading_whitespace(ec.rows[ec.cy].data.clone());
-    let leading_spaces = " ".repeat(whitespace);
+    let whitespace = leading_whitespace(ec.buf.line(ec.cy));
+    let indent = ec.buf.line(ec.cy)[..whitespace].to_string();
+    insert_row(ec, ec.cy + 1, indent);
     ec.cy += 1;
     ec.cx = whitespace + 1;
-    ec.rows.insert(ec.cy, Erow::new(leading_spaces));
-    ec.numrows += 1;
     // set all rows after as dirty
     ec.dirty_rows.extend((ec.cy - ec.rowoff)..ec.screenrows);
     let _ = stdout().execute(cursor::SetCursorStyle::SteadyBar);
@@ -493,11 +869,10 @@ fn o_motion(ec: &mut EditorConfig){
 
 fn uo_motion(ec: &mut EditorConfig){
     // Insert a new row above with the same indention as the current row
-    let whitespace = leading_whitespace(ec.rows[ec.cy].data.clone());
-    let leading_spaces = " ".repeat(whitespace);
+    let whitespace = leading_whitespace(ec.buf.line(ec.cy));
+    let indent = ec.buf.line(ec.cy)[..whitespace].to_string();
+    insert_row(ec, ec.cy, indent);
     ec.cx = whitespace + 1;
-    ec.rows.insert(ec.cy, Erow::new(leading_spaces));
-    ec.numrows += 1;
     // set all rows after as dirty
     ec.dirty_rows.extend((ec.cy - ec.rowoff)..ec.screenrows);
     let _ = stdout().execute(cursor::SetCursorStyle::SteadyBar);
@@ -506,28 +881,27 @@ fn uo_motion(ec: &mut EditorConfig){
 
 fn w_motion(ec: &mut EditorConfig){
     // Move forward 1 (make sure we dont go past eof)
-    ec.cx += 1;
-    if ec.cy == ec.numrows - 1 && ec.cx >= ec.rows[ec.cy].data.len() {return}
+    ec.cx = ec.buf.next_grapheme(ec.cy, ec.cx);
+    if ec.cy == ec.buf.numrows - 1 && ec.cx >= ec.buf.line_len(ec.cy) {return}
 
-    if ec.cx >= ec.rows[ec.cy].data.len() {
+    if ec.cx >= ec.buf.line_len(ec.cy) {
         ec.cy += 1;
         ec.cx = 0;
-        if ec.rows[ec.cy].data.is_empty() {return}
-        while ec.rows[ec.cy].data.chars().nth(ec.cx).unwrap() == ' ' {ec.cx += 1}
+        while ec.buf.char_at(ec.cy, ec.cx) == Some(' ') {ec.cx += 1}
         return
     }
 
     // Find a separator
-    while !SEPARATORS.contains(&ec.rows[ec.cy].data.chars().nth(ec.cx).unwrap()){
-        if ec.cy == ec.numrows && ec.cx >= ec.rows[ec.cy].data.len() {return}
-        if ec.cx == ec.rows[ec.cy].data.len() - 1 {return}
-        ec.cx += 1;
+    while !SEPARATORS.contains(&ec.buf.char_at(ec.cy, ec.cx).unwrap()){
+        if ec.cy == ec.buf.numrows && ec.cx >= ec.buf.line_len(ec.cy) {return}
+        if ec.buf.next_grapheme(ec.cy, ec.cx) == ec.buf.line_len(ec.cy) {return}
+        ec.cx = ec.buf.next_grapheme(ec.cy, ec.cx);
     }
 
     // Find start of next token
-    while SEPARATORS.contains(&ec.rows[ec.cy].data.chars().nth(ec.cx).unwrap()){
-        if ec.cx == ec.rows[ec.cy].data.len() - 1 {return}
-        ec.cx += 1;
+    while SEPARATORS.contains(&ec.buf.char_at(ec.cy, ec.cx).unwrap()){
+        if ec.buf.next_grapheme(ec.cy, ec.cx) == ec.buf.line_len(ec.cy) {return}
+        ec.cx = ec.buf.next_grapheme(ec.cy, ec.cx);
     }
 }
 
@@ -536,61 +910,125 @@ fn b_motion(ec: &mut EditorConfig){
     if ec.cx == 0 && ec.cy == 0 {return}
     while ec.cx == 0 {
         ec.cy -= 1;
-        ec.cx = ec.rows[ec.cy].data.len();
+        ec.cx = ec.buf.line_len(ec.cy);
     }
-    ec.cx -= 1;
+    ec.cx = ec.buf.prev_grapheme(ec.cy, ec.cx);
 
     // Keep going back until we find a letter
-    while SEPARATORS.contains(&ec.rows[ec.cy].data.chars().nth(ec.cx).unwrap()){
+    while SEPARATORS.contains(&ec.buf.char_at(ec.cy, ec.cx).unwrap()){
         if ec.cx == 0 && ec.cy == 0 {return}
         while ec.cx == 0 {
             ec.cy -= 1;
-            ec.cx = ec.rows[ec.cy].data.len();
+            ec.cx = ec.buf.line_len(ec.cy);
         }
-        ec.cx -= 1;
+        ec.cx = ec.buf.prev_grapheme(ec.cy, ec.cx);
     }
 
     // Find whitespace after finding this letter (or get to the front of line?)
-    while !SEPARATORS.contains(&ec.rows[ec.cy].data.chars().nth(ec.cx).unwrap()){
+    while !SEPARATORS.contains(&ec.buf.char_at(ec.cy, ec.cx).unwrap()){
         if ec.cx == 0 && ec.cy == 0 {return}
         if ec.cx == 0 {return}
         while ec.cx == 0 {
             ec.cy -= 1;
-            ec.cx = ec.rows[ec.cy].data.len();
+            ec.cx = ec.buf.line_len(ec.cy);
         }
-        ec.cx -= 1;
+        ec.cx = ec.buf.prev_grapheme(ec.cy, ec.cx);
     }
 
     // Move to letter after whitespace
-    ec.cx += 1;
+    ec.cx = ec.buf.next_grapheme(ec.cy, ec.cx);
 }
 
-fn dd_motion(ec: &mut EditorConfig){
-    if ec.numrows == 1 {
-        ec.rows[0].data = String::new();
-        ec.cx = 0;
-        ec.cy = 0;
-    } else {
-        ec.rows.remove(ec.cy);
-        ec.numrows -= 1;
-        if ec.cy == ec.numrows {ec.cy -= 1}
+// gj and gk move by screen lines when long lines wrap, like j and k otherwise
+fn gj_motion(ec: &mut EditorConfig){
+    if ec.options.wrap {wrap::vertical(ec, true)} else {j_motion(ec)}
+}
+
+fn gk_motion(ec: &mut EditorConfig){
+    if ec.options.wrap {wrap::vertical(ec, false)} else {k_motion(ec)}
+}
+
+// zl and zh scroll the view sideways, moving the cursor when it would go off screen
+fn zl_motion(ec: &mut EditorConfig){
+    ec.coloff += 1;
+    cursor_into_view(ec);
+}
+
+fn zh_motion(ec: &mut EditorConfig){
+    ec.coloff = ec.coloff.saturating_sub(1);
+    cursor_into_view(ec);
+}
+
+// zs and ze scroll so the cursor is at the left or right edge
+fn zs_motion(ec: &mut EditorConfig){
+    let col = grapheme::col(&ec.buf.line(ec.cy), ec.cx, ec.options.tabstop);
+    ec.coloff = col.saturating_sub(ec.options.sidescrolloff);
+    ec.dirty_rows.extend(0..ec.screenrows);
+}
+
+fn ze_motion(ec: &mut EditorConfig){
+    let col = grapheme::col(&ec.buf.line(ec.cy), ec.cx, ec.options.tabstop);
+    ec.coloff = (col + ec.options.sidescrolloff + 1).saturating_sub(text_cols(ec));
+    ec.dirty_rows.extend(0..ec.screenrows);
+}
+
+fn cursor_into_view(ec: &mut EditorConfig){
+    let textcols = text_cols(ec);
+    let siso = ec.options.sidescrolloff.min((textcols - 1) / 2);
+    let line = ec.buf.line(ec.cy);
+    let col = grapheme::col(&line, ec.cx, ec.options.tabstop);
+    if col < ec.coloff + siso {
+        let x = grapheme::at_col(&line, ec.coloff + siso, ec.options.tabstop);
+        // not a wide character that starts off screen
+        let x = if grapheme::col(&line, x, ec.options.tabstop) < ec.coloff + siso {grapheme::next(&line, x)} else {x};
+        ec.cx = x;
+    } else if col + siso >= ec.coloff + textcols {
+        ec.cx = grapheme::at_col(&line, ec.coloff + textcols - 1 - siso, ec.options.tabstop);
     }
-    // set all rows after as dirty
-    ec.dirty_rows.extend((ec.cy - ec.rowoff)..ec.screenrows);
+    ec.dirty_rows.extend(0..ec.screenrows);
+}
+
+fn u_motion(ec: &mut EditorConfig){
+    match undo::undo(ec) {
+        Ok(true) => {}
+        Ok(false) => {let _ = set_status_message(ec, String::from("Already at oldest change"));}
+        Err(msg) => {let _ = set_status_message(ec, msg);}
+    }
+}
+
+fn g_minus_motion(ec: &mut EditorConfig){
+    if let Err(msg) = undo::step(ec, -1) {let _ = set_status_message(ec, msg);}
+}
+
+// {count}gt goes to tab count instead of moving count tabs
+fn gt_motion(ec: &mut EditorConfig){
+    if ec.motion_count == 0 {
+        tab::next(ec, 1);
+    } else if let Err(msg) = tab::goto(ec, ec.motion_count) {
+        let _ = set_status_message(ec, msg);
+    }
+}
+
+fn g_ut_motion(ec: &mut EditorConfig){
+    tab::previous(ec, ec.motion_count.max(1));
+}
+
+fn g_plus_motion(ec: &mut EditorConfig){
+    if let Err(msg) = undo::step(ec, 1) {let _ = set_status_message(ec, msg);}
 }
 
 fn ui_motion(ec: &mut EditorConfig){
-    ec.cx = leading_whitespace(ec.rows[ec.cy].data.clone());
+    ec.cx = leading_whitespace(ec.buf.line(ec.cy));
     let _ = stdout().execute(cursor::SetCursorStyle::SteadyBar);
     ec.mode = Mode::Insert;
 }
 
 fn ug_motion(ec: &mut EditorConfig){
-    ec.cy = ec.numrows - 1;
+    ec.cy = ec.buf.numrows - 1;
 }
 
 fn h_motion(ec: &mut EditorConfig){
-    if ec.cx > 0 {ec.cx -= 1;}
+    ec.cx = ec.buf.prev_grapheme(ec.cy, ec.cx);
 }
 
 fn i_motion(ec: &mut EditorConfig){
@@ -599,130 +1037,369 @@ fn i_motion(ec: &mut EditorConfig){
 }
 
 fn j_motion(ec: &mut EditorConfig){
-    if ec.cy < ec.numrows - 1 {ec.cy += 1;}
+    if ec.cy < ec.buf.numrows - 1 {move_to_row(ec, ec.cy + 1)}
 }
 
 fn k_motion(ec: &mut EditorConfig){
-    if ec.cy > 0 {ec.cy -= 1;}
+    if ec.cy > 0 {move_to_row(ec, ec.cy - 1)}
+}
+
+// Stay in the same screen column, which isn't the same byte once wide characters are involved
+fn move_to_row(ec: &mut EditorConfig, cy: usize){
+    let col = grapheme::col(&ec.buf.line(ec.cy), ec.cx, ec.options.tabstop);
+    ec.cy = cy;
+    ec.cx = grapheme::at_col(&ec.buf.line(ec.cy), col, ec.options.tabstop);
 }
 
 fn l_motion(ec: &mut EditorConfig){
-    if ec.cx < ec.rows[ec.cy].data.len() {ec.cx += 1;}
+    ec.cx = ec.buf.next_grapheme(ec.cy, ec.cx);
 }
 
 fn v_motion(ec: &mut EditorConfig){
-    ec.mode = Mode::Visual
+    visual::start(ec, Mode::Visual);
+}
+
+fn uv_motion(ec: &mut EditorConfig){
+    visual::start(ec, Mode::VisualLine);
 }
 
 fn empty_up(ec: &mut EditorConfig) {
     if ec.cy == 0 {return}
     ec.cy -= 1;
-    while !ec.rows[ec.cy].data.is_empty() {
+    while ec.buf.line_len(ec.cy) != 0 {
         if ec.cy == 0 {return}
         ec.cy -= 1;
     }
 }
 
 fn empty_down(ec: &mut EditorConfig) {
-    if ec.cy == ec.numrows - 1 {return}
+    if ec.cy == ec.buf.numrows - 1 {return}
     ec.cy += 1;
-    while !ec.rows[ec.cy].data.is_empty() {
-        if ec.cy == ec.numrows - 1 {return}
+    while ec.buf.line_len(ec.cy) != 0 {
+        if ec.cy == ec.buf.numrows - 1 {return}
         ec.cy += 1;
     }
 }
 
+// ]m and [m go to the start of the next or previous function, ]M and [M to the end of one
+fn function_jump(ec: &mut EditorConfig, forward: bool, ends: bool) {
+    let Some(target) = ec.buf.function((ec.cx, ec.cy), forward, ends) else {
+        let _ = set_status_message(ec, String::from("No syntax tree for this buffer"));
+        return
+    };
+    if let Some((x, y)) = target {(ec.cx, ec.cy) = (x, y)}
+}
+
+fn next_function(ec: &mut EditorConfig) {
+    function_jump(ec, true, false);
+}
+
+fn prev_function(ec: &mut EditorConfig) {
+    function_jump(ec, false, false);
+}
+
+fn next_function_end(ec: &mut EditorConfig) {
+    function_jump(ec, true, true);
+}
+
+fn prev_function_end(ec: &mut EditorConfig) {
+    function_jump(ec, false, true);
+}
+
 fn gg_motion(ec: &mut EditorConfig){
     ec.cy = 0;
 }
 
+fn line_start(ec: &mut EditorConfig){
+    ec.cx = 0;
+}
+
+fn first_non_blank(ec: &mut EditorConfig){
+    ec.cx = leading_whitespace(ec.buf.line(ec.cy));
+}
+
+fn line_end(ec: &mut EditorConfig){
+    ec.cx = ec.buf.prev_grapheme(ec.cy, ec.buf.line_len(ec.cy));
+}
+
 fn x_motion(ec: &mut EditorConfig){
-    if ec.cx == ec.rows[ec.cy].data.len() {return}
-    ec.rows[ec.cy].data.remove(ec.cx);
+    if ec.cx == ec.buf.line_len(ec.cy) {return}
+    let cx = ec.cx;
+    let mut end = cx;
+    for _i in 0..ec.motion_count.max(1) {end = ec.buf.next_grapheme(ec.cy, end)}
+    let text = ec.buf.text((cx, ec.cy), (end, ec.cy));
+    ec.registers.delete(ec.register, register::Register::charwise(&text));
+    edit_row(ec, ec.cy, |data| {data.drain(cx..end);});
+}
+
+fn uy_motion(ec: &mut EditorConfig){
+    operator::operate_lines(ec, "y", ec.motion_count);
+}
+
+fn p_motion(ec: &mut EditorConfig){
+    put(ec, true);
+}
+
+fn up_motion(ec: &mut EditorConfig){
+    put(ec, false);
+}
+
+fn put(ec: &mut EditorConfig, after: bool){
+    let Some(reg) = ec.registers.get(ec.register).cloned() else {
+        let name = ec.register.unwrap_or('"');
+        let _ = set_status_message(ec, format!("E353: Nothing in register {}", name));
+        return
+    };
+    let count = ec.motion_count.max(1);
+
+    // a block goes in column-wise, one piece on each line from the cursor down
+    if reg.blockwise {
+        let ts = ec.options.tabstop;
+        let line = ec.buf.line(ec.cy);
+        let (start, end) = grapheme::span(&line, ec.cx, ts);
+        let col = if after && !line.is_empty() {end} else {start};
+        let width = |s: &str| grapheme::col(s, s.len(), ts);
+        let block_width = reg.text.iter().map(|piece| width(piece)).max().unwrap_or(0);
+        for (i, piece) in reg.text.iter().enumerate() {
+            let y = ec.cy + i;
+            if y >= ec.buf.numrows {insert_row(ec, y, String::new())}
+            let row_width = width(&ec.buf.line(y));
+            edit_row(ec, y, |data| {
+                if row_width < col {data.push_str(&" ".repeat(col - row_width))}
+                let x = grapheme::at_col(data, col, ts);
+                // short pieces are padded to the block's width, unless nothing follows them
+                let fill = block_width - width(piece);
+                let mut text = format!("{}{}", piece, " ".repeat(fill)).repeat(count);
+                if x == data.len() {text.truncate(text.len() - fill)}
+                data.insert_str(x, &text);
+            });
+        }
+        ec.cx = grapheme::at_col(&ec.buf.line(ec.cy), col, ts);
+        ec.dirty_rows.extend(ec.cy.saturating_sub(ec.rowoff)..ec.screenrows);
+        return
+    }
+
+    if reg.linewise {
+        let at = if after {ec.cy + 1} else {ec.cy};
+        for i in 0..count * reg.text.len() {
+            insert_row(ec, at + i, reg.text[i % reg.text.len()].clone());
+        }
+        ec.cy = at;
+        ec.cx = leading_whitespace(ec.buf.line(ec.cy));
+        ec.dirty_rows.extend(ec.cy.saturating_sub(ec.rowoff)..ec.screenrows);
+        return
+    }
+
+    let line = ec.buf.line(ec.cy);
+    let col = if after {grapheme::next(&line, ec.cx)} else {grapheme::floor(&line, ec.cx)};
+    let text = vec![reg.text.join("\n"); count].concat();
+    let (before_cursor, after_cursor) = line.split_at(col);
+    let mut lines: Vec<String> = text.split('\n').map(String::from).collect();
+    let last_len = lines[lines.len() - 1].len();
+    lines[0] = format!("{}{}", before_cursor, lines[0]);
+    let last = lines.len() - 1;
+    lines[last].push_str(after_cursor);
+
+    let cy = ec.cy;
+    let first = lines.remove(0);
+    edit_row(ec, cy, |data| *data = first);
+    if lines.is_empty() {
+        // single line put leaves the cursor on the last character put
+        ec.cx = ec.buf.prev_grapheme(cy, col + last_len);
+    } else {
+        for (i, line) in lines.into_iter().enumerate() {
+            insert_row(ec, cy + 1 + i, line);
+        }
+        ec.cx = col;
+        ec.dirty_rows.extend(ec.cy.saturating_sub(ec.rowoff)..ec.screenrows);
+    }
 }
 
 fn e_motion(ec: &mut EditorConfig){
     // Move forward 2 (make sure we dont go past eof)
-    ec.cx += 2;
-    if ec.cy == ec.numrows - 1 && ec.cx >= ec.rows[ec.cy].data.len() {return}
+    ec.cx = ec.buf.next_grapheme(ec.cy, ec.cx);
+    ec.cx = ec.buf.next_grapheme(ec.cy, ec.cx);
+    if ec.cy == ec.buf.numrows - 1 && ec.cx >= ec.buf.line_len(ec.cy) {return}
 
-    if ec.cx >= ec.rows[ec.cy].data.len() {
+    if ec.cx >= ec.buf.line_len(ec.cy) {
         ec.cy += 1;
         ec.cx = 0;
-        if ec.rows[ec.cy].data.is_empty() {return}
-        while ec.rows[ec.cy].data.chars().nth(ec.cx).unwrap() == ' ' {ec.cx += 1}
+        while ec.buf.char_at(ec.cy, ec.cx) == Some(' ') {ec.cx += 1}
         return
     }
 
     // Find a separator
-    while !SEPARATORS.contains(&ec.rows[ec.cy].data.chars().nth(ec.cx).unwrap()){
-        if ec.cy == ec.numrows && ec.cx >= ec.rows[ec.cy].data.len() {return}
-        if ec.cx == ec.rows[ec.cy].data.len() - 1 {return}
-        ec.cx += 1;
+    while !SEPARATORS.contains(&ec.buf.char_at(ec.cy, ec.cx).unwrap()){
+        if ec.cy == ec.buf.numrows && ec.cx >= ec.buf.line_len(ec.cy) {return}
+        if ec.buf.next_grapheme(ec.cy, ec.cx) == ec.buf.line_len(ec.cy) {return}
+        ec.cx = ec.buf.next_grapheme(ec.cy, ec.cx);
     }
 
     // go back to the last token
-    ec.cx -= 1;
+    ec.cx = ec.buf.prev_grapheme(ec.cy, ec.cx);
+}
+
+type Motion = fn(&mut EditorConfig);
+
+// Motions that can follow an operator, and how much of the text they cover
+fn motion_for(keys: &str) -> Option<(Motion, MotionKind)> {
+    Some(match keys {
+        "b" => (b_motion, MotionKind::Exclusive),
+        "e" => (e_motion, MotionKind::Inclusive),
+        "G" => (ug_motion, MotionKind::Linewise),
+        "gg" => (gg_motion, MotionKind::Linewise),
+        "gj" => (gj_motion, MotionKind::Exclusive),
+        "gk" => (gk_motion, MotionKind::Exclusive),
+        "h" => (h_motion, MotionKind::Exclusive),
+        "j" => (j_motion, MotionKind::Linewise),
+        "k" => (k_motion, MotionKind::Linewise),
+        "l" => (l_motion, MotionKind::Exclusive),
+        "w" => (w_motion, MotionKind::Exclusive),
+        "0" => (line_start, MotionKind::Exclusive),
+        "^" => (first_non_blank, MotionKind::Exclusive),
+        "$" => (line_end, MotionKind::Inclusive),
+        "{" => (empty_up, MotionKind::Exclusive),
+        "}" => (empty_down, MotionKind::Exclusive),
+        "]m" => (next_function, MotionKind::Exclusive),
+        "[m" => (prev_function, MotionKind::Exclusive),
+        "]M" => (next_function_end, MotionKind::Exclusive),
+        "[M" => (prev_function_end, MotionKind::Exclusive),
+        "n" => (n_motion, MotionKind::Exclusive),
+        "N" => (un_motion, MotionKind::Exclusive),
+        "*" => (star_motion, MotionKind::Exclusive),
+        "#" => (hash_motion, MotionKind::Exclusive),
+        _ => return None,
+    })
 }
 
 /*** Keyboard Event Handling ***/
-fn handle_normal(ec: &mut EditorConfig) -> io::Result<bool>  {
+fn handle_normal(ec: &mut EditorConfig, key: KeyEvent) -> io::Result<bool>  {
 let mut motion_done = false;
-    if event::poll(std::time::Duration::from_millis(1))?{
-        if let Event::Key(key) = event::read()? {
-            // mark current row dirty (if we leave this row we rand to make lineno dark!)
-            ec.dirty_rows.push(ec.cy - ec.rowoff);
-            if let KeyCode::Char(c) = key.code {
-                motion_done = true;
-                // Check for number
-                if matches!(key.code, KeyCode::Char(c) if c.is_ascii_digit()){
-                    if !ec.motion.is_empty() {
-                        ec.motion = String::new();
-                    }
-                    let num = c.to_digit(10).map(|n| n as u16).unwrap_or(0);
-                    ec.motion_count *= 10;
-                    ec.motion_count += num as usize;
-                    set_status_message(ec, ec.motion_count.to_string())?;
-                    return Ok(true);
-                }
-                ec.motion.push(c);
-                let motion = match ec.motion.as_str() {
-                    "dd" => dd_motion,
-                    "a" => a_motion,
-                    "A" => ua_motion,
-                    "b" => b_motion,
-                    "e" => e_motion,
-                    "G" => ug_motion,
-                    "gg" => gg_motion,
-                    "h" => h_motion,
-                    "i" => i_motion,
-                    "I" => ui_motion,
-                    "j" => j_motion,
-                    "k" => k_motion,
-                    "l" => l_motion,
-                    "o" => o_motion,
-                    "O" => uo_motion,
-                    "v" => v_motion,
-                    "w" => w_motion,
-                    "x" => x_motion,
-                    ":" => colon,
-                    "{" => empty_up,
-                    "}" => empty_down,
-                    _ => {
-                        if ec.motion.len() > 3 {ec.motion = String::default()};
-                        let _ = set_status_message(ec, ec.motion.clone());
-                        return Ok(false)
-                    },
-                };
-                if ec.motion_count == 0 {
-                    motion(ec)
+    // mark current row dirty (if we leave this row we rand to make lineno dark!)
+    ec.dirty_rows.push(ec.cy - ec.rowoff);
+    // the key after Ctrl-w is a window command
+    if ec.motion == "\x17" {
+        ec.motion = String::default();
+        let key = if key.code == KeyCode::Backspace {'h'} else if let KeyCode::Char(c) = key.code {c} else {return Ok(true)};
+        if let Err(msg) = window::command(ec, key, ec.motion_count.max(1)) {set_status_message(ec, msg)?}
+        ec.motion_count = 0;
+        return Ok(true);
+    }
+    if key.modifiers == KeyModifiers::CONTROL && key.code == KeyCode::Char('w') {
+        ec.motion = String::from("\x17");
+        return Ok(false);
+    }
+    if key.modifiers == KeyModifiers::CONTROL && key.code == KeyCode::Char('r') {
+        for _i in 0..ec.motion_count.max(1) {
+            match undo::redo(ec) {
+                Ok(true) => {}
+                Ok(false) => {
+                    set_status_message(ec, String::from("Already at newest change"))?;
+                    break;
                 }
-                for _i in 0..ec.motion_count {
-                    motion(ec);
+                Err(msg) => {
+                    set_status_message(ec, msg)?;
+                    break;
                 }
-                ec.motion_count = 0;
             }
         }
+        ec.motion_count = 0;
+        return Ok(true);
+    }
+    if key.modifiers == KeyModifiers::CONTROL && key.code == KeyCode::Char('v') {
+        visual::start(ec, Mode::VisualBlock);
+        return Ok(true);
+    }
+    // Ctrl-^ comes through as Ctrl-6 on most terminals
+    if key.modifiers == KeyModifiers::CONTROL && matches!(key.code, KeyCode::Char('^' | '6')) {
+        if let Err(msg) = buffer::alternate(ec) {set_status_message(ec, msg)?}
+        return Ok(true);
+    }
+    if key.code == KeyCode::Esc {
+        operator::cancel(ec);
+        set_status_message(ec, String::default())?;
+        return Ok(true);
+    }
+    if let KeyCode::Char(c) = key.code {
+        motion_done = true;
+        // "x picks the register for the next command
+        if ec.motion == "\"" {
+            ec.motion = String::new();
+            ec.register = if register::is_register(c) {Some(c)} else {None};
+            return Ok(true);
+        }
+        // Check for number (0 on its own is a motion)
+        if matches!(key.code, KeyCode::Char(c) if c.is_ascii_digit() && (c != '0' || ec.motion_count > 0)){
+            if !ec.motion.is_empty() {
+                ec.motion = String::new();
+            }
+            let num = c.to_digit(10).map(|n| n as u16).unwrap_or(0);
+            ec.motion_count *= 10;
+            ec.motion_count += num as usize;
+            set_status_message(ec, ec.motion_count.to_string())?;
+            return Ok(true);
+        }
+        ec.motion.push(c);
+        if ec.operator.is_some() {
+            if !operator::pending(ec) {return Ok(false)}
+            ec.motion_count = 0;
+            ec.register = None;
+            if ec.mode != Mode::Insert {ec.buf.undo.commit((ec.cx, ec.cy))}
+            ec.motion = String::default();
+            return Ok(true);
+        }
+        if operator::OPERATORS.contains(&ec.motion.as_str()) {
+            ec.operator = Some(ec.motion.clone());
+            ec.operator_count = ec.motion_count;
+            ec.motion_count = 0;
+            ec.motion = String::default();
+            return Ok(true);
+        }
+        let motion = match ec.motion.as_str() {
+            "a" => a_motion,
+            "A" => ua_motion,
+            "g-" => g_minus_motion,
+            "gt" => gt_motion,
+            "gT" => g_ut_motion,
+            "g+" => g_plus_motion,
+            "i" => i_motion,
+            "I" => ui_motion,
+            "o" => o_motion,
+            "O" => uo_motion,
+            "p" => p_motion,
+            "P" => up_motion,
+            "u" => u_motion,
+            "v" => v_motion,
+            "V" => uv_motion,
+            "x" => x_motion,
+            "Y" => uy_motion,
+            "zh" => zh_motion,
+            "zl" => zl_motion,
+            "zs" => zs_motion,
+            "ze" => ze_motion,
+            ":" => colon,
+            "/" => slash,
+            "?" => question,
+            keys if motion_for(keys).is_some() => motion_for(keys).unwrap().0,
+            _ => {
+                if ec.motion.len() > 3 {ec.motion = String::default()};
+                let _ = set_status_message(ec, ec.motion.clone());
+                return Ok(false)
+            },
+        };
+        // these take the count themselves instead of being repeated
+        let counted = ["x", "Y", "p", "P", "gt", "gT"].contains(&ec.motion.as_str());
+        if ec.motion_count == 0 || counted {
+            motion(ec)
+        } else {
+            for _i in 0..ec.motion_count {
+                motion(ec);
+            }
+        }
+        ec.motion_count = 0;
+        ec.register = None;
+        // normal mode edits are one undo step each, insert mode commits on Esc
+        if ec.mode != Mode::Insert {ec.buf.undo.commit((ec.cx, ec.cy))}
     }
     if motion_done {ec.motion = String::default()};
     Ok(motion_done)
@@ -731,27 +1408,26 @@ let mut motion_done = false;
 fn auto_indent(ec: &mut EditorConfig) {
     // split current line at cursor
     let cy = ec.cy;
-    let current_line = ec.rows[cy].data.clone();
+    let current_line = ec.buf.line(cy);
     let (split_left, split_right) = current_line.split_at(ec.cx);
     
-    // generate whitespace
+    // copy the indent of the current line
     let whitespace = leading_whitespace(current_line.clone());
-    let leading_spaces = " ".repeat(whitespace);
+    let indent = &current_line[..whitespace];
 
     // Simplified line splitting and insertion
-    ec.rows.remove(ec.cy);
-    ec.numrows -= 1;
-    insert_row(ec, ec.cy, split_left.to_string());
-    insert_row(ec, ec.cy + 1, format!("{}{}", leading_spaces, split_right));
+    edit_row(ec, cy, |data| *data = split_left.to_string());
+    insert_row(ec, ec.cy + 1, format!("{}{}", indent, split_right));
 
     // Calculate indentation for cursor positioning
-    let additional_indent = if !split_right.is_empty() && [']', '}', ')'].contains(&split_right.chars().next().unwrap()) {
+    let new_cx = if !split_right.is_empty() && [']', '}', ')'].contains(&split_right.chars().next().unwrap()) {
         // Handle specific closing characters with additional indentation
-        let extra_indent_str = " ".repeat(whitespace + TAB_LENGTH);
+        let width = utils::indent_width(&current_line, ec.options.tabstop) + ec.options.shift();
+        let extra_indent_str = utils::indent(width, &ec.options);
         insert_row(ec, ec.cy + 1, extra_indent_str.clone());
-        TAB_LENGTH // Adjust according to your indentation strategy
+        extra_indent_str.len()
     } else {
-        0
+        whitespace
     };
 
     // set current row to dirty bc we will set cy to next row
@@ -761,168 +1437,302 @@ fn auto_indent(ec: &mut EditorConfig) {
     ec.dirty_rows.extend((ec.cy - ec.rowoff + 2)..ec.screenrows);
 
     // set cursor
-    ec.cx = whitespace + additional_indent;
+    ec.cx = new_cx;
     ec.cy += 1;
 }
 
-fn handle_insert(ec: &mut EditorConfig) -> io::Result<bool>{ 
-    if event::poll(std::time::Duration::from_millis(1))?{
-        if let Event::Key(key) = event::read()? {
-            // let _ = set_status_message(ec, format!("Insert key read: {:?}", key));
-            if key.modifiers == KeyModifiers::CONTROL && key.code == KeyCode::Char('h') {
-            let cy: usize = ec.cy;
-            let len = ec.rows[cy].data.len();
-            if ec.cx <= len && ec.cx > 0{
-                // Remove char from data
-                ec.rows[cy].data.remove(ec.cx - 1);
-                ec.cx -= 1;
-            } else if ec.cx == 0 && ec.cy > 0 {
-                // delete the current line
-                let cur_str = ec.rows[cy].data.clone();
-                let new_cx = ec.rows[cy - 1].data.len();
-                ec.rows[cy - 1].data.push_str(&cur_str);
-                ec.rows.remove(cy);
-
-                //set all rows below as dirty because they need to shift up
-                ec.dirty_rows.extend((ec.cy - ec.rowoff)..ec.screenrows);
-                ec.cy -= 1;
-                ec.cx = new_cx;
-                ec.numrows -= 1;
-                }
-            } else if let KeyCode::Char(c) = key.code {
-                let cy: usize = ec.cy;
-                if ec.j_flag && c == 'k' {
-                    ec.rows[cy].data.remove(ec.cx - 1);
-                    ec.cx -= 1;
-                    ec.j_flag = false;
-                    stdout().execute(cursor::SetCursorStyle::SteadyBlock)?;
-                    ec.mode = Mode::Normal;
-                } else {
-                    ec.rows[cy].data.insert(ec.cx, c);
-                    ec.cx += 1;
-                    if c == '{' {
-                        ec.rows[cy].data.insert(ec.cx, '}');
-                    }else if c == '(' {
-                        ec.rows[cy].data.insert(ec.cx, ')');
-                    }else if c == '[' {
-                        ec.rows[cy].data.insert(ec.cx, ']');
-                    } else if ec.cx < ec.rows[cy].data.len() && ((c == '}' && ec.rows[cy].data.chars().nth(ec.cx).unwrap() == '}') ||
-                    (c == ')' && ec.rows[cy].data.chars().nth(ec.cx).unwrap() == ')') ||
-                    (c == ']' && ec.rows[cy].data.chars().nth(ec.cx).unwrap() == ']')) {
-                        ec.rows[cy].data.remove(ec.cx);
-                    } else if c == 'j' {
-                        ec.j_flag = true;
-                    }
-                }
-            } else if key.code == KeyCode::Left{
-                h_motion(ec);
-            } else if key.code == KeyCode::Right {
-                l_motion(ec);
-            } else if key.code == KeyCode::Down {
-                j_motion(ec);
-            } else if key.code == KeyCode::Up {
-                k_motion(ec);
-            } else if key.code == KeyCode::Tab {
-                let tab_str = " ".repeat(TAB_LENGTH);
-                let cy: usize = ec.cy;
-                ec.rows[cy].data.insert_str(ec.cx, &tab_str);
-                ec.cx += TAB_LENGTH;
-            } else if key.code == KeyCode::Esc {
-                if ec.cx > 0 {ec.cx -= 1;}
-                stdout().execute(cursor::SetCursorStyle::SteadyBlock)?;
-                ec.mode = Mode::Normal;
-            } else if key.code == KeyCode::Enter {
-                print!("\x1b[K");
-                auto_indent(ec);
-            } else if key.code == KeyCode::Backspace {
-                let cy: usize = ec.cy;
-                let len = ec.rows[cy].data.len();
-                if ec.cx <= len && ec.cx > 0{
-                    // Remove char from data
-                    ec.rows[cy].data.remove(ec.cx - 1);
-                    ec.cx -= 1;
-                } else if ec.cx == 0 && ec.cy > 0 {
-                    // delete the current line
-                    let cur_str = ec.rows[cy].data.clone();
-                    let new_cx = ec.rows[cy - 1].data.len();
-                    ec.rows[cy - 1].data.push_str(&cur_str);
-                    ec.rows.remove(cy);
-
-                    //set all rows below as dirty because they need to shift up
-                    ec.dirty_rows.extend((ec.cy - ec.rowoff)..ec.screenrows);
-                    ec.cy -= 1;
-                    ec.cx = new_cx;
-                    ec.numrows -= 1;
-                }
+fn handle_insert(ec: &mut EditorConfig, key: KeyEvent) -> io::Result<bool>{
+    // let _ = set_status_message(ec, format!("Insert key read: {:?}", key));
+    // jk only escapes when the k comes straight after the j
+    let j_typed = std::mem::take(&mut ec.j_flag);
+    if key.modifiers == KeyModifiers::CONTROL && key.code == KeyCode::Char('h') {
+    let cy: usize = ec.cy;
+    let len = ec.buf.line_len(cy);
+    if ec.cx <= len && ec.cx > 0{
+        // Remove char from data
+        let (cx, prev) = (ec.cx, ec.buf.prev_grapheme(cy, ec.cx));
+        edit_row(ec, cy, |data| {data.drain(prev..cx);});
+        ec.cx = prev;
+    } else if ec.cx == 0 && ec.cy > 0 {
+        // delete the current line
+        let new_cx = ec.buf.line_len(cy - 1);
+        let cur_str = delete_row(ec, cy);
+        edit_row(ec, cy - 1, |data| data.push_str(&cur_str));
+
+        //set all rows below as dirty because they need to shift up
+        ec.dirty_rows.extend((ec.cy - ec.rowoff)..ec.screenrows);
+        ec.cy -= 1;
+        ec.cx = new_cx;
+        }
+    } else if let KeyCode::Char(c) = key.code {
+        let cy: usize = ec.cy;
+        if j_typed && c == 'k' {
+            let cx = ec.cx;
+            edit_row(ec, cy, |data| {data.remove(cx - 1);});
+            ec.cx -= 1;
+            stdout().execute(cursor::SetCursorStyle::SteadyBlock)?;
+            ec.mode = Mode::Normal;
+            visual::finish_block_insert(ec);
+            ec.buf.undo.commit((ec.cx, ec.cy));
+        } else {
+            let cx = ec.cx;
+            edit_row(ec, cy, |data| data.insert(cx, c));
+            ec.cx += c.len_utf8();
+            let cx = ec.cx;
+            if c == '{' {
+                edit_row(ec, cy, |data| data.insert(cx, '}'));
+            }else if c == '(' {
+                edit_row(ec, cy, |data| data.insert(cx, ')'));
+            }else if c == '[' {
+                edit_row(ec, cy, |data| data.insert(cx, ']'));
+            } else if ec.cx < ec.buf.line_len(cy) && ((c == '}' && ec.buf.char_at(cy, ec.cx).unwrap() == '}') ||
+            (c == ')' && ec.buf.char_at(cy, ec.cx).unwrap() == ')') ||
+            (c == ']' && ec.buf.char_at(cy, ec.cx).unwrap() == ']')) {
+                edit_row(ec, cy, |data| {data.remove(cx);});
+            } else if c == 'j' {
+                ec.j_flag = true;
             }
-            ec.dirty = true;
-            return Ok(true)
+        }
+    } else if key.code == KeyCode::Left{
+        h_motion(ec);
+    } else if key.code == KeyCode::Right {
+        l_motion(ec);
+    } else if key.code == KeyCode::Down {
+        j_motion(ec);
+    } else if key.code == KeyCode::Up {
+        k_motion(ec);
+    } else if key.code == KeyCode::Tab {
+        let cy: usize = ec.cy;
+        let cx = ec.cx;
+        // with expandtab, spaces up to the next multiple of shiftwidth
+        let tab_str = if ec.options.expandtab {
+            let col = grapheme::col(&ec.buf.line(cy), cx, ec.options.tabstop);
+            " ".repeat(ec.options.shift() - col % ec.options.shift())
+        } else {
+            String::from("\t")
+        };
+        edit_row(ec, cy, |data| data.insert_str(cx, &tab_str));
+        ec.cx += tab_str.len();
+    } else if key.code == KeyCode::Esc {
+        ec.cx = ec.buf.prev_grapheme(ec.cy, ec.cx);
+        stdout().execute(cursor::SetCursorStyle::SteadyBlock)?;
+        ec.mode = Mode::Normal;
+        visual::finish_block_insert(ec);
+        // the whole insert session is a single undo step
+        ec.buf.undo.commit((ec.cx, ec.cy));
+    } else if key.code == KeyCode::Enter {
+        print!("\x1b[K");
+        auto_indent(ec);
+    } else if key.code == KeyCode::Backspace {
+        let cy: usize = ec.cy;
+        let len = ec.buf.line_len(cy);
+        if ec.cx <= len && ec.cx > 0{
+            // Remove char from data
+            let (cx, prev) = (ec.cx, ec.buf.prev_grapheme(cy, ec.cx));
+            edit_row(ec, cy, |data| {data.drain(prev..cx);});
+            ec.cx = prev;
+        } else if ec.cx == 0 && ec.cy > 0 {
+            // delete the current line
+            let new_cx = ec.buf.line_len(cy - 1);
+            let cur_str = delete_row(ec, cy);
+            edit_row(ec, cy - 1, |data| data.push_str(&cur_str));
+
+            //set all rows below as dirty because they need to shift up
+            ec.dirty_rows.extend((ec.cy - ec.rowoff)..ec.screenrows);
+            ec.cy -= 1;
+            ec.cx = new_cx;
         }
     }
-    Ok(false)
+    ec.buf.dirty = true;
+    Ok(true)
 }
 
-fn handle_visual(ec: &mut EditorConfig) -> io::Result<bool>{ 
-    if event::poll(std::time::Duration::from_millis(1))?{
-        if let Event::Key(key) = event::read()? {
-            if key.code == KeyCode::Esc {
-                stdout().execute(cursor::SetCursorStyle::SteadyBlock)?;
-                ec.mode = Mode::Normal;
+fn handle_visual(ec: &mut EditorConfig, key: KeyEvent) -> io::Result<bool>{
+    // the selection can change anywhere on screen
+    ec.dirty_rows.extend(0..ec.screenrows);
+    if key.code == KeyCode::Esc {
+        ec.motion = String::default();
+        ec.motion_count = 0;
+        visual::exit(ec);
+        return Ok(true)
+    }
+    if key.modifiers == KeyModifiers::CONTROL && key.code == KeyCode::Char('v') {
+        visual::switch(ec, Mode::VisualBlock);
+        return Ok(true)
+    }
+    let KeyCode::Char(c) = key.code else {return Ok(true)};
+    if ec.motion == "\"" {
+        ec.motion = String::new();
+        ec.register = if register::is_register(c) {Some(c)} else {None};
+        return Ok(true);
+    }
+    if c.is_ascii_digit() && (c != '0' || ec.motion_count > 0) {
+        ec.motion_count = ec.motion_count * 10 + c.to_digit(10).unwrap_or(0) as usize;
+        return Ok(true);
+    }
+    ec.motion.push(c);
+    let count = ec.motion_count.max(1);
+    match ec.motion.as_str() {
+        "v" => visual::switch(ec, Mode::Visual),
+        "V" => visual::switch(ec, Mode::VisualLine),
+        "o" => visual::swap(ec),
+        ":" => visual::command(ec),
+        op @ ("d" | "x" | "y" | "c" | "s" | ">" | "<" | "~" | "u" | "U" | "J" | "I" | "A") => {
+            let op = op.to_string();
+            visual::operate(ec, &op);
+            ec.register = None;
+            if ec.mode != Mode::Insert {ec.buf.undo.commit((ec.cx, ec.cy))}
+        }
+        keys => {
+            let keys = keys.to_string();
+            if let Some((motion, _)) = motion_for(&keys) {
+                for _i in 0..count {motion(ec)}
+            } else if !visual::select_object(ec, &keys, count) && ["g", "i", "a", "\"", "]", "["].contains(&keys.as_str()) {
+                return Ok(false)
             }
-            ec.dirty = true;
-            return Ok(true)
         }
     }
-    Ok(false)
+    ec.motion = String::default();
+    ec.motion_count = 0;
+    Ok(true)
 }
 
-fn handle_command(ec: &mut EditorConfig) -> io::Result<bool>{ 
-    if event::poll(std::time::Duration::from_millis(1))?{
-        if let Event::Key(key) = event::read()? {
-            if key.code == KeyCode::Esc {
-                ec.command = String::default();
-                ec.mode = Mode::Normal;
-            }
-            if let KeyCode::Char(c) = key.code {
-                ec.command.push(c);
-            }
-            if key.code == KeyCode::Backspace {
-                ec.command.pop();
+fn handle_command(ec: &mut EditorConfig, key: KeyEvent) -> io::Result<bool>{
+    if key.code == KeyCode::Esc {
+        ec.command = String::default();
+        ec.mode = Mode::Normal;
+    }
+    if let KeyCode::Char(c) = key.code {
+        ec.command.push(c);
+    }
+    if key.code == KeyCode::Backspace {
+        ec.command.pop();
+    }
+    if key.code == KeyCode::Enter {
+        let command = std::mem::take(&mut ec.command);
+        ec.mode = Mode::Normal;
+        if let Err(msg) = ex::run(ec, &command) {set_status_message(ec, msg)?}
+        ec.buf.undo.commit((ec.cx, ec.cy));
+    }
+    Ok(true)
+}
+
+fn handle_search(ec: &mut EditorConfig, key: KeyEvent) -> io::Result<bool>{
+    match key.code {
+        KeyCode::Esc => search::cancel(ec),
+        KeyCode::Enter => search::confirm(ec),
+        KeyCode::Backspace => {
+            if ec.command.pop().is_none() {
+                search::cancel(ec);
+            } else {
+                search::update(ec);
             }
-            if key.code == KeyCode::Enter {
-                match ec.command.as_str() {
-                    "w" => {
-                        editor_save(ec)?;
-                        set_status_message(ec, format!("{} {}L written", ec.filename, ec.numrows))?;
-                    }
-                    "q" => {
-                        if !(ec.dirty) {
-                            disable_raw_mode()?;
-                            stdout().execute(LeaveAlternateScreen)?;
-                            exit(0);
-                        } else {
-                            set_status_message(ec, String::from("FILE HAS NOT BEEN SAVED!"))?;
+        }
+        KeyCode::Char(c) => {
+            ec.command.push(c);
+            search::update(ec);
+        }
+        _ => {}
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+impl EditorConfig {
+    // An 80x24 editor holding text, with no terminal behind it
+    fn test(text: &str) -> EditorConfig {
+        let mut ec = EditorConfig::with_size(80, 24, events::Events::none());
+        window::arrange(&mut ec);
+        ec.buf.load(text);
+        if ec.buf.numrows == 0 {ec.buf.insert_line(0, "")}
+        ec
+    }
+
+    // Type keys as the main loop gets them. <Esc>, <CR>, <BS> and <C-x> stand for the keys that
+    // aren't chars
+    fn keys(&mut self, keys: &str) {
+        let mut rest = keys;
+        while let Some(c) = rest.chars().next() {
+            let name = rest.strip_prefix('<').and_then(|r| r.split_once('>')).filter(|(name, _)| name.len() > 1);
+            let key = match name {
+                Some((name, after)) => {
+                    rest = after;
+                    match name {
+                        "Esc" => KeyEvent::from(KeyCode::Esc),
+                        "CR" => KeyEvent::from(KeyCode::Enter),
+                        "BS" => KeyEvent::from(KeyCode::Backspace),
+                        _ => {
+                            let c = name.strip_prefix("C-").and_then(|c| c.chars().next()).expect("unknown key");
+                            KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL)
                         }
                     }
-                    "wq" => {
-                        editor_save(ec)?;
-                        disable_raw_mode()?;
-                        stdout().execute(LeaveAlternateScreen)?;
-                        exit(0);
-                    }
-                    "q!" => {
-                        disable_raw_mode()?;
-                        stdout().execute(LeaveAlternateScreen)?;
-                        exit(0);
-                    }
-                    _ => {
-                    }
                 }
-                ec.command = String::default();
-                ec.mode = Mode::Normal;
-            }
-            return Ok(true)
+                None => {
+                    rest = &rest[c.len_utf8()..];
+                    KeyEvent::from(KeyCode::Char(c))
+                }
+            };
+            handle_key(self, key).unwrap();
+        }
+    }
+
+    fn lines(&self) -> Vec<String> {
+        (0..self.buf.numrows).map(|y| self.buf.line(y)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_put_goes_in_column_wise() {
+        let mut ec = EditorConfig::test("abcd\nefgh\nijkl");
+        ec.keys("l<C-v>jly");
+        assert_eq!(ec.lines(), ["abcd", "efgh", "ijkl"]);
+        ec.keys("jp");
+        assert_eq!(ec.lines(), ["abcd", "efbcgh", "ijfgkl"]);
+        assert_eq!((ec.cx, ec.cy), (2, 1));
+        ec.keys("u0P");
+        assert_eq!(ec.lines(), ["abcd", "bcefgh", "fgijkl"]);
+    }
+
+    #[test]
+    fn block_put_pads_short_lines_and_adds_lines_at_the_end() {
+        let mut ec = EditorConfig::test("abc\nd\nxyz");
+        ec.keys("<C-v>jjd");
+        assert_eq!(ec.lines(), ["bc", "", "yz"]);
+        ec.keys("G$p");
+        assert_eq!(ec.lines(), ["bc", "", "yza", "  d", "  x"]);
+    }
+
+    #[test]
+    fn block_put_pads_pieces_to_the_block_width() {
+        let mut ec = EditorConfig::test("ab\nc\nxyz\nxyz");
+        ec.keys("<C-v>jly");
+        ec.keys("jj2P");
+        assert_eq!(ec.lines(), ["ab", "c", "ababxyz", "c c xyz"]);
+    }
+
+    #[test]
+    fn only_jk_times_out() {
+        let mut ec = EditorConfig::test("a\nb");
+        for prefix in ["g", "z", "[", "]", "d", "2y", "\"a", "gu"] {
+            ec.keys(prefix);
+            assert!(!ec.events.is_set(Timer::KeySequence), "{}", prefix);
+            ec.keys("<Esc>");
         }
+        ec.keys("ij");
+        assert!(ec.events.is_set(Timer::KeySequence));
+        ec.keys("xk");
+        assert!(!ec.events.is_set(Timer::KeySequence));
+        assert!(ec.mode == Mode::Insert);
+        ec.keys("jk");
+        assert!(ec.mode == Mode::Normal);
+        assert_eq!(ec.lines(), ["jxka", "b"]);
+        ec.keys("i");
+        ec.options.timeout = false;
+        ec.keys("j");
+        assert!(!ec.events.is_set(Timer::KeySequence));
     }
-    Ok(false)
 }
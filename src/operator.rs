@@ -0,0 +1,334 @@
+use std::io::stdout;
+use crossterm::{cursor, ExecutableCommand};
+use crate::{
+    delete_row, edit_row, insert_row, register::Register, textobject, utils::leading_whitespace, EditorConfig, Mode,
+    utils, SEPARATORS,
+};
+
+pub const OPERATORS: [&str; 8] = ["d", "c", "y", ">", "<", "gu", "gU", "="];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MotionKind {
+    Exclusive, // the character under the end position is not included (w, b, h, l, {, })
+    Inclusive, // it is (e, $)
+    Linewise,  // whole lines (j, k, gg, G)
+}
+
+// (cx, cy), the same order the undo history stores cursors in
+pub type Pos = (usize, usize);
+
+pub fn ordered(a: Pos, b: Pos) -> (Pos, Pos) {
+    if (a.1, a.0) <= (b.1, b.0) {(a, b)} else {(b, a)}
+}
+
+// Text from start up to (not including) end, lines joined with \n
+pub fn get_text(ec: &EditorConfig, start: Pos, end: Pos) -> String {
+    ec.buf.text(start, end)
+}
+
+pub fn delete_text(ec: &mut EditorConfig, start: Pos, end: Pos) {
+    if start.1 == end.1 {
+        edit_row(ec, start.1, |data| {data.drain(start.0..end.0);});
+        return;
+    }
+    let tail = ec.buf.line(end.1)[end.0..].to_string();
+    edit_row(ec, start.1, |data| {
+        data.truncate(start.0);
+        data.push_str(&tail);
+    });
+    for _i in start.1 + 1..=end.1 {
+        delete_row(ec, start.1 + 1);
+    }
+}
+
+// Operator on count lines starting at the cursor (dd, yy, >>, guu)
+pub fn operate_lines(ec: &mut EditorConfig, op: &str, count: usize) {
+    let end = (ec.cy + count.max(1) - 1).min(ec.buf.numrows - 1);
+    apply(ec, op, (ec.cx, ec.cy), (0, end), MotionKind::Linewise);
+}
+
+// Called for each key while an operator is pending, returns false while more keys are needed
+pub fn pending(ec: &mut EditorConfig) -> bool {
+    let op = ec.operator.clone().unwrap_or_default();
+    let keys = ec.motion.clone();
+    let count = ec.operator_count.max(1) * ec.motion_count.max(1);
+
+    if keys == op || (op.len() == 2 && keys == op[1..]) {
+        // doubled operator works on lines
+        operate_lines(ec, &op, count);
+    } else if let Some((motion, mut kind)) = crate::motion_for(&keys) {
+        let start = (ec.cx, ec.cy);
+        let on_word = ec.buf.char_at(ec.cy, ec.cx).is_some_and(|c| !SEPARATORS.contains(&c));
+        let mut last = start;
+        if op == "c" && keys == "w" && on_word {
+            // cw changes to the end of the word like ce
+            let word_char = |c: char| !SEPARATORS.contains(&c);
+            while ec.buf.char_at(ec.cy, ec.buf.next_grapheme(ec.cy, ec.cx)).is_some_and(word_char) {
+                ec.cx = ec.buf.next_grapheme(ec.cy, ec.cx);
+            }
+            for _i in 1..count {crate::e_motion(ec)}
+            kind = MotionKind::Inclusive;
+        } else {
+            for _i in 0..count {
+                last = (ec.cx, ec.cy);
+                motion(ec);
+            }
+        }
+        ec.cy = ec.cy.min(ec.buf.numrows - 1);
+        ec.cx = ec.buf.snap(ec.cy, ec.cx);
+        let mut end = (ec.cx, ec.cy);
+        // w stops at the end of the line instead of eating the indent of the next one
+        if keys == "w" && end.1 > last.1 {
+            end = (ec.buf.line_len(last.1), last.1);
+        }
+        // and when it stopped on the last character without reaching a new word, that goes too
+        let separator = |x: usize| ec.buf.char_at(end.1, x).is_none_or(|c| SEPARATORS.contains(&c));
+        let word_start = end != last && !separator(end.0) && (end.0 == 0 || separator(ec.buf.prev_grapheme(end.1, end.0)));
+        if keys == "w" && ec.buf.next_grapheme(end.1, end.0) == ec.buf.line_len(end.1) && !word_start {
+            end.0 = ec.buf.line_len(end.1);
+        }
+        // an exclusive motion from the indent to the first column of a later line takes whole lines
+        let (from, to) = ordered(start, end);
+        if kind == MotionKind::Exclusive && to.0 == 0 && to.1 > from.1 && from.0 <= leading_whitespace(ec.buf.line(from.1)) {
+            apply(ec, &op, from, (0, to.1 - 1), MotionKind::Linewise);
+        } else {
+            apply(ec, &op, start, end, kind);
+        }
+    } else if let Some((start, end, kind)) = textobject::select(ec, &keys, count) {
+        apply(ec, &op, start, end, kind);
+    } else if ["g", "i", "a", "]", "["].contains(&keys.as_str()) {
+        return false;
+    }
+
+    ec.operator = None;
+    ec.operator_count = 0;
+    true
+}
+
+pub fn apply(ec: &mut EditorConfig, op: &str, start: Pos, end: Pos, kind: MotionKind) {
+    let (start, mut end) = ordered(start, end);
+    let linewise = kind == MotionKind::Linewise;
+    if kind == MotionKind::Inclusive {
+        end.0 = ec.buf.next_grapheme(end.1, end.0);
+    } else if kind == MotionKind::Exclusive && end.0 == 0 && end.1 > start.1 {
+        // ending in the first column of a later line stops at the end of the line before it
+        end = (ec.buf.line_len(end.1 - 1), end.1 - 1);
+    }
+    // edits are recorded against the start so undo puts the cursor back there
+    (ec.cx, ec.cy) = start;
+    let (first, last) = (start.1, end.1);
+
+    let register = if linewise {
+        Register::linewise((first..=last).map(|y| ec.buf.line(y)).collect())
+    } else {
+        Register::charwise(&get_text(ec, start, end))
+    };
+
+    match op {
+        "y" => {
+            ec.registers.yank(ec.register, register);
+            if linewise {
+                ec.cx = ec.cx.min(ec.buf.line_len(first));
+                if last > first {let _ = crate::set_status_message(ec, format!("{} lines yanked", last - first + 1));}
+            }
+        }
+        "d" | "c" => {
+            ec.registers.delete(ec.register, register);
+            if linewise && op == "c" {
+                // keep one line with the indent of the first to type into
+                let line = ec.buf.line(first);
+                let indent = line[..leading_whitespace(line.clone())].to_string();
+                for _i in first + 1..=last {delete_row(ec, first + 1);}
+                edit_row(ec, first, |data| *data = indent);
+                ec.cx = ec.buf.line_len(first);
+            } else if linewise {
+                for _i in first..=last {delete_row(ec, first);}
+                if ec.buf.numrows == 0 {insert_row(ec, 0, String::new())}
+                ec.cy = first.min(ec.buf.numrows - 1);
+                ec.cx = leading_whitespace(ec.buf.line(ec.cy));
+            } else {
+                delete_text(ec, start, end);
+            }
+            if op == "c" {
+                let _ = stdout().execute(cursor::SetCursorStyle::SteadyBar);
+                ec.mode = Mode::Insert;
+            }
+        }
+        ">" | "<" => {
+            for y in first..=last {
+                let line = ec.buf.line(y);
+                if line.is_empty() {continue}
+                // the indent is rebuilt so tabs and spaces follow the options
+                let width = utils::indent_width(&line, ec.options.tabstop);
+                let width = if op == ">" {width + ec.options.shift()} else {width.saturating_sub(ec.options.shift())};
+                let indent = utils::indent(width, &ec.options);
+                let old = leading_whitespace(line);
+                edit_row(ec, y, |data| data.replace_range(..old, &indent));
+            }
+            ec.cx = leading_whitespace(ec.buf.line(first));
+        }
+        "gu" | "gU" | "~" => {
+            for y in first..=last {
+                let from = if y == first && !linewise {start.0} else {0};
+                let to = if y == last && !linewise {end.0} else {ec.buf.line_len(y)};
+                edit_row(ec, y, |data| {
+                    let text = change_case(op, &data[from..to]);
+                    data.replace_range(from..to, &text);
+                });
+            }
+        }
+        "J" => join_lines(ec, first, last),
+        "=" => {
+            reindent(ec, first, last);
+            ec.cx = leading_whitespace(ec.buf.line(first));
+        }
+        _ => {}
+    }
+    ec.dirty_rows.extend(0..ec.screenrows);
+}
+
+pub fn change_case(op: &str, text: &str) -> String {
+    match op {
+        "gu" => text.to_lowercase(),
+        "gU" => text.to_uppercase(),
+        _ => text.chars().map(|c| {
+            if c.is_uppercase() {c.to_lowercase().next().unwrap_or(c)} else {c.to_uppercase().next().unwrap_or(c)}
+        }).collect(),
+    }
+}
+
+// Join lines into the first with a single space, a single line is joined with the one below
+fn join_lines(ec: &mut EditorConfig, first: usize, last: usize) {
+    let last = if last == first {first + 1} else {last};
+    if last >= ec.buf.numrows {return}
+    for _i in first..last {
+        let next = delete_row(ec, first + 1);
+        let next = next.trim_start();
+        edit_row(ec, first, |data| {
+            data.truncate(data.trim_end().len());
+            if !data.is_empty() && !next.is_empty() && !next.starts_with(')') {data.push(' ')}
+            data.push_str(next);
+        });
+    }
+}
+
+// Indent lines by bracket depth, following the same rules as auto_indent
+fn reindent(ec: &mut EditorConfig, first: usize, last: usize) {
+    let opens = |line: &str| line.trim_end().ends_with(['{', '(', '[']);
+    let closes = |line: &str| line.trim_start().starts_with(['}', ')', ']']);
+
+    let mut indent = match (0..first).rev().map(|y| ec.buf.line(y)).find(|row| !row.trim().is_empty()) {
+        Some(row) => utils::indent_width(&row, ec.options.tabstop) + if opens(&row) {ec.options.shift()} else {0},
+        None => 0,
+    };
+    for y in first..=last {
+        let line = ec.buf.line(y).trim().to_string();
+        if line.is_empty() {
+            edit_row(ec, y, |data| data.clear());
+            continue;
+        }
+        if closes(&line) {indent = indent.saturating_sub(ec.options.shift())}
+        let new = format!("{}{}", utils::indent(indent, &ec.options), line);
+        edit_row(ec, y, |data| *data = new);
+        if opens(&line) {indent += ec.options.shift()}
+    }
+}
+
+// Esc while an operator is pending
+pub fn cancel(ec: &mut EditorConfig) {
+    ec.operator = None;
+    ec.operator_count = 0;
+    ec.motion_count = 0;
+    ec.register = None;
+    ec.motion = String::default();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{EditorConfig, Mode};
+
+    fn typed(text: &str, keys: &str) -> Vec<String> {
+        let mut ec = EditorConfig::test(text);
+        ec.keys(keys);
+        ec.lines()
+    }
+
+    #[test]
+    fn delete_with_motions() {
+        assert_eq!(typed("one two three", "dw"), ["two three"]);
+        assert_eq!(typed("one two three", "wdb"), ["two three"]);
+        assert_eq!(typed("one two three", "de"), [" two three"]);
+        assert_eq!(typed("one two three", "wd$"), ["one "]);
+        assert_eq!(typed("one two three", "wd0"), ["two three"]);
+        assert_eq!(typed("a\nb\nc\nd\ne", "jd2j"), ["a", "e"]);
+        assert_eq!(typed("a\nb\nc\nd\ne", "Gdk"), ["a", "b", "c"]);
+        assert_eq!(typed("a\nb\nc", "jdG"), ["a"]);
+        assert_eq!(typed("a\nb\nc", "jdgg"), ["c"]);
+        assert_eq!(typed("a\nb\n\nc", "d}"), ["", "c"]);
+    }
+
+    #[test]
+    fn counts_on_both_sides_multiply() {
+        let words = "a b c d e f g h";
+        assert_eq!(typed(words, "2d3w"), ["g h"]);
+        assert_eq!(typed(words, "d3w"), ["d e f g h"]);
+        assert_eq!(typed(words, "3dw"), ["d e f g h"]);
+        assert_eq!(typed("1\n2\n3\n4\n5\n6\n7", "2d2j"), ["6", "7"]);
+        assert_eq!(typed("1\n2\n3\n4", "3dd"), ["4"]);
+    }
+
+    #[test]
+    fn exclusive_motions_ending_a_line_stay_on_it() {
+        // dw on the last word doesn't join the next line
+        assert_eq!(typed("one two\n  three", "wdw"), ["one ", "  three"]);
+        assert_eq!(typed("one two", "wdw"), ["one "]);
+        assert_eq!(typed("one two x", "wdw"), ["one x"]);
+        assert_eq!(typed("x\ny", "dw"), ["", "y"]);
+        // ending in the first column of a later line stops at the end of the one before
+        assert_eq!(typed("one\ntwo\n\nthree", "ld}"), ["o", "", "three"]);
+    }
+
+    #[test]
+    fn exclusive_motions_from_the_indent_take_whole_lines() {
+        assert_eq!(typed("a\n\nb\nc", "Gd{"), ["a", "c"]);
+        assert_eq!(typed("  a\n  b\n\nc", "d}"), ["", "c"]);
+        assert_eq!(typed("ab\nb\n\nc", "ld}"), ["a", "", "c"]);
+    }
+
+    #[test]
+    fn change() {
+        let mut ec = EditorConfig::test("one two three");
+        ec.keys("cwnew<Esc>");
+        assert_eq!(ec.lines(), ["new two three"]);
+        ec.keys("wc$end<Esc>");
+        assert_eq!(ec.lines(), ["new end"]);
+        ec.keys("0c2wold<Esc>");
+        assert_eq!(ec.lines(), ["old"]);
+        let mut ec = EditorConfig::test("  one\n  two\nthree");
+        ec.keys("cjx");
+        assert!(ec.mode == Mode::Insert);
+        ec.keys("<Esc>");
+        assert_eq!(ec.lines(), ["  x", "three"]);
+    }
+
+    #[test]
+    fn yank_and_put_back() {
+        let mut ec = EditorConfig::test("a\nb\n\nc");
+        ec.keys("y}GP");
+        assert_eq!(ec.lines(), ["a", "b", "", "a", "b", "c"]);
+        let mut ec = EditorConfig::test("one two");
+        ec.keys("yeP");
+        assert_eq!(ec.lines(), ["oneone two"]);
+        assert_eq!((ec.cx, ec.cy), (2, 0));
+    }
+
+    #[test]
+    fn shift_case_and_indent() {
+        assert_eq!(typed("a\nb\nc", ">j"), ["    a", "    b", "c"]);
+        assert_eq!(typed("        a\n    b", "<j"), ["    a", "b"]);
+        assert_eq!(typed("one two", "gUw"), ["ONE two"]);
+        assert_eq!(typed("ONE TWO", "wgu$"), ["ONE two"]);
+        assert_eq!(typed("ONE TWO", "guu"), ["one two"]);
+        assert_eq!(typed("f() {\nx;\n}", "=G"), ["f() {", "    x;", "}"]);
+    }
+}
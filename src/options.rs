@@ -0,0 +1,140 @@
+use crate::EditorConfig;
+
+// Settings changed with :set
+pub struct Options {
+    pub tabstop: usize,
+    pub shiftwidth: usize, // 0 follows tabstop
+    pub expandtab: bool,
+    pub sidescroll: usize, // columns to scroll at a time, 0 centers the cursor
+    pub sidescrolloff: usize, // columns kept visible left and right of the cursor
+    pub wrap: bool,
+    pub linebreak: bool, // wrap after a separator instead of at the last column
+    pub showbreak: String, // drawn at the start of wrapped screen lines
+    pub timeout: bool, // give up on a partly typed jk after timeoutlen
+    pub timeoutlen: usize, // milliseconds
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { tabstop: 8, shiftwidth: 4, expandtab: true, sidescroll: 1, sidescrolloff: 0,
+            wrap: false, linebreak: false, showbreak: String::new(),
+            timeout: true, timeoutlen: 1000 }
+    }
+}
+
+impl Options {
+    // Columns one level of indent takes
+    pub fn shift(&self) -> usize {
+        if self.shiftwidth == 0 {self.tabstop} else {self.shiftwidth}
+    }
+}
+
+enum Value<'a> {
+    Number(&'a mut usize),
+    Bool(&'a mut bool),
+    Text(&'a mut String),
+}
+
+// The full name and value of an option, by either of its names
+fn lookup<'a>(ec: &'a mut EditorConfig, name: &str) -> Option<(&'static str, Value<'a>)> {
+    let options = &mut ec.options;
+    Some(match name {
+        "tabstop" | "ts" => ("tabstop", Value::Number(&mut options.tabstop)),
+        "shiftwidth" | "sw" => ("shiftwidth", Value::Number(&mut options.shiftwidth)),
+        "expandtab" | "et" => ("expandtab", Value::Bool(&mut options.expandtab)),
+        "sidescroll" | "ss" => ("sidescroll", Value::Number(&mut options.sidescroll)),
+        "sidescrolloff" | "siso" => ("sidescrolloff", Value::Number(&mut options.sidescrolloff)),
+        "wrap" => ("wrap", Value::Bool(&mut options.wrap)),
+        "linebreak" | "lbr" => ("linebreak", Value::Bool(&mut options.linebreak)),
+        "showbreak" | "sbr" => ("showbreak", Value::Text(&mut options.showbreak)),
+        "timeout" | "to" => ("timeout", Value::Bool(&mut options.timeout)),
+        "timeoutlen" | "tm" => ("timeoutlen", Value::Number(&mut options.timeoutlen)),
+        // belongs to the buffer rather than the editor
+        "filetype" | "ft" => ("filetype", Value::Text(&mut ec.buf.filetype)),
+        _ => return None,
+    })
+}
+
+// What :set with no arguments shows
+const ALL: &[&str] = &["tabstop", "shiftwidth", "expandtab", "sidescroll", "sidescrolloff", "wrap", "linebreak", "showbreak", "timeout", "timeoutlen", "filetype"];
+
+fn show(name: &str, value: &Value) -> String {
+    match value {
+        Value::Number(n) => format!("{}={}", name, n),
+        Value::Bool(b) => format!("{}{}", if **b {""} else {"no"}, name),
+        Value::Text(t) => format!("{}={}", name, t),
+    }
+}
+
+// One argument of :set: name, noname, invname, name!, name?, name=value
+fn set_one(ec: &mut EditorConfig, arg: &str) -> Result<Option<String>, String> {
+    let unknown = || format!("E518: Unknown option: {}", arg);
+    if let Some((name, value)) = arg.split_once('=') {
+        let (name, target) = lookup(ec, name).ok_or_else(unknown)?;
+        let target = match target {
+            Value::Number(target) => target,
+            Value::Text(target) => {*target = value.replace("\\ ", " "); return Ok(None)}
+            Value::Bool(_) => return Err(format!("E474: Invalid argument: {}", arg)),
+        };
+        let n: usize = value.parse().map_err(|_| format!("E521: Number required after =: {}", arg))?;
+        if n == 0 && name == "tabstop" {return Err(format!("E487: Argument must be positive: {}", arg))}
+        *target = n;
+        return Ok(None);
+    }
+    if let Some(name) = arg.strip_suffix('?') {
+        let (name, value) = lookup(ec, name).ok_or_else(unknown)?;
+        return Ok(Some(show(name, &value)));
+    }
+    let (name, change): (&str, fn(bool) -> bool) = if let Some(name) = arg.strip_suffix('!') {
+        (name, |b| !b)
+    } else if let Some(name) = arg.strip_prefix("inv") {
+        (name, |b| !b)
+    } else if let Some(name) = arg.strip_prefix("no").filter(|name| lookup(ec, name).is_some()) {
+        (name, |_| false)
+    } else {
+        (arg, |_| true)
+    };
+    let bare = name == arg;
+    match lookup(ec, name).ok_or_else(unknown)? {
+        (_, Value::Bool(b)) => *b = change(*b),
+        // a number option on its own shows its value
+        (name, value) if bare => return Ok(Some(show(name, &value))),
+        _ => return Err(format!("E474: Invalid argument: {}", arg)),
+    }
+    Ok(None)
+}
+
+// Arguments split at whitespace, unless it is escaped with a backslash
+fn split_args(args: &str) -> Vec<&str> {
+    let mut split = vec![];
+    let mut start = None;
+    let mut escaped = false;
+    for (i, c) in args.char_indices() {
+        if c.is_whitespace() && !escaped {
+            if let Some(s) = start.take() {split.push(&args[s..i])}
+        } else if start.is_none() {
+            start = Some(i);
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    if let Some(s) = start {split.push(&args[s..])}
+    split
+}
+
+// :set, values asked for are shown on the message line
+pub fn set(ec: &mut EditorConfig, args: &str) -> Result<(), String> {
+    let mut shown = vec![];
+    if args.trim().is_empty() {
+        for name in ALL {
+            let (name, value) = lookup(ec, name).unwrap();
+            shown.push(show(name, &value));
+        }
+    }
+    for arg in split_args(args) {
+        if let Some(value) = set_one(ec, arg)? {shown.push(value)}
+    }
+    if !shown.is_empty() {let _ = crate::set_status_message(ec, shown.join("  "));}
+    // tabs may be drawn wider or narrower now, or the view may have to scroll
+    ec.dirty_rows.extend(0..ec.screenrows);
+    Ok(())
+}
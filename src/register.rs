@@ -0,0 +1,189 @@
+#[derive(Clone, Default)]
+pub struct Register {
+    pub text: Vec<String>,
+    pub linewise: bool,
+    pub blockwise: bool,
+}
+
+impl Register {
+    pub fn charwise(text: &str) -> Register {
+        Register { text: text.split('\n').map(String::from).collect(), ..Default::default() }
+    }
+
+    pub fn linewise(text: Vec<String>) -> Register {
+        Register { text, linewise: true, ..Default::default() }
+    }
+
+    // One piece of text per line, from a visual block
+    pub fn blockwise(text: Vec<String>) -> Register {
+        Register { text, blockwise: true, ..Default::default() }
+    }
+
+    fn append(&mut self, other: Register) {
+        if self.text.is_empty() {
+            *self = other;
+        } else if self.linewise || other.linewise || self.blockwise || other.blockwise {
+            // appending anything linewise makes the whole register linewise
+            self.text.extend(other.text);
+            self.linewise = true;
+            self.blockwise = false;
+        } else {
+            let mut other = other.text.into_iter();
+            if let (Some(last), Some(first)) = (self.text.last_mut(), other.next()) {
+                last.push_str(&first);
+            }
+            self.text.extend(other);
+        }
+    }
+}
+
+// The vim register model:
+//   "      unnamed, whatever was last yanked or deleted
+//   0      last yank
+//   1-9    delete ring, shifted on every multi-line delete
+//   -      last small (within one line) delete
+//   a-z    named, A-Z appends to the lowercase one
+//   _      black hole, never stores anything
+#[derive(Default)]
+pub struct Registers {
+    unnamed: Register,
+    small_delete: Register,
+    numbered: [Register; 10],
+    named: [Register; 26],
+}
+
+pub fn is_register(name: char) -> bool {
+    name.is_ascii_alphanumeric() || ['"', '-', '_'].contains(&name)
+}
+
+impl Registers {
+    pub fn get(&self, name: Option<char>) -> Option<&Register> {
+        let register = match name.unwrap_or('"') {
+            '"' => &self.unnamed,
+            '-' => &self.small_delete,
+            c @ '0'..='9' => &self.numbered[c as usize - '0' as usize],
+            c if c.is_ascii_alphabetic() => &self.named[c.to_ascii_lowercase() as usize - 'a' as usize],
+            _ => return None,
+        };
+        if register.text.is_empty() {None} else {Some(register)}
+    }
+
+    // Store into a register picked with "x, returns false if none was picked
+    fn store_named(&mut self, name: Option<char>, register: &Register) -> bool {
+        match name {
+            Some(c @ 'a'..='z') => self.named[c as usize - 'a' as usize] = register.clone(),
+            Some(c @ 'A'..='Z') => {
+                let named = &mut self.named[c as usize - 'A' as usize];
+                named.append(register.clone());
+                self.unnamed = named.clone();
+                return true;
+            }
+            Some(c @ '0'..='9') => self.numbered[c as usize - '0' as usize] = register.clone(),
+            Some('-') => self.small_delete = register.clone(),
+            _ => return false,
+        }
+        self.unnamed = register.clone();
+        true
+    }
+
+    pub fn yank(&mut self, name: Option<char>, register: Register) {
+        if name == Some('_') || self.store_named(name, &register) {return}
+        self.numbered[0] = register.clone();
+        self.unnamed = register;
+    }
+
+    pub fn delete(&mut self, name: Option<char>, register: Register) {
+        if name == Some('_') || self.store_named(name, &register) {return}
+        if register.linewise || register.text.len() > 1 {
+            self.numbered[1..].rotate_right(1);
+            self.numbered[1] = register.clone();
+        } else {
+            self.small_delete = register.clone();
+        }
+        self.unnamed = register;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EditorConfig;
+
+    fn text(registers: &Registers, name: char) -> Option<Vec<String>> {
+        registers.get(Some(name)).map(|r| r.text.clone())
+    }
+
+    #[test]
+    fn yanks_go_to_zero_and_unnamed() {
+        let mut registers = Registers::default();
+        registers.yank(None, Register::charwise("one"));
+        assert_eq!(text(&registers, '0'), Some(vec![String::from("one")]));
+        assert_eq!(text(&registers, '"'), Some(vec![String::from("one")]));
+        assert_eq!(text(&registers, '1'), None);
+    }
+
+    #[test]
+    fn deletes_shift_through_the_numbered_registers() {
+        let mut registers = Registers::default();
+        for i in 1..=10 {registers.delete(None, Register::linewise(vec![i.to_string()]))}
+        assert_eq!(text(&registers, '1'), Some(vec![String::from("10")]));
+        assert_eq!(text(&registers, '9'), Some(vec![String::from("2")]));
+        // a multi-line charwise delete shifts too, a small one goes to - instead
+        registers.delete(None, Register::charwise("a\nb"));
+        assert_eq!(text(&registers, '1'), Some(vec![String::from("a"), String::from("b")]));
+        registers.delete(None, Register::charwise("word"));
+        assert_eq!(text(&registers, '-'), Some(vec![String::from("word")]));
+        assert_eq!(text(&registers, '1'), Some(vec![String::from("a"), String::from("b")]));
+        assert_eq!(text(&registers, '"'), Some(vec![String::from("word")]));
+    }
+
+    #[test]
+    fn named_registers_and_appending() {
+        let mut registers = Registers::default();
+        registers.yank(Some('a'), Register::charwise("one"));
+        registers.yank(Some('A'), Register::charwise("two"));
+        assert_eq!(text(&registers, 'a'), Some(vec![String::from("onetwo")]));
+        assert_eq!(text(&registers, '"'), Some(vec![String::from("onetwo")]));
+        // a named yank leaves 0 alone
+        assert_eq!(text(&registers, '0'), None);
+        // appending lines makes the register linewise
+        registers.delete(Some('A'), Register::linewise(vec![String::from("three")]));
+        let a = registers.get(Some('a')).unwrap();
+        assert!(a.linewise);
+        assert_eq!(a.text, ["onetwo", "three"]);
+        // appending to an empty register just stores
+        registers.yank(Some('B'), Register::charwise("b"));
+        assert_eq!(text(&registers, 'b'), Some(vec![String::from("b")]));
+    }
+
+    #[test]
+    fn black_hole_keeps_nothing() {
+        let mut registers = Registers::default();
+        registers.yank(None, Register::charwise("kept"));
+        registers.delete(Some('_'), Register::linewise(vec![String::from("gone")]));
+        assert_eq!(text(&registers, '"'), Some(vec![String::from("kept")]));
+        assert_eq!(text(&registers, '1'), None);
+        assert_eq!(text(&registers, '_'), None);
+        assert!(is_register('_') && is_register('a') && is_register('7') && !is_register('!'));
+    }
+
+    #[test]
+    fn yank_and_put() {
+        let mut ec = EditorConfig::test("one two\nthree");
+        ec.keys("yyjp");
+        assert_eq!(ec.lines(), ["one two", "three", "one two"]);
+        ec.keys("ggyw$p");
+        assert_eq!(ec.lines(), ["one twoone ", "three", "one two"]);
+        ec.keys("\"add\"ap");
+        assert_eq!(ec.lines(), ["three", "one twoone ", "one two"]);
+        ec.keys("ggx\"-P");
+        assert_eq!(ec.lines(), ["three", "one twoone ", "one two"]);
+        // a delete into a named register leaves 1 alone
+        ec.keys("\"1P");
+        assert_eq!(ec.status_msg, "E353: Nothing in register 1");
+        ec.keys("ggdd\"_dd\"1P");
+        assert_eq!(ec.lines(), ["three", "one two"]);
+        ec.keys("P");
+        assert_eq!(ec.lines(), ["three", "three", "one two"]);
+    }
+}
@@ -0,0 +1,340 @@
+use regex::{Regex, RegexBuilder};
+use crate::{operator::Pos, set_status_message, EditorConfig, Mode, SEPARATORS};
+
+#[derive(Default)]
+pub struct Search {
+    pattern: String,
+    forward: bool,
+    // compiled pattern that is highlighted, the one being typed while the prompt is open
+    regex: Option<Regex>,
+    highlight: bool,
+    origin: Pos,
+}
+
+impl Search {
+    pub fn forward(&self) -> bool {
+        self.forward
+    }
+}
+
+// Vim's magic patterns: \( \) \| \+ \= \{n,m} are special and ( ) | + ? { } match themselves,
+// \< and \> are word boundaries and \c anywhere ignores case
+pub fn compile(pattern: &str) -> Option<Regex> {
+    if pattern.is_empty() {return None}
+    let (pattern, ignore_case) = translate(pattern)?;
+    RegexBuilder::new(&pattern).case_insensitive(ignore_case).build().ok()
+}
+
+// The same pattern in the regex crate's syntax, None when it is cut short
+fn translate(pattern: &str) -> Option<(String, bool)> {
+    let chars = pattern.chars().collect::<Vec<_>>();
+    let (mut out, mut ignore_case) = (String::new(), false);
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        if c == '[' {
+            match collection(&chars[i - 1..]) {
+                Some((class, len)) => {
+                    out.push_str(&class);
+                    i += len - 1;
+                }
+                None => out.push_str("\\["),
+            }
+            continue
+        }
+        if c != '\\' {
+            if "()|+?{}".contains(c) {out.push('\\')}
+            out.push(c);
+            continue
+        }
+        let Some(&c) = chars.get(i) else {
+            out.push_str("\\\\");
+            break
+        };
+        i += 1;
+        match c {
+            '(' | ')' | '|' | '+' => out.push(c),
+            '%' if chars.get(i) == Some(&'(') => {
+                out.push_str("(?:");
+                i += 1;
+            }
+            '=' | '?' => out.push('?'),
+            '{' => {
+                // \{n,m} or \{n,m\}, a - in front matches as few as it can
+                let close = (i..chars.len()).find(|&j| chars[j] == '}')?;
+                let body = chars[i..close].iter().collect::<String>();
+                let body = body.strip_suffix('\\').unwrap_or(&body);
+                let (lazy, body) = match body.strip_prefix('-') {
+                    Some(rest) => (true, rest),
+                    None => (false, body),
+                };
+                let body = if body.starts_with(',') {format!("0{}", body)} else {body.to_string()};
+                out.push_str(&if body.is_empty() {String::from("*")} else {format!("{{{}}}", body)});
+                if lazy {out.push('?')}
+                i = close + 1;
+            }
+            '<' | '>' => out.push_str("\\b"),
+            'c' => ignore_case = true,
+            'C' => {}
+            'a' => out.push_str("[A-Za-z]"),
+            'l' => out.push_str("[a-z]"),
+            'u' => out.push_str("[A-Z]"),
+            'h' => out.push_str("[A-Za-z_]"),
+            'x' => out.push_str("[0-9A-Fa-f]"),
+            'e' => out.push_str("\\x1b"),
+            c if c.is_ascii_punctuation() => out.push_str(&regex::escape(&c.to_string())),
+            // \s \d \w \t \n and the rest mean the same to both
+            c => {
+                out.push('\\');
+                out.push(c);
+            }
+        }
+    }
+    Some((out, ignore_case))
+}
+
+// A [] collection starting at chars[0] and how many chars it takes, None when nothing closes it
+fn collection(chars: &[char]) -> Option<(String, usize)> {
+    let mut out = String::from("[");
+    let mut i = 1;
+    if chars.get(i) == Some(&'^') {
+        out.push('^');
+        i += 1;
+    }
+    // a ] straight after the [ is one of the characters
+    if chars.get(i) == Some(&']') {
+        out.push_str("\\]");
+        i += 1;
+    }
+    while let Some(&c) = chars.get(i) {
+        match c {
+            ']' => return Some((out + "]", i + 1)),
+            '[' if chars.get(i + 1) == Some(&':') => {
+                // [:alpha:] and the other classes read the same
+                let close = (i + 2..chars.len() - 1).find(|&j| chars[j] == ':' && chars[j + 1] == ']')?;
+                out.extend(&chars[i..close + 2]);
+                i = close + 1;
+            }
+            '\\' if i + 1 < chars.len() => {
+                out.extend(&chars[i..i + 2]);
+                i += 1;
+            }
+            // the regex crate would nest these or read them as set operations
+            '[' | '&' | '~' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+        i += 1;
+    }
+    None
+}
+
+// Text that matches itself in a magic pattern
+pub fn escape(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        if "\\.*[]^$~/".contains(c) {out.push('\\')}
+        out.push(c);
+    }
+    out
+}
+
+// Next match strictly after (or before, going backward) from, wrapping around the file
+pub fn find(ec: &EditorConfig, regex: &Regex, from: Pos, forward: bool) -> Option<(Pos, bool)> {
+    let (cx, cy) = from;
+    let starts = |y: usize| regex.find_iter(&ec.buf.line(y)).map(|m| m.start()).collect::<Vec<_>>();
+
+    if forward {
+        if let Some(x) = starts(cy).into_iter().find(|&x| x > cx) {return Some(((x, cy), false))}
+        for y in (cy + 1..ec.buf.numrows).chain(0..=cy) {
+            if let Some(&x) = starts(y).first() {
+                if y == cy && x > cx {continue}
+                return Some(((x, y), y <= cy));
+            }
+        }
+    } else {
+        if let Some(x) = starts(cy).into_iter().rev().find(|&x| x < cx) {return Some(((x, cy), false))}
+        for y in (0..cy).rev().chain((cy..ec.buf.numrows).rev()) {
+            if let Some(&x) = starts(y).last() {
+                return Some(((x, y), y >= cy));
+            }
+        }
+    }
+    None
+}
+
+// Byte ranges of the highlighted matches in a row
+pub fn matches_in_row(ec: &EditorConfig, row: &str) -> Vec<(usize, usize)> {
+    let Some(regex) = &ec.search.regex else {return vec![]};
+    if !ec.search.highlight && ec.mode != Mode::Search {return vec![]}
+    regex.find_iter(row).filter(|m| !m.is_empty()).map(|m| (m.start(), m.end())).collect()
+}
+
+// / and ? open the prompt
+pub fn start(ec: &mut EditorConfig, forward: bool) {
+    ec.search.origin = (ec.cx, ec.cy);
+    ec.search.forward = forward;
+    ec.command = String::default();
+    ec.mode = Mode::Search;
+    let _ = set_status_message(ec, String::default());
+}
+
+// Called on every key in the prompt, jumps to the first match of what has been typed so far
+pub fn update(ec: &mut EditorConfig) {
+    (ec.cx, ec.cy) = ec.search.origin;
+    ec.search.regex = compile(&ec.command);
+    if let Some(regex) = ec.search.regex.clone() {
+        if let Some(((x, y), _)) = find(ec, &regex, ec.search.origin, ec.search.forward) {
+            (ec.cx, ec.cy) = (x, y);
+        }
+    }
+    ec.dirty_rows.extend(0..ec.screenrows);
+}
+
+pub fn cancel(ec: &mut EditorConfig) {
+    (ec.cx, ec.cy) = ec.search.origin;
+    ec.search.regex = compile(&ec.search.pattern);
+    ec.command = String::default();
+    ec.mode = Mode::Normal;
+    ec.dirty_rows.extend(0..ec.screenrows);
+}
+
+// Enter in the prompt, an empty pattern searches for the last one again
+pub fn confirm(ec: &mut EditorConfig) {
+    let typed = std::mem::take(&mut ec.command);
+    if !typed.is_empty() {ec.search.pattern = typed}
+    ec.mode = Mode::Normal;
+    (ec.cx, ec.cy) = ec.search.origin;
+    let forward = ec.search.forward;
+    jump(ec, forward);
+}
+
+fn jump(ec: &mut EditorConfig, forward: bool) {
+    let pattern = ec.search.pattern.clone();
+    let prefix = if forward {'/'} else {'?'};
+    ec.search.regex = compile(&pattern);
+    ec.search.highlight = true;
+    ec.dirty_rows.extend(0..ec.screenrows);
+    let Some(regex) = ec.search.regex.clone() else {
+        let message = if pattern.is_empty() {"E35: No previous regular expression".to_string()}
+            else {format!("E486: Pattern not found: {}", pattern)};
+        let _ = set_status_message(ec, message);
+        return
+    };
+    match find(ec, &regex, (ec.cx, ec.cy), forward) {
+        Some(((x, y), wrapped)) => {
+            (ec.cx, ec.cy) = (x, y);
+            let message = match (wrapped, forward) {
+                (true, true) => "search hit BOTTOM, continuing at TOP".to_string(),
+                (true, false) => "search hit TOP, continuing at BOTTOM".to_string(),
+                _ => format!("{}{}", prefix, pattern),
+            };
+            let _ = set_status_message(ec, message);
+        }
+        None => {let _ = set_status_message(ec, format!("E486: Pattern not found: {}", pattern));}
+    }
+}
+
+// n and N, N goes the other way from the last search
+pub fn repeat(ec: &mut EditorConfig, reverse: bool) {
+    let forward = ec.search.forward != reverse;
+    jump(ec, forward);
+}
+
+// * and # search for the whole word under the cursor
+pub fn word_under_cursor(ec: &mut EditorConfig, forward: bool) {
+    let line = &ec.buf.line(ec.cy);
+    let is_word = |c: char| !SEPARATORS.contains(&c);
+    let Some(rest) = line.get(ec.cx..) else {return};
+    let Some(offset) = rest.find(is_word) else {
+        let _ = set_status_message(ec, String::from("E348: No string under cursor"));
+        return
+    };
+    let at = ec.cx + offset;
+    let start = line[..at].rfind(|c| !is_word(c)).map(|i| i + 1).unwrap_or(0);
+    let end = line[at..].find(|c| !is_word(c)).map(|i| at + i).unwrap_or(line.len());
+    let word = escape(&line[start..end]);
+    ec.search.pattern = format!("\\<{}\\>", word);
+    ec.search.forward = forward;
+    ec.cx = start;
+    jump(ec, forward);
+}
+
+pub fn last_pattern(ec: &EditorConfig) -> String {
+    ec.search.pattern.clone()
+}
+
+// :s also sets the pattern that n, N and the highlighting use
+pub fn set_pattern(ec: &mut EditorConfig, pattern: &str) {
+    ec.search.pattern = pattern.to_string();
+    ec.search.regex = compile(pattern);
+    ec.search.highlight = true;
+}
+
+// :noh
+pub fn clear_highlight(ec: &mut EditorConfig) {
+    ec.search.highlight = false;
+    ec.dirty_rows.extend(0..ec.screenrows);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn found<'a>(pattern: &str, text: &'a str) -> Vec<&'a str> {
+        compile(pattern).unwrap().find_iter(text).map(|m| m.as_str()).collect()
+    }
+
+    #[test]
+    fn magic_specials_need_a_backslash() {
+        assert_eq!(found("\\(ab\\)\\+", "ababx ab"), ["abab", "ab"]);
+        assert_eq!(found("cat\\|dog", "a dog, a cat"), ["dog", "cat"]);
+        assert_eq!(found("colou\\=r", "color colour"), ["color", "colour"]);
+        assert_eq!(found("colou\\?r", "color colour"), ["color", "colour"]);
+        assert_eq!(found("a\\{2,3}", "a aa aaaa"), ["aa", "aaa"]);
+        assert_eq!(found("a\\{2\\}", "aaa"), ["aa"]);
+        assert_eq!(found("a\\{,2}", "aaa"), ["aa", "a"]);
+        assert_eq!(found("a\\{-1,}", "aaa"), ["a", "a", "a"]);
+        assert_eq!(found("\\%(x\\|y\\)z", "xz yz zz"), ["xz", "yz"]);
+    }
+
+    #[test]
+    fn plain_brackets_and_operators_match_themselves() {
+        assert_eq!(found("f(x)", "f(x) fx"), ["f(x)"]);
+        assert_eq!(found("a|b", "a|b ab"), ["a|b"]);
+        assert_eq!(found("1+1?", "1+1? 11"), ["1+1?"]);
+        assert_eq!(found("{}", "fn f() {}"), ["{}"]);
+        assert_eq!(found("a.*b", "xaxxbx"), ["axxb"]);
+    }
+
+    #[test]
+    fn collections() {
+        assert_eq!(found("[(|)]", "a(b|c)"), ["(", "|", ")"]);
+        assert_eq!(found("[]x]", "a]x"), ["]", "x"]);
+        assert_eq!(found("[^a-c]", "abcd"), ["d"]);
+        assert_eq!(found("[[:digit:]]\\+", "a12b3"), ["12", "3"]);
+        assert_eq!(found("[[]", "a[b"), ["["]);
+        assert_eq!(found("a[", "a[b"), ["a["]);
+    }
+
+    #[test]
+    fn words_case_and_classes() {
+        assert_eq!(found("\\<is\\>", "this is"), ["is"]);
+        assert_eq!(found("\\cabc", "ABC abc"), ["ABC", "abc"]);
+        assert_eq!(found("abc", "ABC abc"), ["abc"]);
+        assert_eq!(found("\\u\\l\\+", "Word word"), ["Word"]);
+        assert_eq!(found("\\d\\+\\s", "12 34"), ["12 "]);
+        assert_eq!(found("a\\/b\\.", "a/b. a/bc"), ["a/b."]);
+        assert!(compile("a\\{2").is_none());
+        assert!(compile("").is_none());
+    }
+
+    #[test]
+    fn escaped_text_matches_itself() {
+        let text = "a.b*c[d]^e$f~g/h\\i(j)";
+        assert_eq!(found(&escape(text), text), [text]);
+    }
+}
@@ -0,0 +1,235 @@
+use std::io;
+use crossterm::event::KeyCode;
+use crate::{edit_row, ex::Range, insert_row, search, set_status_message, utils::leading_whitespace, EditorConfig};
+
+enum Answer {
+    Yes,
+    No,
+    All,
+    Quit,
+    Last,
+}
+
+// Split on the delimiter, except where it is escaped with a backslash
+fn split_args(args: &str, delim: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = args.chars();
+    while let Some(c) = chars.next() {
+        let part = parts.last_mut().unwrap();
+        if c == '\\' {
+            match chars.next() {
+                Some(next) if next == delim => part.push(next),
+                Some(next) => {
+                    part.push('\\');
+                    part.push(next);
+                }
+                None => part.push('\\'),
+            }
+        } else if c == delim {
+            parts.push(String::new());
+        } else {
+            part.push(c);
+        }
+    }
+    parts
+}
+
+// Vim's replacement syntax in terms of the regex crate: & and \0 are the whole match, \1 to \9
+// the groups, \r breaks the line and \n puts in a NUL as vim does
+fn replacement(rep: &str) -> String {
+    let mut template = String::new();
+    let mut chars = rep.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '$' => template.push_str("$$"),
+            '&' => template.push_str("${0}"),
+            '\\' => match chars.next() {
+                Some(d @ '0'..='9') => template.push_str(&format!("${{{}}}", d)),
+                Some('r') => template.push('\n'),
+                Some('n') => template.push('\0'),
+                Some('t') => template.push('\t'),
+                Some('$') => template.push_str("$$"),
+                Some(other) => template.push(other),
+                None => template.push('\\'),
+            },
+            c => template.push(c),
+        }
+    }
+    template
+}
+
+fn ask(ec: &mut EditorConfig, rep: &str) -> io::Result<Answer> {
+    set_status_message(ec, format!("replace with {} (y/n/a/q/l)?", rep))?;
+    ec.dirty_rows.extend(0..ec.screenrows);
+    crate::refresh_screen(ec)?;
+    loop {
+        return Ok(match crate::read_key(ec)?.code {
+            KeyCode::Char('y') => Answer::Yes,
+            KeyCode::Char('n') => Answer::No,
+            KeyCode::Char('a') => Answer::All,
+            KeyCode::Char('l') => Answer::Last,
+            KeyCode::Char('q') | KeyCode::Esc => Answer::Quit,
+            _ => continue,
+        });
+    }
+}
+
+fn plural(n: usize, word: &str) -> String {
+    let suffix = match (n, word.ends_with('h')) {
+        (1, _) => "",
+        (_, true) => "es",
+        _ => "s",
+    };
+    format!("{} {}{}", n, word, suffix)
+}
+
+// :[range]s/pattern/replacement/[flags]
+pub fn substitute(ec: &mut EditorConfig, range: Range, args: &str) -> Result<(), String> {
+    let mut chars = args.chars();
+    let delim = chars.next().ok_or("E35: No previous regular expression")?;
+    if delim.is_alphanumeric() || ['\\', '"', '|', ' '].contains(&delim) {
+        return Err(String::from("E146: Regular expressions can't be delimited by letters"));
+    }
+    let parts = split_args(chars.as_str(), delim);
+    let pattern = if parts[0].is_empty() {search::last_pattern(ec)} else {parts[0].clone()};
+    let rep = parts.get(1).cloned().unwrap_or_default();
+    let flags = parts.get(2).map(|f| f.trim_end().to_string()).unwrap_or_default();
+    if let Some(bad) = flags.chars().find(|c| !"gicnI".contains(*c)) {
+        return Err(format!("E488: Trailing characters: {}", bad));
+    }
+    let (global, confirm, count_only) = (flags.contains('g'), flags.contains('c'), flags.contains('n'));
+    let full_pattern = if flags.contains('i') {format!("\\c{}", pattern)} else {pattern.clone()};
+    let Some(regex) = search::compile(&full_pattern) else {
+        return Err(if pattern.is_empty() {String::from("E35: No previous regular expression")}
+            else {format!("E383: Invalid search string: {}", pattern)});
+    };
+    search::set_pattern(ec, &pattern);
+    let template = replacement(&rep);
+
+    let (mut y, mut last) = range;
+    let (mut matched, mut subs, mut lines) = (false, 0, 0);
+    let mut ask_each = confirm && !count_only;
+    let mut quit = false;
+    while y <= last && !quit {
+        let line = ec.buf.line(y);
+        let mut new = String::new();
+        let mut copied = 0;
+        let mut changed = 0;
+        for caps in regex.captures_iter(&line) {
+            let m = caps.get(0).unwrap();
+            matched = true;
+            let mut replace = true;
+            if ask_each {
+                (ec.cx, ec.cy) = (m.start(), y);
+                match ask(ec, &rep).map_err(|e| e.to_string())? {
+                    Answer::Yes => {}
+                    Answer::No => replace = false,
+                    Answer::All => ask_each = false,
+                    Answer::Last => quit = true,
+                    Answer::Quit => {
+                        replace = false;
+                        quit = true;
+                    }
+                }
+            }
+            if replace {
+                new.push_str(&line[copied..m.start()]);
+                caps.expand(&template, &mut new);
+                copied = m.end();
+                changed += 1;
+            }
+            if !global || quit {break}
+        }
+        new.push_str(&line[copied..]);
+
+        if changed > 0 {
+            subs += changed;
+            lines += 1;
+            if !count_only {
+                let mut new_lines = new.split('\n').map(String::from);
+                let first = new_lines.next().unwrap_or_default();
+                edit_row(ec, y, |data| *data = first);
+                for extra in new_lines {
+                    y += 1;
+                    last += 1;
+                    insert_row(ec, y, extra);
+                }
+                ec.cy = y;
+                ec.cx = leading_whitespace(ec.buf.line(y));
+            }
+        }
+        y += 1;
+    }
+    ec.dirty_rows.extend(0..ec.screenrows);
+
+    if !matched {
+        return Err(format!("E486: Pattern not found: {}", pattern));
+    }
+    let message = if count_only {
+        format!("{} on {}", plural(subs, "match"), plural(lines, "line"))
+    } else {
+        format!("{} on {}", plural(subs, "substitution"), plural(lines, "line"))
+    };
+    let _ = set_status_message(ec, message);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ex;
+
+    fn substituted(text: &str, command: &str) -> (Vec<String>, String) {
+        let mut ec = EditorConfig::test(text);
+        if let Err(e) = ex::run(&mut ec, command) {ec.status_msg = e}
+        (ec.lines(), ec.status_msg.clone())
+    }
+
+    #[test]
+    fn replacement_escapes() {
+        assert_eq!(replacement("<&>"), "<${0}>");
+        assert_eq!(replacement("\\2\\1\\0"), "${2}${1}${0}");
+        assert_eq!(replacement("$1 \\$"), "$$1 $$");
+        assert_eq!(replacement("a\\tb\\rc\\nd"), "a\tb\nc\0d");
+        assert_eq!(replacement("\\&\\\\\\/"), "&\\/");
+        assert_eq!(replacement("end\\"), "end\\");
+    }
+
+    #[test]
+    fn escaped_delimiters_stay_in_their_part() {
+        assert_eq!(split_args("a\\/b/c\\d/g", '/'), ["a/b", "c\\d", "g"]);
+        assert_eq!(split_args("x#y", '#'), ["x", "y"]);
+        assert_eq!(split_args("x", '/'), ["x"]);
+    }
+
+    #[test]
+    fn flags() {
+        assert_eq!(substituted("a a\na", "s/a/b/"), (vec!["b a".into(), "a".into()], "1 substitution on 1 line".into()));
+        assert_eq!(substituted("a a\na", "%s/a/b/g"), (vec!["b b".into(), "b".into()], "3 substitutions on 2 lines".into()));
+        assert_eq!(substituted("A a", "s/a/b/gi").0, ["b b"]);
+        assert_eq!(substituted("a a\na", "%s/a/b/gn"), (vec!["a a".into(), "a".into()], "3 matches on 2 lines".into()));
+        assert_eq!(substituted("a", "s/a/b/x").1, "E488: Trailing characters: x");
+        assert_eq!(substituted("a", "s/b/c/").1, "E486: Pattern not found: b");
+        let mut ec = EditorConfig::test("a");
+        assert_eq!(substitute(&mut ec, (0, 0), "a/b/"), Err(String::from("E146: Regular expressions can't be delimited by letters")));
+    }
+
+    #[test]
+    fn ranges_and_groups() {
+        let text = "1\n2\n3\n4";
+        assert_eq!(substituted(text, "2,3s/$/!/").0, ["1", "2!", "3!", "4"]);
+        assert_eq!(substituted(text, "$s/^/>/").0, ["1", "2", "3", ">4"]);
+        assert_eq!(substituted("key = value", "s/\\(\\w\\+\\) = \\(\\w\\+\\)/\\2 = \\1/").0, ["value = key"]);
+        assert_eq!(substituted("f(x)", "s/(x)/[&]/").0, ["f[(x)]"]);
+        assert_eq!(substituted("a,b", "s#,#\\r#").0, ["a", "b"]);
+        assert_eq!(substituted("a/b", "s/\\//|/").0, ["a|b"]);
+    }
+
+    #[test]
+    fn empty_pattern_reuses_the_last_search() {
+        let mut ec = EditorConfig::test("one two one");
+        ec.keys("/one<CR>");
+        assert_eq!(ex::run(&mut ec, "s//1/g"), Ok(()));
+        assert_eq!(ec.lines(), ["1 two 1"]);
+    }
+}
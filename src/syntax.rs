@@ -0,0 +1,142 @@
+use std::{env, fs, path::Path, rc::Rc};
+use regex::Regex;
+use regex_syntax::hir::{Hir, HirKind};
+use serde::Deserialize;
+use crate::highlight::Style;
+
+// How a language is coloured and which files it is for. The built in ones come from syntax/ in
+// the source tree, files in ~/.config/rim/syntax/ add more or replace them by name
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct Syntax {
+    pub name: String,
+    pub extensions: Vec<String>,
+    pub filenames: Vec<String>,
+    pub globs: Vec<String>, // * and ?, matched against the whole path when they have a /
+    pub shebangs: Vec<String>, // interpreters named on a #! line
+    pub keywords: Vec<String>,
+    pub types: Vec<String>,
+    pub preprocess: Vec<String>,
+    pub strings: Vec<char>, // open a string, '<' closes with '>'
+    pub line_comment: Option<String>,
+    pub block_comment: Option<(String, String)>,
+    pub nested_comments: bool,
+    pub multiline_strings: bool,
+    pub raw_strings: bool, // r"..." and r#"..."#
+    pub char_literals: bool, // '"' is a char and not the start of a string
+    pub long_brackets: bool, // Lua [[ ]], [==[ ]==] and --[[ ]] comments
+    pub long_strings: Vec<String>, // close with the same text and may span lines, like """
+    pub heredocs: bool, // <<EOF up to a line with just EOF
+    pub interpolations: Vec<String>, // open code that runs to the matching bracket, like $(
+    pub interpolated: Vec<String>, // the closing quotes of strings interpolations work in
+    pub regex_literals: bool, // /re/ where a value can start
+    pub fences: bool, // Markdown code blocks, coloured in the language named after the fence
+    pub comment_starts_word: bool, // a#b is a word and not the start of a comment
+    patterns: Vec<Pattern>, // tried first at every token
+    number: Option<String>, // regex for a whole word, without it words starting with a digit
+    #[serde(skip)]
+    number_regex: Option<Regex>,
+}
+
+// A regex colouring what it matches at the start of a token. With a group in it only the text up
+// to the end of the group is taken, the rest just has to follow
+#[derive(Deserialize)]
+struct Pattern {
+    regex: String,
+    style: Style,
+    #[serde(default)]
+    line_start: bool, // only tried at the start of a line
+    #[serde(skip)]
+    compiled: Option<Regex>,
+}
+
+impl Syntax {
+    pub fn is_number(&self, word: &str) -> bool {
+        match &self.number_regex {
+            Some(regex) => regex.is_match(word),
+            None => word.starts_with(|c: char| c.is_numeric()),
+        }
+    }
+
+    // The length and style of a pattern matching at the start of rest
+    pub fn pattern(&self, rest: &str, line_start: bool) -> Option<(usize, Style)> {
+        self.patterns.iter().filter(|p| line_start || !p.line_start).find_map(|p| {
+            let captures = p.compiled.as_ref()?.captures(rest)?;
+            let end = captures.get(1).or(captures.get(0))?.end();
+            (end > 0).then_some((end, p.style))
+        })
+    }
+}
+
+const BUILTIN: [&str; 12] = [
+    include_str!("../syntax/c.json"),
+    include_str!("../syntax/rust.json"),
+    include_str!("../syntax/lua.json"),
+    include_str!("../syntax/python.json"),
+    include_str!("../syntax/go.json"),
+    include_str!("../syntax/javascript.json"),
+    include_str!("../syntax/typescript.json"),
+    include_str!("../syntax/sh.json"),
+    include_str!("../syntax/toml.json"),
+    include_str!("../syntax/json.json"),
+    include_str!("../syntax/yaml.json"),
+    include_str!("../syntax/markdown.json"),
+];
+
+fn parse(json: &str) -> Result<Syntax, String> {
+    let mut syntax: Syntax = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    // the lexer looks for these in the text, an empty one would be found everywhere
+    let empty = [
+        ("line_comment", syntax.line_comment.iter().any(String::is_empty)),
+        ("block_comment", syntax.block_comment.iter().any(|(open, close)| open.is_empty() || close.is_empty())),
+        ("long_strings", syntax.long_strings.iter().any(String::is_empty)),
+        ("interpolations", syntax.interpolations.iter().any(String::is_empty)),
+    ];
+    if let Some((name, _)) = empty.iter().find(|(_, empty)| *empty) {return Err(format!("{} can't be empty", name))}
+    if let Some(number) = &syntax.number {
+        syntax.number_regex = Some(Regex::new(&format!("^(?:{})$", number)).map_err(|e| e.to_string())?);
+    }
+    for pattern in &mut syntax.patterns {
+        pattern.compiled = Some(Regex::new(&format!("^(?:{})", pattern.regex)).map_err(|e| e.to_string())?);
+        let hir = regex_syntax::parse(&pattern.regex).map_err(|e| e.to_string())?;
+        if shortest(&hir) == Some(0) {return Err(format!("pattern {} can match an empty string", pattern.regex))}
+    }
+    Ok(syntax)
+}
+
+// The fewest bytes a pattern takes, which is up to the end of its group when the group is at the
+// top. None when it can't match at all
+fn shortest(hir: &Hir) -> Option<usize> {
+    if let HirKind::Concat(parts) = hir.kind() {
+        if let Some(group) = parts.iter().position(|part| matches!(part.kind(), HirKind::Capture(c) if c.index == 1)) {
+            return parts[..=group].iter().map(|part| part.properties().minimum_len()).sum();
+        }
+    }
+    hir.properties().minimum_len()
+}
+
+pub fn builtin() -> Vec<Rc<Syntax>> {
+    BUILTIN.iter().map(|json| Rc::new(parse(json).unwrap())).collect()
+}
+
+// The built in syntaxes and the user's, and an error for each file that couldn't be used
+pub fn load() -> (Vec<Rc<Syntax>>, Vec<String>) {
+    let mut syntaxes = builtin();
+    let mut errors = vec![];
+    let Some(home) = env::var_os("HOME") else {return (syntaxes, errors)};
+    let Ok(dir) = fs::read_dir(Path::new(&home).join(".config/rim/syntax")) else {return (syntaxes, errors)};
+    let mut paths: Vec<_> = dir.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|e| e == "json"))
+        .collect();
+    paths.sort();
+    for path in paths {
+        match fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|json| parse(&json)) {
+            Ok(syntax) => {
+                syntaxes.retain(|s| s.name != syntax.name);
+                syntaxes.push(Rc::new(syntax));
+            }
+            Err(e) => errors.push(format!("{}: {}", path.display(), e)),
+        }
+    }
+    (syntaxes, errors)
+}
@@ -0,0 +1,90 @@
+use crate::{buffer, window::{self, Layout, Window}, EditorConfig};
+
+// A tab page is a set of windows. The current one's live in ec.windows and its slot in ec.tabs is
+// left empty until another tab is entered
+#[derive(Default)]
+pub struct Tab {
+    windows: Vec<Window>,
+    window: usize,
+    layout: Layout,
+}
+
+// Swap the current tab's windows out and tab i's in
+fn enter(ec: &mut EditorConfig, i: usize) {
+    if i == ec.tab {return}
+    window::save(ec);
+    ec.tabs[ec.tab] = Tab {
+        windows: std::mem::take(&mut ec.windows),
+        window: ec.window,
+        layout: std::mem::take(&mut ec.layout),
+    };
+    let tab = std::mem::take(&mut ec.tabs[i]);
+    (ec.windows, ec.window, ec.layout) = (tab.windows, tab.window, tab.layout);
+    ec.tab = i;
+    window::load(ec);
+}
+
+// :tabnew, a tab after this one with a single window on an empty buffer
+pub fn new(ec: &mut EditorConfig) {
+    window::save(ec);
+    let tab = Tab { windows: vec![Window::new(ec.next_window, ec.buf.id)], window: ec.next_window, layout: Layout::Leaf(ec.next_window) };
+    ec.next_window += 1;
+    ec.tabs.insert(ec.tab + 1, tab);
+    enter(ec, ec.tab + 1);
+    buffer::new_empty(ec);
+    window::arrange(ec);
+}
+
+// gt and :tabn go forward, wrapping around
+pub fn next(ec: &mut EditorConfig, count: usize) {
+    enter(ec, (ec.tab + count) % ec.tabs.len());
+}
+
+// gT and :tabp
+pub fn previous(ec: &mut EditorConfig, count: usize) {
+    let n = ec.tabs.len();
+    enter(ec, (ec.tab + n - count % n) % n);
+}
+
+// {count}gt and :tabn N, counting from 1
+pub fn goto(ec: &mut EditorConfig, n: usize) -> Result<(), String> {
+    if n == 0 || n > ec.tabs.len() {return Err(format!("E16: Invalid range: {}", n))}
+    enter(ec, n - 1);
+    Ok(())
+}
+
+// :tabclose, the buffers stay loaded
+pub fn close(ec: &mut EditorConfig) -> Result<(), String> {
+    if ec.tabs.len() == 1 {return Err(String::from("E784: Cannot close last tab page"))}
+    let closing = ec.tab;
+    enter(ec, if closing + 1 < ec.tabs.len() {closing + 1} else {closing - 1});
+    ec.tabs.remove(closing);
+    if ec.tab > closing {ec.tab -= 1}
+    window::arrange(ec);
+    Ok(())
+}
+
+// :tabonly
+pub fn only(ec: &mut EditorConfig) {
+    ec.tabs = vec![Tab::default()];
+    ec.tab = 0;
+    window::arrange(ec);
+}
+
+// The name of the file in each tab's current window and whether it is modified, for the tab line
+pub fn labels(ec: &EditorConfig) -> Vec<(String, bool)> {
+    ec.tabs.iter().enumerate().map(|(i, tab)| {
+        let buf = if i == ec.tab {
+            &ec.buf
+        } else {
+            let w = tab.windows.iter().find(|w| w.id == tab.window).unwrap();
+            buffer::get(ec, w.buffer)
+        };
+        (buf.name().to_string(), buf.dirty)
+    }).collect()
+}
+
+// Windows in the tabs that aren't current, for buffer::delete to retarget
+pub fn hidden_windows(ec: &mut EditorConfig) -> impl Iterator<Item = &mut Window> {
+    ec.tabs.iter_mut().flat_map(|tab| tab.windows.iter_mut())
+}
@@ -0,0 +1,289 @@
+use crate::{operator::{self, MotionKind, Pos}, EditorConfig, Mode, SEPARATORS};
+
+type Selection = (Pos, Pos, MotionKind);
+
+// A line's chars with the byte offset of each, plus one for the end of the line
+fn chars_of(line: &str) -> (Vec<char>, Vec<usize>) {
+    let chars = line.chars().collect();
+    let bytes = line.char_indices().map(|(i, _)| i).chain([line.len()]).collect();
+    (chars, bytes)
+}
+
+// iw, a", i( ... with the count given to the operator
+pub fn select(ec: &EditorConfig, keys: &str, count: usize) -> Option<Selection> {
+    let mut chars = keys.chars();
+    let around = match chars.next()? {
+        'i' => false,
+        'a' => true,
+        _ => return None,
+    };
+    let count = count.max(1);
+    match chars.next()? {
+        'w' => word(ec, count, around, false),
+        'W' => word(ec, count, around, true),
+        c @ ('"' | '\'' | '`') => quote(ec, c, around),
+        '(' | ')' | 'b' => bracket(ec, '(', ')', count, around),
+        '{' | '}' | 'B' => bracket(ec, '{', '}', count, around),
+        '[' | ']' => bracket(ec, '[', ']', count, around),
+        '<' | '>' => bracket(ec, '<', '>', count, around),
+        't' => tag(ec, count, around),
+        'p' => paragraph(ec, count, around),
+        'n' => node(ec, count, around),
+        _ => None,
+    }
+}
+
+// 0 for blanks, 1 for separators, 2 for word characters. WORDs only split on blanks
+fn class(c: char, big: bool) -> u8 {
+    if c == ' ' || c == '\t' {0}
+    else if big || !SEPARATORS.contains(&c) {2}
+    else {1}
+}
+
+fn word(ec: &EditorConfig, count: usize, around: bool, big: bool) -> Option<Selection> {
+    let (line, bytes) = chars_of(&ec.buf.line(ec.cy));
+    if line.is_empty() {return None}
+    let cls = |i: usize| class(line[i], big);
+    let cx = bytes.partition_point(|&b| b <= ec.cx).clamp(1, line.len()) - 1;
+
+    let mut start = cx;
+    while start > 0 && cls(start - 1) == cls(cx) {start -= 1}
+    let mut end = cx;
+    for _i in 0..count {
+        if end >= line.len() {break}
+        let run = cls(end);
+        while end < line.len() && cls(end) == run {end += 1}
+    }
+
+    if around {
+        if cls(cx) == 0 {
+            // on blanks, aw is the blanks plus the word after them
+            if end < line.len() {
+                let run = cls(end);
+                while end < line.len() && cls(end) == run {end += 1}
+            }
+        } else if end < line.len() && cls(end) == 0 {
+            while end < line.len() && cls(end) == 0 {end += 1}
+        } else {
+            while start > 0 && cls(start - 1) == 0 {start -= 1}
+        }
+    }
+    Some(((bytes[start], ec.cy), (bytes[end], ec.cy), MotionKind::Exclusive))
+}
+
+// Quotes pair up from the start of the line, the pair under or after the cursor is used
+fn quote(ec: &EditorConfig, q: char, around: bool) -> Option<Selection> {
+    let (line, bytes) = chars_of(&ec.buf.line(ec.cy));
+    let quotes: Vec<usize> = (0..line.len()).filter(|&i| line[i] == q && (i == 0 || line[i - 1] != '\\')).collect();
+    let pair = quotes.chunks(2).find(|pair| pair.len() == 2 && bytes[pair[1]] >= ec.cx)?;
+
+    let (mut start, mut end) = if around {(pair[0], pair[1] + 1)} else {(pair[0] + 1, pair[1])};
+    if around {
+        if end < line.len() && line[end] == ' ' {
+            while end < line.len() && line[end] == ' ' {end += 1}
+        } else {
+            while start > 0 && line[start - 1] == ' ' {start -= 1}
+        }
+    }
+    Some(((bytes[start], ec.cy), (bytes[end], ec.cy), MotionKind::Exclusive))
+}
+
+fn bracket(ec: &EditorConfig, open: char, close: char, count: usize, around: bool) -> Option<Selection> {
+    let buf = &ec.buf;
+    let cursor = buf.char_index((ec.cx.min(buf.line_len(ec.cy)), ec.cy));
+
+    // walk back to the count-th unmatched open bracket, one under the cursor counts
+    let under = buf.chars_after(cursor).next().filter(|&(_, c)| c == open);
+    let mut depth = 0;
+    let mut found = 0;
+    let (open_at, _) = under.into_iter().chain(buf.chars_before(cursor)).find(|&(_, c)| {
+        if c == close {
+            depth += 1;
+        } else if c == open && depth > 0 {
+            depth -= 1;
+        } else if c == open {
+            found += 1;
+            return found == count;
+        }
+        false
+    })?;
+
+    // and forward to its match
+    let mut depth = 0;
+    let (close_at, _) = buf.chars_after(open_at + 1).find(|&(_, c)| {
+        if c == open {depth += 1}
+        if c == close {
+            if depth == 0 {return true}
+            depth -= 1;
+        }
+        false
+    })?;
+
+    if around {
+        return Some((buf.char_pos(open_at), buf.char_pos(close_at + 1), MotionKind::Exclusive));
+    }
+    let (open_pos, close_pos) = (buf.char_pos(open_at), buf.char_pos(close_at));
+    // a block with the brackets on their own lines is its lines, like di{ on a function body
+    let blank = |c: char| c == ' ' || c == '\t';
+    if open_pos.0 + 1 == buf.line_len(open_pos.1)
+        && close_pos.1 > open_pos.1 + 1
+        && buf.line(close_pos.1)[..close_pos.0].chars().all(blank)
+    {
+        return Some(((0, open_pos.1 + 1), (0, close_pos.1 - 1), MotionKind::Linewise));
+    }
+    Some((buf.char_pos(open_at + 1), close_pos, MotionKind::Exclusive))
+}
+
+// <name ...> or </name>, start and end being char indices
+struct Tag {
+    name: String,
+    closing: bool,
+    start: usize,
+    end: usize,
+}
+
+impl Tag {
+    // None for <br/>, <!-- --> and <?xml ?>, which don't pair up
+    fn new(inside: &str, start: usize, end: usize) -> Option<Tag> {
+        if let Some(name) = inside.strip_prefix('/') {
+            return Some(Tag { name: name.trim().to_string(), closing: true, start, end });
+        }
+        if inside.ends_with('/') || inside.starts_with(['!', '?']) {return None}
+        let name = inside.chars().take_while(|c| !c.is_whitespace()).collect();
+        Some(Tag { name, closing: false, start, end })
+    }
+}
+
+// The tags from char index i on
+fn tags_after(ec: &EditorConfig, i: usize) -> impl Iterator<Item = Tag> + '_ {
+    let mut chars = ec.buf.chars_after(i);
+    std::iter::from_fn(move || loop {
+        let (mut start, _) = chars.find(|&(_, c)| c == '<')?;
+        let mut inside = String::new();
+        // a < that isn't closed before the next one is text
+        let end = loop {
+            match chars.next()? {
+                (i, '>') => break i + 1,
+                (i, '<') => (start, inside) = (i, String::new()),
+                (_, c) => inside.push(c),
+            }
+        };
+        if let Some(tag) = Tag::new(&inside, start, end) {return Some(tag)}
+    })
+}
+
+// The tags before char index i, nearest first
+fn tags_before(ec: &EditorConfig, i: usize) -> impl Iterator<Item = Tag> + '_ {
+    let mut chars = ec.buf.chars_before(i);
+    std::iter::from_fn(move || loop {
+        let (mut last, _) = chars.find(|&(_, c)| c == '>')?;
+        let mut inside = vec![];
+        let start = loop {
+            match chars.next()? {
+                (i, '<') => break i,
+                (i, '>') => (last, inside) = (i, vec![]),
+                (_, c) => inside.push(c),
+            }
+        };
+        if let Some(tag) = Tag::new(&inside.into_iter().rev().collect::<String>(), start, last + 1) {return Some(tag)}
+    })
+}
+
+// Matched <tag>...</tag> pairs around the cursor, innermost first. The text is read outwards from
+// the cursor until the count-th pair is found
+fn tag(ec: &EditorConfig, count: usize, around: bool) -> Option<Selection> {
+    let buf = &ec.buf;
+    let cursor = buf.char_index((ec.cx.min(buf.line_len(ec.cy)), ec.cy));
+
+    // a cursor on a tag is inside its pair, so the text is split before a closing tag and after an
+    // opening one
+    let mut split = cursor;
+    if let Some((_, c)) = buf.chars_after(cursor).next() {
+        let bracket = |&(_, c): &(usize, char)| c == '<' || c == '>';
+        let start = if c == '<' {Some((cursor, c))} else {buf.chars_before(cursor).find(bracket)};
+        let end = if c == '>' {Some((cursor, c))} else {buf.chars_after(cursor + 1).find(bracket)};
+        if let (Some((start, '<')), Some((end, '>'))) = (start, end) {
+            let closing = buf.chars_after(start + 1).next().is_some_and(|(_, c)| c == '/');
+            split = if closing {start} else {end + 1};
+        }
+    }
+
+    // the open tags before the split that aren't closed before it, nearest first
+    let mut closed: Vec<String> = vec![];
+    let mut unclosed = tags_before(ec, split).filter_map(|tag| {
+        if tag.closing {
+            closed.push(tag.name);
+        } else if let Some(at) = closed.iter().rposition(|name| *name == tag.name) {
+            closed.truncate(at);
+        } else {
+            return Some(tag);
+        }
+        None
+    });
+    let mut opens = vec![];
+    let mut next = 0; // the first of opens that hasn't been closed
+
+    let mut inner: Vec<String> = vec![]; // opened after the split
+    let mut found = 0;
+    for tag in tags_after(ec, split) {
+        if !tag.closing {
+            inner.push(tag.name);
+            continue;
+        }
+        if let Some(at) = inner.iter().rposition(|name| *name == tag.name) {
+            inner.truncate(at);
+            continue;
+        }
+        // it closes one of the tags before the split, the ones nearer than that are never closed
+        while !opens[next..].iter().any(|open: &Tag| open.name == tag.name) {
+            let Some(open) = unclosed.next() else {break};
+            opens.push(open);
+        }
+        let Some(at) = opens[next..].iter().position(|open| open.name == tag.name) else {continue};
+        next += at + 1;
+        found += 1;
+        if found == count {
+            let open = &opens[next - 1];
+            let (start, end) = if around {(open.start, tag.end)} else {(open.end, tag.start)};
+            return Some((buf.char_pos(start), buf.char_pos(end), MotionKind::Exclusive));
+        }
+    }
+    None
+}
+
+// Paragraphs are split by empty lines, the same as { and }. A run of empty lines is a paragraph too
+fn paragraph(ec: &EditorConfig, count: usize, around: bool) -> Option<Selection> {
+    let blank = |y: usize| ec.buf.line_len(y) == 0;
+    let mut first = ec.cy;
+    while first > 0 && blank(first - 1) == blank(ec.cy) {first -= 1}
+
+    let mut last = ec.cy;
+    for i in 0..count {
+        if i > 0 {
+            if last + 1 >= ec.buf.numrows {break}
+            last += 1;
+        }
+        while last + 1 < ec.buf.numrows && blank(last + 1) == blank(last) {last += 1}
+    }
+
+    if around {
+        if last + 1 < ec.buf.numrows {
+            last += 1;
+            while last + 1 < ec.buf.numrows && blank(last + 1) == blank(last) {last += 1}
+        } else if first > 0 {
+            first -= 1;
+            while first > 0 && blank(first - 1) == blank(first) {first -= 1}
+        }
+    }
+    Some(((0, first), (0, last), MotionKind::Linewise))
+}
+
+// an is the syntax node around the cursor, or around the selection and bigger than it so that
+// repeating it grows the selection. in is the named nodes inside it
+fn node(ec: &EditorConfig, count: usize, around: bool) -> Option<Selection> {
+    let visual = ec.mode == Mode::Visual;
+    let (start, last) = if visual {operator::ordered(ec.visual_start, (ec.cx, ec.cy))} else {((ec.cx, ec.cy), (ec.cx, ec.cy))};
+    let end = (ec.buf.next_grapheme(last.1, last.0), last.1);
+    let (start, end) = ec.buf.node(start, end, count, visual, !around)?;
+    Some((start, end, MotionKind::Exclusive))
+}
@@ -0,0 +1,194 @@
+use std::ops::Range;
+use ropey::Rope;
+use streaming_iterator::StreamingIterator;
+use tree_sitter::{InputEdit, Language, Node, Parser, Point, Query, QueryCursor, Tree};
+use tree_sitter_language::LanguageFn;
+use crate::{highlight::{self, Span, Style}, operator::Pos};
+
+// compiled from grammars/ by build.rs
+extern "C" {
+    fn tree_sitter_c() -> *const ();
+    fn tree_sitter_rust() -> *const ();
+}
+
+// The filetypes with a grammar, their highlight query and the nodes ]m and [m go to
+fn grammar(filetype: &str) -> Option<(Language, &'static str, &'static [&'static str])> {
+    let language = |f| Language::new(unsafe { LanguageFn::from_raw(f) });
+    Some(match filetype {
+        "rust" => (language(tree_sitter_rust), include_str!("../grammars/rust/queries/highlights.scm"), &["function_item", "function_signature_item"]),
+        "c" => (language(tree_sitter_c), include_str!("../grammars/c/queries/highlights.scm"), &["function_definition"]),
+        _ => return None,
+    })
+}
+
+// The colour for a capture name from a highlight query, by the part before the first dot
+fn style(capture: &str) -> Style {
+    match capture {
+        "constant.builtin" | "variable.builtin" => return Style::Keyword,
+        _ => {}
+    }
+    match capture.split('.').next().unwrap_or_default() {
+        "function" | "constructor" => Style::Function,
+        "string" | "escape" | "char" => Style::String,
+        "keyword" => Style::Keyword,
+        "type" | "label" => Style::Type,
+        "constant" | "number" | "attribute" => Style::Number,
+        "comment" => Style::Comment,
+        "punctuation" | "delimiter" | "operator" => Style::Plain,
+        _ => Style::Text,
+    }
+}
+
+struct Grammar {
+    parser: Parser,
+    query: Query,
+    functions: &'static [&'static str],
+}
+
+// A buffer's text parsed by tree-sitter. Edits are applied to the old tree as they happen, the text
+// is parsed again when the tree is next needed, reusing what didn't change
+#[derive(Default)]
+pub struct SyntaxTree {
+    filetype: Option<String>, // the grammar below is for
+    grammar: Option<Grammar>,
+    tree: Option<Tree>,
+    stale: bool,
+    restyled: Vec<Range<usize>>, // lines parsed differently since the last redraw
+}
+
+impl SyntaxTree {
+    pub fn clear(&mut self) {
+        self.tree = None;
+    }
+
+    // Called before the text from start to end is replaced with inserted
+    pub fn replace(&mut self, text: &Rope, start: Pos, end: Pos, inserted: &str) {
+        let Some(tree) = &mut self.tree else {return};
+        let byte = |(x, y): Pos| text.line_to_byte(y) + x;
+        let point = |(x, y): Pos| Point { row: y, column: x };
+        let lines = inserted.matches('\n').count();
+        let last = inserted.rsplit('\n').next().unwrap_or_default().len();
+        let new_end = if lines == 0 {(start.0 + last, start.1)} else {(last, start.1 + lines)};
+        tree.edit(&InputEdit {
+            start_byte: byte(start),
+            old_end_byte: byte(end),
+            new_end_byte: byte(start) + inserted.len(),
+            start_position: point(start),
+            old_end_position: point(end),
+            new_end_position: point(new_end),
+        });
+        self.stale = true;
+    }
+
+    // Parse text if it changed, None when its filetype has no grammar
+    fn update(&mut self, filetype: &str, text: &Rope) -> Option<()> {
+        if self.filetype.as_deref() != Some(filetype) {
+            *self = SyntaxTree { filetype: Some(filetype.to_string()), ..Default::default() };
+            self.grammar = grammar(filetype).and_then(|(language, query, functions)| {
+                let mut parser = Parser::new();
+                parser.set_language(&language).ok()?;
+                Some(Grammar { parser, query: Query::new(&language, query).ok()?, functions })
+            });
+        }
+        let grammar = self.grammar.as_mut()?;
+        if self.tree.is_none() || self.stale {
+            let old = self.tree.take();
+            let mut read = |byte: usize, _| {
+                if byte >= text.len_bytes() {return &[][..]}
+                let (chunk, start, _, _) = text.chunk_at_byte(byte);
+                &chunk.as_bytes()[byte - start..]
+            };
+            let tree = grammar.parser.parse_with(&mut read, old.as_ref())?;
+            if let Some(old) = old {
+                self.restyled.extend(old.changed_ranges(&tree).map(|r| r.start_point.row..r.end_point.row + 1));
+            }
+            self.tree = Some(tree);
+            self.stale = false;
+        }
+        Some(())
+    }
+
+    // Colours of line y from the highlight query
+    pub fn line(&mut self, filetype: &str, text: &Rope, y: usize) -> Option<Vec<Span>> {
+        self.update(filetype, text)?;
+        let root = self.tree.as_ref()?.root_node();
+        let query = &self.grammar.as_ref()?.query;
+        let start = text.line_to_byte(y);
+        let len = text.line(y).len_bytes() - usize::from(y + 1 < text.len_lines());
+        let mut styles = vec![Style::Text; len];
+        // a node captured twice keeps the first, the query puts the more specific patterns first.
+        // Nodes inside it come later and colour over it
+        let mut done = vec![];
+        let mut cursor = QueryCursor::new();
+        cursor.set_byte_range(start..start + len);
+        let mut captures = cursor.captures(query, root, |node: Node| text.byte_slice(node.byte_range()).chunks().map(str::as_bytes));
+        while let Some((m, i)) = captures.next() {
+            let capture = m.captures[*i];
+            let range = capture.node.byte_range();
+            if done.contains(&range) {continue}
+            let from = range.start.clamp(start, start + len) - start;
+            let to = range.end.clamp(start, start + len) - start;
+            styles[from..to].fill(style(query.capture_names()[capture.index as usize]));
+            done.push(range);
+        }
+        let mut spans = vec![];
+        for (x, style) in styles.into_iter().enumerate() {highlight::push(&mut spans, x + 1, style)}
+        Some(spans)
+    }
+
+    // Lines up to last parsed differently since this was last asked, None without a grammar
+    pub fn restyled(&mut self, filetype: &str, text: &Rope, last: usize) -> Option<Vec<usize>> {
+        self.update(filetype, text)?;
+        Some(self.restyled.drain(..).flat_map(|r| r.start..r.end.min(last + 1)).collect())
+    }
+
+    // The nearest function starting, or with its last char, after or before byte at
+    pub fn function(&mut self, filetype: &str, text: &Rope, at: usize, forward: bool, ends: bool) -> Option<Option<Pos>> {
+        self.update(filetype, text)?;
+        let root = self.tree.as_ref()?.root_node();
+        let kinds = self.grammar.as_ref()?.functions;
+        Some(nearest(root, kinds, at, forward, ends).map(|node| {
+            let (start, end) = (node.start_position(), node.end_position());
+            if ends {(end.column.saturating_sub(1), end.row)} else {(start.column, start.row)}
+        }))
+    }
+
+    // The named node around bytes start..end, count levels up. With grow it has to be bigger than
+    // that, with inner only its named children are taken
+    pub fn node(&mut self, filetype: &str, text: &Rope, (start, end): (usize, usize), count: usize, grow: bool, inner: bool) -> Option<Range<usize>> {
+        self.update(filetype, text)?;
+        let root = self.tree.as_ref()?.root_node();
+        let mut node = root.named_descendant_for_byte_range(start, end)?;
+        if grow {
+            while node.byte_range() == (start..end) || !node.is_named() {node = node.parent()?}
+        }
+        // each count after the first goes one node further out
+        for _ in 1..count {
+            let range = node.byte_range();
+            while node.byte_range() == range || !node.is_named() {node = node.parent()?}
+        }
+        if !inner {return Some(node.byte_range())}
+        let mut cursor = node.walk();
+        let children: Vec<Node> = node.named_children(&mut cursor).collect();
+        Some(match (children.first(), children.last()) {
+            (Some(first), Some(last)) => first.start_byte()..last.end_byte(),
+            _ => node.byte_range(),
+        })
+    }
+}
+
+// Only the children on the side of at that is searched are looked into, nearest first. A function
+// starts before anything inside it and ends after it
+fn nearest<'a>(node: Node<'a>, kinds: &[&str], at: usize, forward: bool, ends: bool) -> Option<Node<'a>> {
+    let target = |node: Node| if ends {node.end_byte().saturating_sub(1)} else {node.start_byte()};
+    let mut cursor = node.walk();
+    let mut children: Vec<Node> = node.children(&mut cursor).filter(|c| if forward {c.end_byte() > at} else {c.start_byte() < at}).collect();
+    if !forward {children.reverse()}
+    for child in children {
+        let function = kinds.contains(&child.kind()) && if forward {target(child) > at} else {target(child) < at};
+        if function && forward != ends {return Some(child)}
+        if let Some(inside) = nearest(child, kinds, at, forward, ends) {return Some(inside)}
+        if function {return Some(child)}
+    }
+    None
+}
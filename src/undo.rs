@@ -0,0 +1,443 @@
+use std::{env, fs, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+use serde::{Deserialize, Serialize};
+use crate::{utils, EditorConfig};
+
+// A splice replaces the rows at..at+old.len() with new
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Splice {
+    pub at: usize,
+    pub old: Vec<String>,
+    pub new: Vec<String>,
+}
+
+// One undoable change, made up of every splice between two commits
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Change {
+    pub splices: Vec<Splice>,
+    pub before: (usize, usize),
+    pub after: (usize, usize),
+}
+
+// Node 0 is the root (the text as it was loaded), every other node is the state after its change.
+// Nodes are only ever appended, so a node's index is also its change number.
+#[derive(Clone, Serialize, Deserialize)]
+struct Node {
+    parent: usize,
+    change: Change,
+    time: u64,
+    // child that redo walks into, the last branch we came up from or created
+    redo_child: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UndoHistory {
+    nodes: Vec<Node>,
+    current: usize,
+    // nodes the buffer was written at, for :earlier Nf
+    saves: Vec<usize>,
+    #[serde(skip)]
+    pending: Option<Change>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UndoFile {
+    hash: u64,
+    history: UndoHistory,
+}
+
+impl Default for UndoHistory {
+    fn default() -> Self {
+        UndoHistory {
+            nodes: vec![Node {
+                parent: 0,
+                change: Change { splices: vec![], before: (0, 0), after: (0, 0) },
+                time: now(),
+                redo_child: None,
+            }],
+            current: 0,
+            saves: vec![],
+            pending: None,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl UndoHistory {
+    // Record a splice into the pending change, cursor is the position before the edit
+    pub fn record(&mut self, splice: Splice, cursor: (usize, usize)) {
+        let change = self.pending.get_or_insert(Change {
+            splices: vec![],
+            before: cursor,
+            after: cursor,
+        });
+
+        // Typing on the same line keeps replacing that line, so fold it into the last splice
+        if let Some(last) = change.splices.last_mut() {
+            if last.at == splice.at && last.new.len() == 1 && splice.old.len() == 1 && splice.new.len() == 1 {
+                last.new = splice.new;
+                return;
+            }
+        }
+        change.splices.push(splice);
+    }
+
+    // Close the pending change into a new node below the current one
+    pub fn commit(&mut self, cursor: (usize, usize)) {
+        if let Some(mut change) = self.pending.take() {
+            change.after = cursor;
+            let id = self.nodes.len();
+            self.nodes.push(Node {
+                parent: self.current,
+                change,
+                time: now(),
+                redo_child: None,
+            });
+            self.nodes[self.current].redo_child = Some(id);
+            self.current = id;
+        }
+    }
+
+    fn ancestors(&self, mut node: usize) -> Vec<usize> {
+        let mut path = vec![node];
+        while node != 0 {
+            node = self.nodes[node].parent;
+            path.push(node);
+        }
+        path
+    }
+
+    // A history read from disk has to hold together: every index in range and each node after its
+    // parent, so walking up always reaches the root
+    fn valid(&self) -> bool {
+        let len = self.nodes.len();
+        len > 0 && self.current < len && self.saves.iter().all(|&n| n < len)
+            && self.nodes.iter().enumerate().all(|(n, node)| {
+                (n == 0 || node.parent < n) && node.redo_child.is_none_or(|c| c > n && c < len && self.nodes[c].parent == n)
+            })
+    }
+
+    // Newest node that existed at the given time
+    fn node_at_time(&self, time: u64) -> usize {
+        (0..self.nodes.len()).rev().find(|&n| self.nodes[n].time <= time).unwrap_or(0)
+    }
+}
+
+// Whether the splices fit a buffer of rows lines, applied in turn
+fn fits(mut rows: usize, splices: &[Splice], reverse: bool) -> bool {
+    let mut fit = |at: usize, remove: usize, insert: usize| {
+        if at + remove > rows {return false}
+        rows = rows - remove + insert;
+        true
+    };
+    if reverse {
+        splices.iter().rev().all(|splice| fit(splice.at, splice.new.len(), splice.old.len()))
+    } else {
+        splices.iter().all(|splice| fit(splice.at, splice.old.len(), splice.new.len()))
+    }
+}
+
+// A change that doesn't fit the text means the history is wrong, it is dropped instead of used
+fn apply(ec: &mut EditorConfig, splices: &[Splice], reverse: bool) -> Result<(), String> {
+    if !fits(ec.buf.numrows, splices, reverse) {
+        ec.buf.undo = UndoHistory::default();
+        return Err(String::from("E438: u_undo: line numbers wrong"));
+    }
+    let mut replace = |at: usize, remove: &[String], insert: &[String]| {
+        for _ in remove {ec.buf.remove_line(at);}
+        for (i, line) in insert.iter().enumerate() {ec.buf.insert_line(at + i, line)}
+    };
+    if reverse {
+        for splice in splices.iter().rev() {replace(splice.at, &splice.new, &splice.old)}
+    } else {
+        for splice in splices {replace(splice.at, &splice.old, &splice.new)}
+    }
+    if ec.buf.numrows == 0 {ec.buf.insert_line(0, "")}
+    ec.buf.dirty = true;
+    // every row on screen may have moved
+    ec.dirty_rows.extend(0..ec.screenrows);
+    Ok(())
+}
+
+fn set_cursor(ec: &mut EditorConfig, (cx, cy): (usize, usize)) {
+    ec.cy = cy.min(ec.buf.numrows - 1);
+    ec.cx = cx.min(ec.buf.line_len(ec.cy));
+}
+
+fn undo_node(ec: &mut EditorConfig) -> Result<(), String> {
+    let node = ec.buf.undo.current;
+    let change = ec.buf.undo.nodes[node].change.clone();
+    apply(ec, &change.splices, true)?;
+    set_cursor(ec, change.before);
+    let parent = ec.buf.undo.nodes[node].parent;
+    ec.buf.undo.nodes[parent].redo_child = Some(node);
+    ec.buf.undo.current = parent;
+    Ok(())
+}
+
+fn redo_node(ec: &mut EditorConfig, child: usize) -> Result<(), String> {
+    let change = ec.buf.undo.nodes[child].change.clone();
+    apply(ec, &change.splices, false)?;
+    set_cursor(ec, change.after);
+    ec.buf.undo.nodes[ec.buf.undo.current].redo_child = Some(child);
+    ec.buf.undo.current = child;
+    Ok(())
+}
+
+// Ok(false) when there is nothing to undo
+pub fn undo(ec: &mut EditorConfig) -> Result<bool, String> {
+    ec.buf.undo.commit((ec.cx, ec.cy));
+    if ec.buf.undo.current == 0 {return Ok(false)}
+    undo_node(ec)?;
+    Ok(true)
+}
+
+pub fn redo(ec: &mut EditorConfig) -> Result<bool, String> {
+    let Some(child) = ec.buf.undo.nodes[ec.buf.undo.current].redo_child else {return Ok(false)};
+    redo_node(ec, child)?;
+    Ok(true)
+}
+
+// Move the buffer to the state of any node, undoing up to the common ancestor and redoing down
+pub fn goto(ec: &mut EditorConfig, target: usize) -> Result<(), String> {
+    ec.buf.undo.commit((ec.cx, ec.cy));
+    let target = target.min(ec.buf.undo.nodes.len() - 1);
+    let down = ec.buf.undo.ancestors(target);
+    let up = ec.buf.undo.ancestors(ec.buf.undo.current);
+    let common = *down.iter().find(|n| up.contains(n)).unwrap_or(&0);
+
+    while ec.buf.undo.current != common {undo_node(ec)?}
+    let path: Vec<usize> = down.into_iter().take_while(|&n| n != common).collect();
+    for node in path.into_iter().rev() {
+        redo_node(ec, node)?;
+    }
+    let _ = crate::set_status_message(ec, format!("change #{}", ec.buf.undo.current));
+    Ok(())
+}
+
+// g- and g+ step through the changes in the order they were made, across branches
+pub fn step(ec: &mut EditorConfig, count: isize) -> Result<(), String> {
+    ec.buf.undo.commit((ec.cx, ec.cy));
+    let target = (ec.buf.undo.current as isize + count).max(0) as usize;
+    goto(ec, target)
+}
+
+// :earlier and :later, arg is a count or a count with s/m/h/d/f
+pub fn travel(ec: &mut EditorConfig, arg: &str, forward: bool) -> Result<(), String> {
+    ec.buf.undo.commit((ec.cx, ec.cy));
+    let arg = if arg.is_empty() {"1"} else {arg};
+    let (num, unit) = arg.split_at(arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len()));
+    let num: u64 = num.parse().map_err(|_| format!("Invalid argument: {}", arg))?;
+    let seconds = match unit {
+        "" => {
+            let count = if forward {num as isize} else {-(num as isize)};
+            return step(ec, count);
+        }
+        "f" => {
+            let saves = &ec.buf.undo.saves;
+            let current = ec.buf.undo.current;
+            let target = if forward {
+                saves.iter().filter(|&&s| s > current).nth((num as usize).saturating_sub(1)).copied().unwrap_or(ec.buf.undo.nodes.len() - 1)
+            } else {
+                saves.iter().rev().filter(|&&s| s < current).nth((num as usize).saturating_sub(1)).copied().unwrap_or(0)
+            };
+            return goto(ec, target);
+        }
+        "s" => num,
+        "m" => num * 60,
+        "h" => num * 60 * 60,
+        "d" => num * 60 * 60 * 24,
+        _ => return Err(format!("Invalid argument: {}", arg)),
+    };
+    let time = ec.buf.undo.nodes[ec.buf.undo.current].time;
+    let target = if forward {
+        ec.buf.undo.node_at_time(time + seconds).max(ec.buf.undo.current)
+    } else {
+        ec.buf.undo.node_at_time(time.saturating_sub(seconds)).min(ec.buf.undo.current.saturating_sub(1))
+    };
+    goto(ec, target)
+}
+
+// Undo files live in ~/.local/state/rim/undo/ named after the full path of the file, like vim's
+// undodir. Without a HOME they sit next to the file as .name.un~
+fn undo_path(filename: &str) -> Option<PathBuf> {
+    let path = fs::canonicalize(filename).ok()?;
+    if let Some(home) = env::var_os("HOME") {
+        let name = path.to_string_lossy().replace('/', "%");
+        Some(Path::new(&home).join(".local/state/rim/undo").join(name))
+    } else {
+        let name = format!(".{}.un~", path.file_name()?.to_string_lossy());
+        Some(path.with_file_name(name))
+    }
+}
+
+pub fn save(ec: &mut EditorConfig) -> std::io::Result<()> {
+    ec.buf.undo.commit((ec.cx, ec.cy));
+    let current = ec.buf.undo.current;
+    if ec.buf.undo.saves.last() != Some(&current) {ec.buf.undo.saves.push(current)}
+    let Some(path) = undo_path(&ec.buf.filename) else {return Ok(())};
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let undo_file = UndoFile { hash: utils::hash_content(ec.buf.chunks()), history: std::mem::take(&mut ec.buf.undo) };
+    let json = serde_json::to_string(&undo_file);
+    ec.buf.undo = undo_file.history;
+    fs::write(path, json?)
+}
+
+// Restore the history saved for this file, as long as the file wasn't changed outside rim
+pub fn load(ec: &mut EditorConfig, content: &str) {
+    let json = undo_path(&ec.buf.filename).and_then(|path| fs::read_to_string(path).ok());
+    ec.buf.undo = json.and_then(|json| read(&json, content)).unwrap_or_default();
+}
+
+// The history in an undo file, if it is whole and was saved for content
+fn read(json: &str, content: &str) -> Option<UndoHistory> {
+    let undo_file = serde_json::from_str::<UndoFile>(json).ok()?;
+    if undo_file.hash != utils::hash_content([content]) {return None}
+    undo_file.history.valid().then_some(undo_file.history)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ex;
+
+    // The undo file rim would write for ec, changed by corrupt
+    fn undo_file(ec: &mut EditorConfig, corrupt: impl Fn(&mut serde_json::Value)) -> String {
+        ec.buf.undo.commit((ec.cx, ec.cy));
+        let undo_file = UndoFile { hash: utils::hash_content(ec.buf.chunks()), history: std::mem::take(&mut ec.buf.undo) };
+        let mut json = serde_json::to_value(&undo_file).unwrap();
+        ec.buf.undo = undo_file.history;
+        corrupt(&mut json);
+        json.to_string()
+    }
+
+    // a, then ab, abc, and abd on a branch from ab
+    fn branched() -> EditorConfig {
+        let mut ec = EditorConfig::test("a");
+        ec.keys("Ab<Esc>Ac<Esc>uAd<Esc>");
+        assert_eq!(ec.lines(), ["abd"]);
+        ec
+    }
+
+    #[test]
+    fn undo_and_redo_follow_the_last_branch() {
+        let mut ec = branched();
+        ec.keys("u");
+        assert_eq!(ec.lines(), ["ab"]);
+        ec.keys("<C-r>");
+        assert_eq!(ec.lines(), ["abd"]);
+        ec.keys("uuu");
+        assert_eq!(ec.lines(), ["a"]);
+        assert_eq!(ec.status_msg, "Already at oldest change");
+        ec.keys("3<C-r>");
+        assert_eq!(ec.lines(), ["abd"]);
+        assert_eq!(ec.status_msg, "Already at newest change");
+    }
+
+    #[test]
+    fn steps_cross_branches_in_the_order_changes_were_made() {
+        let mut ec = branched();
+        ec.keys("g-");
+        assert_eq!(ec.lines(), ["abc"]);
+        ec.keys("g-");
+        assert_eq!(ec.lines(), ["ab"]);
+        ec.keys("g-g-g-");
+        assert_eq!(ec.lines(), ["a"]);
+        ec.keys("g+g+");
+        assert_eq!(ec.lines(), ["abc"]);
+        ec.keys("g+g+");
+        assert_eq!(ec.lines(), ["abd"]);
+        // redo goes back down the branch g- last came up
+        ec.keys("g-u<C-r>");
+        assert_eq!(ec.lines(), ["abc"]);
+    }
+
+    #[test]
+    fn goto_any_node() {
+        let mut ec = branched();
+        goto(&mut ec, 2).unwrap();
+        assert_eq!(ec.lines(), ["abc"]);
+        assert_eq!(ec.status_msg, "change #2");
+        goto(&mut ec, 0).unwrap();
+        assert_eq!(ec.lines(), ["a"]);
+        goto(&mut ec, 9).unwrap();
+        assert_eq!(ec.lines(), ["abd"]);
+    }
+
+    #[test]
+    fn earlier_and_later() {
+        let mut ec = branched();
+        ex::run(&mut ec, "earlier 2").unwrap();
+        assert_eq!(ec.lines(), ["ab"]);
+        ex::run(&mut ec, "later").unwrap();
+        assert_eq!(ec.lines(), ["abc"]);
+        assert_eq!(ex::run(&mut ec, "earlier 2x"), Err(String::from("Invalid argument: 2x")));
+
+        // a minute between changes
+        for (i, node) in ec.buf.undo.nodes.iter_mut().enumerate() {node.time = 1000 + 60 * i as u64}
+        ex::run(&mut ec, "later 1m").unwrap();
+        assert_eq!(ec.lines(), ["abd"]);
+        ex::run(&mut ec, "earlier 90s").unwrap();
+        assert_eq!(ec.lines(), ["ab"]);
+        ex::run(&mut ec, "earlier 1h").unwrap();
+        assert_eq!(ec.lines(), ["a"]);
+        ex::run(&mut ec, "later 1d").unwrap();
+        assert_eq!(ec.lines(), ["abd"]);
+    }
+
+    #[test]
+    fn earlier_and_later_by_writes() {
+        let mut ec = EditorConfig::test("a");
+        ec.keys("Ab<Esc>");
+        save(&mut ec).unwrap();
+        ec.keys("Ac<Esc>Ad<Esc>");
+        save(&mut ec).unwrap();
+        ec.keys("Ae<Esc>");
+        ex::run(&mut ec, "earlier 1f").unwrap();
+        assert_eq!(ec.lines(), ["abcd"]);
+        ex::run(&mut ec, "earlier 1f").unwrap();
+        assert_eq!(ec.lines(), ["ab"]);
+        // past the first write is the text as it was loaded
+        ex::run(&mut ec, "earlier 1f").unwrap();
+        assert_eq!(ec.lines(), ["a"]);
+        ex::run(&mut ec, "later 2f").unwrap();
+        assert_eq!(ec.lines(), ["abcd"]);
+        // past the last write is the newest change
+        ex::run(&mut ec, "later 1f").unwrap();
+        assert_eq!(ec.lines(), ["abcde"]);
+    }
+
+    #[test]
+    fn broken_graphs_are_not_loaded() {
+        let mut ec = EditorConfig::test("one\ntwo");
+        ec.keys("xjx");
+        let content = ec.lines().join("\n");
+        assert!(read(&undo_file(&mut ec, |_| {}), &content).is_some());
+        assert!(read(&undo_file(&mut ec, |_| {}), "one\ntwo").is_none());
+        // a cycle, and parents and children out of range
+        assert!(read(&undo_file(&mut ec, |json| json["history"]["nodes"][1]["parent"] = 2.into()), &content).is_none());
+        assert!(read(&undo_file(&mut ec, |json| json["history"]["nodes"][2]["parent"] = 7.into()), &content).is_none());
+        assert!(read(&undo_file(&mut ec, |json| json["history"]["nodes"][0]["redo_child"] = 9.into()), &content).is_none());
+        assert!(read(&undo_file(&mut ec, |json| json["history"]["current"] = 3.into()), &content).is_none());
+    }
+
+    #[test]
+    fn splices_past_the_end_are_an_error() {
+        let mut ec = EditorConfig::test("one\ntwo");
+        ec.keys("xjx");
+        let content = ec.lines().join("\n");
+        let json = undo_file(&mut ec, |json| json["history"]["nodes"][2]["change"]["splices"][0]["at"] = 5.into());
+        ec.buf.undo = read(&json, &content).unwrap();
+        ec.keys("u");
+        assert_eq!(ec.status_msg, "E438: u_undo: line numbers wrong");
+        assert_eq!(ec.lines(), ["ne", "wo"]);
+        // the history is gone rather than tried again
+        ec.keys("u");
+        assert_eq!(ec.status_msg, "Already at oldest change");
+        assert_eq!(ec.lines(), ["ne", "wo"]);
+    }
+}
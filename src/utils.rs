@@ -1,3 +1,5 @@
+use crate::{grapheme, options::Options};
+
 pub fn split_hex_into_bytes(hex: u32) -> (u8, u8, u8) {
     let byte1 = (hex >> 16) as u8; // First byte (most significant)
     let byte2 = (hex >> 8 & 0xFF) as u8; // Second byte
@@ -6,11 +8,41 @@ pub fn split_hex_into_bytes(hex: u32) -> (u8, u8, u8) {
     (byte1, byte2, byte3)
 }
 
+// Bytes of spaces and tabs the line starts with
 pub fn leading_whitespace(line: String) -> usize {
     let mut spaces = 0;
     for char in line.chars() {
-        if char == ' ' {spaces += 1}
+        if char == ' ' || char == '\t' {spaces += 1}
         else {break}
     }
     spaces
 }
+
+// Columns the indent of line takes on screen
+pub fn indent_width(line: &str, tabstop: usize) -> usize {
+    grapheme::col(line, leading_whitespace(line.to_string()), tabstop)
+}
+
+// Whitespace that indents to width, using tabs as far as they go unless expandtab is set
+pub fn indent(width: usize, options: &Options) -> String {
+    if options.expandtab {return " ".repeat(width)}
+    format!("{}{}", "\t".repeat(width / options.tabstop), " ".repeat(width % options.tabstop))
+}
+
+// FNV-1a, stable across builds so it can be stored on disk
+pub fn hash_content<'a, I: IntoIterator<Item = &'a str>>(chunks: I) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in chunks.into_iter().flat_map(str::bytes) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// ~/ at the start of a path typed on the command line
+pub fn expand_path(path: &str) -> String {
+    match (path.strip_prefix("~/"), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{}/{}", home, rest),
+        _ => path.to_string(),
+    }
+}
@@ -0,0 +1,222 @@
+use std::io::stdout;
+use crossterm::{cursor, ExecutableCommand};
+use crate::{
+    edit_row, grapheme, insert_row,
+    operator::{self, MotionKind, Pos},
+    register::Register,
+    textobject, EditorConfig, Mode,
+};
+
+// Set up by I/A on a block, the text typed on the first line is copied to the rest on Esc
+pub struct BlockInsert {
+    col: usize, // screen column
+    first: usize,
+    last: usize,
+    len: usize, // length of the first line before typing
+    pad: bool,  // A pads short lines out to the block, I skips them
+}
+
+pub fn start(ec: &mut EditorConfig, mode: Mode) {
+    ec.visual_start = (ec.cx, ec.cy);
+    ec.mode = mode;
+    ec.dirty_rows.extend(0..ec.screenrows);
+}
+
+pub fn exit(ec: &mut EditorConfig) {
+    save_marks(ec);
+    let _ = stdout().execute(cursor::SetCursorStyle::SteadyBlock);
+    ec.mode = Mode::Normal;
+    ec.dirty_rows.extend(0..ec.screenrows);
+}
+
+// '< and '> for ranges like :'<,'>s
+fn save_marks(ec: &mut EditorConfig) {
+    ec.buf.visual_marks = Some(operator::ordered(ec.visual_start, (ec.cx, ec.cy)));
+}
+
+// : on a selection starts the command with its range
+pub fn command(ec: &mut EditorConfig) {
+    exit(ec);
+    ec.mode = Mode::Command;
+    ec.command = String::from("'<,'>");
+}
+
+// v, V and Ctrl-v switch between the visual modes, or leave when already in that one
+pub fn switch(ec: &mut EditorConfig, mode: Mode) {
+    if ec.mode == mode {
+        exit(ec);
+    } else {
+        ec.mode = mode;
+    }
+}
+
+// o jumps to the other end of the selection
+pub fn swap(ec: &mut EditorConfig) {
+    let cursor = (ec.cx, ec.cy);
+    (ec.cx, ec.cy) = ec.visual_start;
+    ec.visual_start = cursor;
+}
+
+// Columns of row y covered by the selection, end exclusive
+pub fn selected_cols(ec: &EditorConfig, y: usize) -> Option<(usize, usize)> {
+    let (start, end) = operator::ordered(ec.visual_start, (ec.cx, ec.cy));
+    if y < start.1 || y > end.1 {return None}
+    let len = ec.buf.line_len(y);
+    match ec.mode {
+        Mode::Visual => {
+            let from = if y == start.1 {start.0} else {0};
+            let to = if y == end.1 {ec.buf.next_grapheme(y, end.0)} else {len};
+            Some((from, to))
+        }
+        Mode::VisualLine => Some((0, len)),
+        Mode::VisualBlock => {
+            let (left, right) = block_cols(ec, ec.visual_start, (ec.cx, ec.cy));
+            let line = ec.buf.line(y);
+            Some((grapheme::at_col(&line, left, ec.options.tabstop), grapheme::at_col(&line, right, ec.options.tabstop)))
+        }
+        _ => None,
+    }
+}
+
+// iw, ap ... grow the selection to the text object
+pub fn select_object(ec: &mut EditorConfig, keys: &str, count: usize) -> bool {
+    let Some((start, end, kind)) = textobject::select(ec, keys, count) else {return false};
+    if kind == MotionKind::Linewise {
+        ec.mode = Mode::VisualLine;
+        ec.visual_start = (0, start.1);
+        (ec.cx, ec.cy) = (0, end.1);
+        return true;
+    }
+    ec.visual_start = start;
+    // the selection is inclusive, the object end isn't
+    (ec.cx, ec.cy) = if end.0 > 0 {
+        (ec.buf.prev_grapheme(end.1, end.0), end.1)
+    } else if end.1 > start.1 {
+        (ec.buf.prev_grapheme(end.1 - 1, ec.buf.line_len(end.1 - 1)), end.1 - 1)
+    } else {
+        start
+    };
+    true
+}
+
+// d, y, c, >, <, ~, u, U, J, I and A on the selection
+pub fn operate(ec: &mut EditorConfig, op: &str) {
+    let start = ec.visual_start;
+    let end = (ec.cx, ec.cy);
+    save_marks(ec);
+    let mode = std::mem::replace(&mut ec.mode, Mode::Normal);
+    let _ = stdout().execute(cursor::SetCursorStyle::SteadyBlock);
+
+    let op = match op {
+        "x" => "d",
+        "s" => "c",
+        "u" => "gu",
+        "U" => "gU",
+        op => op,
+    };
+    if mode == Mode::VisualBlock {
+        block(ec, op, start, end);
+        ec.dirty_rows.extend(0..ec.screenrows);
+        return;
+    }
+
+    let kind = if mode == Mode::VisualLine {MotionKind::Linewise} else {MotionKind::Inclusive};
+    let (first, last) = operator::ordered(start, end);
+    match op {
+        "I" => {
+            (ec.cx, ec.cy) = if kind == MotionKind::Linewise {(0, first.1)} else {first};
+            insert_mode(ec);
+        }
+        "A" => {
+            (ec.cx, ec.cy) = if kind == MotionKind::Linewise {(ec.buf.line_len(last.1), last.1)} else {(ec.buf.next_grapheme(last.1, last.0), last.1)};
+            ec.cx = ec.cx.min(ec.buf.line_len(ec.cy));
+            insert_mode(ec);
+        }
+        op => operator::apply(ec, op, start, end, kind),
+    }
+    ec.dirty_rows.extend(0..ec.screenrows);
+}
+
+fn insert_mode(ec: &mut EditorConfig) {
+    let _ = stdout().execute(cursor::SetCursorStyle::SteadyBar);
+    ec.mode = Mode::Insert;
+}
+
+// Screen columns between two corners of a block, end exclusive. Lines with wide characters
+// line up on screen, not by bytes
+fn block_cols(ec: &EditorConfig, a: Pos, b: Pos) -> (usize, usize) {
+    let (a, b) = (grapheme::span(&ec.buf.line(a.1), a.0, ec.options.tabstop), grapheme::span(&ec.buf.line(b.1), b.0, ec.options.tabstop));
+    (a.0.min(b.0), a.1.max(b.1))
+}
+
+fn block(ec: &mut EditorConfig, op: &str, start: Pos, end: Pos) {
+    let (first, last) = (start.1.min(end.1), start.1.max(end.1));
+    let (left, right) = block_cols(ec, start, end);
+    let ts = ec.options.tabstop;
+    let cols = |data: &str| (grapheme::at_col(data, left, ts), grapheme::at_col(data, right, ts));
+    (ec.cx, ec.cy) = (grapheme::at_col(&ec.buf.line(first), left, ts), first);
+
+    let text: Vec<String> = (first..=last).map(|y| {
+        let row = ec.buf.line(y);
+        let (from, to) = cols(&row);
+        row[from..to].to_string()
+    }).collect();
+
+    match op {
+        "y" => ec.registers.yank(ec.register, Register::blockwise(text)),
+        "d" | "c" => {
+            ec.registers.delete(ec.register, Register::blockwise(text));
+            for y in first..=last {
+                let (from, to) = cols(&ec.buf.line(y));
+                edit_row(ec, y, |data| {data.drain(from..to);});
+            }
+            if op == "c" {block_insert(ec, left, first, last, false)}
+        }
+        "~" | "gu" | "gU" => {
+            for y in first..=last {
+                let (from, to) = cols(&ec.buf.line(y));
+                edit_row(ec, y, |data| {
+                    let text = operator::change_case(op, &data[from..to]);
+                    data.replace_range(from..to, &text);
+                });
+            }
+        }
+        "I" => block_insert(ec, left, first, last, false),
+        "A" => block_insert(ec, right, first, last, true),
+        op => operator::apply(ec, op, (left, first), (left, last), MotionKind::Linewise),
+    }
+    ec.cx = ec.buf.snap(ec.cy, ec.cx);
+}
+
+fn block_insert(ec: &mut EditorConfig, col: usize, first: usize, last: usize, pad: bool) {
+    let width = grapheme::col(&ec.buf.line(first), ec.buf.line_len(first), ec.options.tabstop);
+    if width < col {
+        edit_row(ec, first, |data| data.push_str(&" ".repeat(col - width)));
+    }
+    ec.block_insert = Some(BlockInsert { col, first, last, len: ec.buf.line_len(first), pad });
+    (ec.cx, ec.cy) = (grapheme::at_col(&ec.buf.line(first), col, ec.options.tabstop), first);
+    insert_mode(ec);
+}
+
+// Esc after a block I/A, copy what was typed to the other lines
+pub fn finish_block_insert(ec: &mut EditorConfig) {
+    let Some(bi) = ec.block_insert.take() else {return};
+    let line = &ec.buf.line(bi.first);
+    if ec.cy != bi.first || line.len() <= bi.len {return}
+    let ts = ec.options.tabstop;
+    let x = grapheme::at_col(line, bi.col, ts);
+    let Some(typed) = line.get(x..x + line.len() - bi.len) else {return};
+    let typed = typed.to_string();
+
+    for y in bi.first + 1..=bi.last {
+        if y >= ec.buf.numrows {insert_row(ec, y, String::new())}
+        let width = grapheme::col(&ec.buf.line(y), ec.buf.line_len(y), ts);
+        if width < bi.col && !bi.pad {continue}
+        edit_row(ec, y, |data| {
+            if width < bi.col {data.push_str(&" ".repeat(bi.col - width))}
+            let x = grapheme::at_col(data, bi.col, ts);
+            data.insert_str(x, &typed);
+        });
+    }
+    ec.dirty_rows.extend(0..ec.screenrows);
+}
@@ -0,0 +1,311 @@
+use std::io::stdout;
+use crossterm::{queue, terminal::{Clear, ClearType}};
+use crate::{buffer, operator::Pos, wrap, EditorConfig};
+
+const MIN_HEIGHT: usize = 2; // one line of text and the status line
+const MIN_WIDTH: usize = 8; // line numbers and a little text
+
+// A view on a buffer. The current window's cursor and offsets live in ec while it is current
+pub struct Window {
+    pub id: usize,
+    pub buffer: usize,
+    pub cursor: Pos,
+    pub rowoff: usize,
+    pub coloff: usize,
+    // where on screen it is drawn, the last row is its status line
+    pub top: usize,
+    pub left: usize,
+    pub height: usize,
+    pub width: usize,
+}
+
+impl Window {
+    pub fn new(id: usize, buffer: usize) -> Window {
+        Window { id, buffer, cursor: (0, 0), rowoff: 0, coloff: 0, top: 0, left: 0, height: 0, width: 0 }
+    }
+}
+
+// How the screen is divided. Sizes are rows in a horizontal split and columns in a vertical one,
+// where every window but the last is followed by a one column separator
+pub enum Layout {
+    Leaf(usize),
+    Split { vertical: bool, children: Vec<(Layout, usize)> },
+}
+
+// Scale sizes to add up to total, all zero means share it equally
+fn fit(sizes: &mut [usize], total: usize) {
+    let sum: usize = sizes.iter().sum();
+    let n = sizes.len();
+    for (i, size) in sizes.iter_mut().enumerate() {
+        *size = (*size * total).checked_div(sum).unwrap_or(total / n + usize::from(i < total % n));
+    }
+    // rounding leftovers go to the last one
+    let used: usize = sizes.iter().sum();
+    sizes[n - 1] += total - used;
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::Leaf(0)
+    }
+}
+
+impl Layout {
+    fn is_leaf(&self, id: usize) -> bool {
+        matches!(self, Layout::Leaf(leaf) if *leaf == id)
+    }
+
+    // Give every window its rectangle
+    fn place(&mut self, windows: &mut [Window], top: usize, left: usize, height: usize, width: usize) {
+        match self {
+            Layout::Leaf(id) => {
+                if let Some(w) = windows.iter_mut().find(|w| w.id == *id) {
+                    (w.top, w.left, w.height, w.width) = (top, left, height, width);
+                }
+            }
+            Layout::Split { vertical, children } => {
+                let vertical = *vertical;
+                let total = if vertical {width.saturating_sub(children.len() - 1)} else {height};
+                let mut sizes: Vec<usize> = children.iter().map(|(_, size)| *size).collect();
+                fit(&mut sizes, total);
+                let mut offset = if vertical {left} else {top};
+                for ((child, size), new) in children.iter_mut().zip(sizes) {
+                    *size = new;
+                    if vertical {
+                        child.place(windows, top, offset, height, new);
+                        offset += new + 1;
+                    } else {
+                        child.place(windows, offset, left, new, width);
+                        offset += new;
+                    }
+                }
+            }
+        }
+    }
+
+    // Put window new before id, taking half of its space
+    fn split(&mut self, id: usize, new: usize, vertical: bool) -> bool {
+        match self {
+            Layout::Leaf(leaf) if *leaf == id => {
+                *self = Layout::Split { vertical, children: vec![(Layout::Leaf(new), 0), (Layout::Leaf(id), 0)] };
+                true
+            }
+            Layout::Leaf(_) => false,
+            Layout::Split { vertical: v, children } => {
+                if *v == vertical {
+                    if let Some(i) = children.iter().position(|(child, _)| child.is_leaf(id)) {
+                        let size = children[i].1 - usize::from(vertical);
+                        children[i].1 = size - size / 2;
+                        children.insert(i, (Layout::Leaf(new), size / 2));
+                        return true;
+                    }
+                }
+                children.iter_mut().any(|(child, _)| child.split(id, new, vertical))
+            }
+        }
+    }
+
+    // Take window id out, its space goes to the one before it (or after, if it was first)
+    fn remove(&mut self, id: usize) -> bool {
+        let Layout::Split { vertical, children } = self else {return false};
+        let Some(i) = children.iter().position(|(child, _)| child.is_leaf(id)) else {
+            return children.iter_mut().any(|(child, _)| child.remove(id));
+        };
+        let (_, size) = children.remove(i);
+        children[i.saturating_sub(1)].1 += size + usize::from(*vertical);
+        if children.len() == 1 {
+            let (only, _) = children.pop().unwrap();
+            *self = only;
+        }
+        true
+    }
+
+    fn equalize(&mut self) {
+        if let Layout::Split { children, .. } = self {
+            for (child, size) in children {
+                *size = 0;
+                child.equalize();
+            }
+        }
+    }
+
+    // Window ids in screen order, for Ctrl-w w
+    fn leaves(&self, out: &mut Vec<usize>) {
+        match self {
+            Layout::Leaf(id) => out.push(*id),
+            Layout::Split { children, .. } => children.iter().for_each(|(child, _)| child.leaves(out)),
+        }
+    }
+}
+
+pub fn current(ec: &EditorConfig) -> &Window {
+    ec.windows.iter().find(|w| w.id == ec.window).unwrap()
+}
+
+fn current_mut(ec: &mut EditorConfig) -> &mut Window {
+    let id = ec.window;
+    ec.windows.iter_mut().find(|w| w.id == id).unwrap()
+}
+
+// Store the cursor and offsets of the current window before leaving it
+pub fn save(ec: &mut EditorConfig) {
+    let (cursor, rowoff, coloff, buffer) = ((ec.cx, ec.cy), ec.rowoff, ec.coloff, ec.buf.id);
+    let w = current_mut(ec);
+    (w.cursor, w.rowoff, w.coloff, w.buffer) = (cursor, rowoff, coloff, buffer);
+}
+
+// Lay the windows out again after they change, the whole screen is redrawn
+pub fn arrange(ec: &mut EditorConfig) {
+    // the tab line takes the top row once there is more than one tab
+    let top = usize::from(ec.tabs.len() > 1);
+    ec.layout.place(&mut ec.windows, top, 0, ec.termrows - 1 - top, ec.termcols);
+    // a window that got smaller still shows its cursor
+    for w in ec.windows.iter_mut() {
+        let rows = w.height.saturating_sub(1).max(1);
+        if w.cursor.1 >= w.rowoff + rows {w.rowoff = w.cursor.1 + 1 - rows}
+    }
+    let w = current(ec);
+    (ec.screenrows, ec.screencols) = (w.height - 1, w.width);
+    let _ = queue!(stdout(), Clear(ClearType::All));
+    ec.dirty_rows.extend(0..ec.screenrows);
+}
+
+// The terminal changed size. It is kept big enough for every window to get its minimum size, past
+// that the screen is cut off
+pub fn resize(ec: &mut EditorConfig, cols: usize, rows: usize) {
+    let top = usize::from(ec.tabs.len() > 1);
+    let n = ec.windows.len();
+    ec.termcols = cols.max(n * (MIN_WIDTH + 1));
+    ec.termrows = rows.max(top + 1 + n * MIN_HEIGHT);
+    save(ec);
+    load(ec);
+}
+
+fn enter(ec: &mut EditorConfig, id: usize) {
+    if id == ec.window {return}
+    save(ec);
+    ec.window = id;
+    load(ec);
+}
+
+// Make the current window's buffer, cursor and offsets the ones being edited
+pub fn load(ec: &mut EditorConfig) {
+    let w = current(ec);
+    let (buffer, cursor, rowoff, coloff) = (w.buffer, w.cursor, w.rowoff, w.coloff);
+    if buffer != ec.buf.id {let _ = buffer::switch(ec, buffer);}
+    // the buffer may have shrunk while another window was editing it
+    ec.cy = cursor.1.min(ec.buf.numrows.saturating_sub(1));
+    ec.cx = cursor.0;
+    (ec.rowoff, ec.coloff) = (rowoff.min(ec.cy), coloff);
+    arrange(ec);
+}
+
+// :split and :vsplit, the new window goes above or left of this one and shows the same buffer
+pub fn split(ec: &mut EditorConfig, vertical: bool) -> Result<(), String> {
+    let w = current(ec);
+    let room = if vertical {w.width > 2 * MIN_WIDTH} else {w.height >= 2 * MIN_HEIGHT};
+    if !room {return Err(String::from("E36: Not enough room"))}
+    save(ec);
+    let mut new = Window::new(ec.next_window, ec.buf.id);
+    ec.next_window += 1;
+    (new.cursor, new.rowoff, new.coloff) = ((ec.cx, ec.cy), ec.rowoff, ec.coloff);
+    ec.layout.split(ec.window, new.id, vertical);
+    ec.window = new.id;
+    ec.windows.push(new);
+    arrange(ec);
+    Ok(())
+}
+
+// :close, the buffer stays loaded
+pub fn close(ec: &mut EditorConfig) -> Result<(), String> {
+    if ec.windows.len() == 1 {return Err(String::from("E444: Cannot close last window"))}
+    let mut order = vec![];
+    ec.layout.leaves(&mut order);
+    let at = order.iter().position(|&id| id == ec.window).unwrap_or(0);
+    let next = if at > 0 {order[at - 1]} else {order[1]};
+    let closing = ec.window;
+    enter(ec, next);
+    ec.layout.remove(closing);
+    ec.windows.retain(|w| w.id != closing);
+    arrange(ec);
+    Ok(())
+}
+
+// :only
+pub fn only(ec: &mut EditorConfig) {
+    ec.layout = Layout::Leaf(ec.window);
+    let id = ec.window;
+    ec.windows.retain(|w| w.id == id);
+    arrange(ec);
+}
+
+// Ctrl-w h/j/k/l, the window next to this one that lines up with the cursor
+fn focus(ec: &mut EditorConfig, direction: char) {
+    let w = current(ec);
+    // where the cursor is drawn, a wrapped line above it pushes it down
+    let (y, x) = if ec.options.wrap {wrap::cursor(ec)} else {(ec.cy - ec.rowoff, ec.rx - ec.coloff)};
+    let (row, col) = (w.top + y, w.left + 6 + x);
+    let adjacent = |o: &Window| match direction {
+        'h' => o.left + o.width + 1 == w.left,
+        'l' => w.left + w.width + 1 == o.left,
+        'k' => o.top + o.height == w.top,
+        _ => w.top + w.height == o.top,
+    };
+    let lines_up = |o: &Window| match direction {
+        'h' | 'l' => (o.top..o.top + o.height).contains(&row),
+        _ => (o.left..=o.left + o.width).contains(&col),
+    };
+    let candidates: Vec<&Window> = ec.windows.iter().filter(|o| adjacent(o)).collect();
+    let target = candidates.iter().find(|o| lines_up(o)).or(candidates.first()).map(|o| o.id);
+    if let Some(id) = target {enter(ec, id)}
+}
+
+// Ctrl-w w and W go through the windows in screen order
+fn cycle(ec: &mut EditorConfig, count: usize, forward: bool) {
+    let mut order = vec![];
+    ec.layout.leaves(&mut order);
+    let at = order.iter().position(|&id| id == ec.window).unwrap_or(0);
+    let count = count % order.len();
+    let to = if forward {(at + count) % order.len()} else {(at + order.len() - count) % order.len()};
+    enter(ec, order[to]);
+}
+
+// The key after Ctrl-w
+pub fn command(ec: &mut EditorConfig, key: char, count: usize) -> Result<(), String> {
+    match key {
+        's' | 'S' => split(ec, false)?,
+        'v' => split(ec, true)?,
+        'h' | 'j' | 'k' | 'l' => (0..count).for_each(|_| focus(ec, key)),
+        'w' => cycle(ec, count, true),
+        'W' => cycle(ec, count, false),
+        'c' => close(ec)?,
+        'q' => crate::ex::run(ec, "quit")?,
+        'o' => only(ec),
+        '=' => {
+            ec.layout.equalize();
+            arrange(ec);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::EditorConfig;
+
+    #[test]
+    fn focus_lines_up_with_the_wrapped_cursor() {
+        let mut ec = EditorConfig::test(&format!("{}\nshort", "long ".repeat(100)));
+        ec.keys(":vsplit<CR><C-w>l:split<CR>");
+        let (top, left) = (ec.window, ec.windows.iter().find(|w| w.left == 0).unwrap().id);
+        ec.keys("<C-w>j");
+        let bottom = ec.window;
+        assert_ne!(top, bottom);
+        ec.keys("<C-w>h:set wrap<CR>j");
+        assert_eq!((ec.window, ec.cy), (left, 1));
+        // the second line is drawn below where the top window ends
+        ec.keys("<C-w>l");
+        assert_eq!(ec.window, bottom);
+    }
+}
@@ -0,0 +1,89 @@
+use unicode_segmentation::UnicodeSegmentation;
+use crate::{grapheme, options::Options, EditorConfig, SEPARATORS};
+
+// Byte ranges of the screen lines a line takes when wrapped to width columns. The lines after the
+// first lose room to showbreak, and with linebreak they end after a separator when there is one
+pub fn segments(line: &str, width: usize, options: &Options) -> Vec<(usize, usize)> {
+    let mut segments = vec![];
+    let (mut start, mut start_col) = (0, 0);
+    let mut room = width.max(1);
+    let mut col = 0;
+    let mut last_break = None;
+    for (x, g) in line.grapheme_indices(true) {
+        let w = grapheme::cell_width(g, col, options.tabstop);
+        while col + w - start_col > room && x > start {
+            let (at, at_col) = match last_break {
+                Some((at, at_col)) if options.linebreak && at > start => (at, at_col),
+                _ => (x, col),
+            };
+            segments.push((start, at));
+            (start, start_col) = (at, at_col);
+            room = width.saturating_sub(grapheme::width(&options.showbreak)).max(1);
+        }
+        col += w;
+        if g.starts_with(SEPARATORS) {last_break = Some((x + g.len(), col))}
+    }
+    segments.push((start, line.len()));
+    segments
+}
+
+// Which of the segments byte x is on
+pub fn segment_of(segments: &[(usize, usize)], x: usize) -> usize {
+    segments.iter().rposition(|&(from, _)| from <= x).unwrap_or(0)
+}
+
+// Screen lines row y of the current buffer takes in the current window
+pub fn height(ec: &EditorConfig, y: usize) -> usize {
+    segments(&ec.buf.line(y), crate::text_cols(ec), &ec.options).len()
+}
+
+// Which screen line of its row the cursor is on
+pub fn segment(ec: &EditorConfig) -> usize {
+    segment_of(&segments(&ec.buf.line(ec.cy), crate::text_cols(ec), &ec.options), ec.cx)
+}
+
+// Where the cursor is drawn, as screen lines below the top of the window and columns into the
+// text area
+pub fn cursor(ec: &EditorConfig) -> (usize, usize) {
+    let lines: usize = (ec.rowoff..ec.cy).map(|y| height(ec, y)).sum();
+    let line = ec.buf.line(ec.cy);
+    let segments = segments(&line, crate::text_cols(ec), &ec.options);
+    let i = segment_of(&segments, ec.cx);
+    let start = grapheme::col(&line, segments[i].0, ec.options.tabstop);
+    let showbreak = if i > 0 {grapheme::width(&ec.options.showbreak)} else {0};
+    ((lines + i).saturating_sub(ec.skip), showbreak + ec.rx - start)
+}
+
+// gj and gk, the same screen column one screen line down or up
+pub fn vertical(ec: &mut EditorConfig, down: bool) {
+    let width = crate::text_cols(ec);
+    let ts = ec.options.tabstop;
+    let showbreak = grapheme::width(&ec.options.showbreak);
+    let line = ec.buf.line(ec.cy);
+    let segments = segments(&line, width, &ec.options);
+    let i = segment_of(&segments, ec.cx);
+    let screen_col = if i > 0 {showbreak} else {0} + grapheme::col(&line, ec.cx, ts) - grapheme::col(&line, segments[i].0, ts);
+
+    let (cy, line, segments, i) = if down && i + 1 < segments.len() {
+        (ec.cy, line, segments, i + 1)
+    } else if !down && i > 0 {
+        (ec.cy, line, segments, i - 1)
+    } else if down && ec.cy + 1 < ec.buf.numrows {
+        let line = ec.buf.line(ec.cy + 1);
+        let segments = self::segments(&line, width, &ec.options);
+        (ec.cy + 1, line, segments, 0)
+    } else if !down && ec.cy > 0 {
+        let line = ec.buf.line(ec.cy - 1);
+        let segments = self::segments(&line, width, &ec.options);
+        let last = segments.len() - 1;
+        (ec.cy - 1, line, segments, last)
+    } else {
+        return
+    };
+    let (from, to) = segments[i];
+    let col = grapheme::col(&line, from, ts) + screen_col.saturating_sub(if i > 0 {showbreak} else {0});
+    let x = grapheme::at_col(&line, col, ts);
+    // stay on this screen line, only the last one of a line can put the cursor at its end
+    let x = if x >= to && i + 1 < segments.len() {grapheme::prev(&line, to)} else {x};
+    (ec.cx, ec.cy) = (x.max(from), cy);
+}